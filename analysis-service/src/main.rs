@@ -1,10 +1,19 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use clap::{Parser, Subcommand};
 use tonic::{async_trait, transport::Server};
 
 use proto::mediacorral::analysis::v1 as pb;
 
-use crate::utils::{ExtractDetailsError, extract_details, subtitles::ocr::PartessCache};
+use crate::utils::{
+    ExtractDetailsError, align_srt_documents, analyze_sup_file, analyze_vobsub_files,
+    compare_audio_fingerprints, compare_srt_documents, extract_details, find_shared_video,
+    fingerprint_file, map_hash_options, map_subtitle_options, match_signature,
+    subtitles::{
+        SubtitleOptions, normalize::NormalizeOptions, ocr::PartessCache, ocr_cache::OcrCache,
+        preprocess::PreprocessOptions, selection::SubtitleTracks,
+    },
+};
 
 mod proto;
 mod rayon_helpers;
@@ -15,12 +24,48 @@ struct MediaAnalysisServiceProvider {
     blob_dir: PathBuf,
 }
 impl MediaAnalysisServiceProvider {
-    pub fn new(blob_dir: PathBuf) -> Self {
+    pub fn new(blob_dir: PathBuf, ocr_cache: OcrCache) -> Self {
         return MediaAnalysisServiceProvider {
-            partess_cache: PartessCache::new(),
+            partess_cache: PartessCache::new(ocr_cache),
             blob_dir,
         };
     }
+
+    /// Validates a blob ID and resolves it to a path
+    fn blob_path(&self, blob_id: &str) -> tonic::Result<PathBuf> {
+        if blob_id
+            .chars()
+            .any(|i| !matches!(i, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-'))
+        {
+            return Err(tonic::Status::invalid_argument("Invalid blob_id."));
+        };
+        return Ok(self.blob_dir.join(blob_id));
+    }
+
+    /// Validates subtitle documents without reading them yet
+    fn resolve_documents(
+        &self,
+        documents: Vec<pb::SubtitleDocument>,
+    ) -> tonic::Result<Vec<(String, SrtSource)>> {
+        return documents
+            .into_iter()
+            .map(|document| {
+                let source = match document.source {
+                    Some(pb::subtitle_document::Source::BlobId(blob_id)) => {
+                        SrtSource::Blob(self.blob_path(&blob_id)?)
+                    }
+                    Some(pb::subtitle_document::Source::Srt(srt)) => SrtSource::Text(srt),
+                    None => {
+                        return Err(tonic::Status::invalid_argument(format!(
+                            "Missing source for subtitle document {:?}.",
+                            document.id
+                        )));
+                    }
+                };
+                return Ok((document.id, source));
+            })
+            .collect();
+    }
 }
 #[async_trait]
 impl pb::media_analysis_service_server::MediaAnalysisService for MediaAnalysisServiceProvider {
@@ -29,46 +74,343 @@ impl pb::media_analysis_service_server::MediaAnalysisService for MediaAnalysisSe
         request: tonic::Request<pb::AnalyzeMkvRequest>,
     ) -> tonic::Result<tonic::Response<pb::AnalyzeMkvResponse>> {
         let request = request.into_inner();
-        if request
-            .blob_id
-            .chars()
-            .any(|i| !matches!(i, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-'))
-        {
-            return Err(tonic::Status::invalid_argument("Invalid blob_id."));
-        };
-        let blob_path = self.blob_dir.join(&request.blob_id);
+        let blob_path = self.blob_path(&request.blob_id)?;
         let partess_cache = self.partess_cache.clone();
+        let options = map_subtitle_options(
+            request.normalization,
+            request.review_threshold,
+            request.forced_only,
+            request.preprocessing,
+        );
+        let hash_options = map_hash_options(
+            &request.track_hash_algorithms,
+            request.file_hash_algorithm,
+            request.skip_file_hash,
+        );
+        let st_tracks = if request.all_subtitle_tracks {
+            SubtitleTracks::All
+        } else if !request.st_track_numbers.is_empty() {
+            SubtitleTracks::Tracks(request.st_track_numbers)
+        } else if request.st_track_number != 0 {
+            SubtitleTracks::Track(request.st_track_number)
+        } else {
+            SubtitleTracks::Auto
+        };
         let result = tokio::task::spawn_blocking(move || {
             let file = std::fs::File::open(blob_path)?;
-            return extract_details(file, &partess_cache, request.st_track_number);
+            return extract_details(file, &partess_cache, &st_tracks, &options, &hash_options);
         })
         .await
         .unwrap();
 
         return match result {
             Ok(result) => Ok(tonic::Response::new(result)),
-            // Handle special-case I/O error for non-existent file
-            Err(ExtractDetailsError::Io(io_err))
-                if io_err.kind() == std::io::ErrorKind::NotFound =>
-            {
-                Err(tonic::Status::not_found(io_err.to_string()))
-            }
-            // Pull out I/O errors as they may not be safe to disclose
-            Err(ExtractDetailsError::Io(err)) => {
-                eprintln!("{err}");
-                Err(tonic::Status::internal("An internal error occurred"))
+            Err(err) => Err(error_to_status(err)),
+        };
+    }
+
+    async fn analyze_subtitle_file(
+        &self,
+        request: tonic::Request<pb::AnalyzeSubtitleFileRequest>,
+    ) -> tonic::Result<tonic::Response<pb::AnalyzeSubtitleFileResponse>> {
+        let request = request.into_inner();
+        let partess_cache = self.partess_cache.clone();
+        let options = map_subtitle_options(
+            request.normalization,
+            request.review_threshold,
+            request.forced_only,
+            request.preprocessing,
+        );
+        let result = match request.source {
+            Some(pb::analyze_subtitle_file_request::Source::SupBlobId(blob_id)) => {
+                let sup_path = self.blob_path(&blob_id)?;
+                tokio::task::spawn_blocking(move || {
+                    let file = std::fs::File::open(sup_path)?;
+                    return analyze_sup_file(
+                        std::io::BufReader::new(file),
+                        &partess_cache,
+                        &options,
+                    );
+                })
+                .await
+                .unwrap()
             }
-            // Other errors are safe to disclose
-            Err(err) => {
-                eprintln!("{err}");
-                Err(tonic::Status::internal(err.to_string()))
+            Some(pb::analyze_subtitle_file_request::Source::Vobsub(files)) => {
+                let idx_path = self.blob_path(&files.idx_blob_id)?;
+                let sub_path = self.blob_path(&files.sub_blob_id)?;
+                tokio::task::spawn_blocking(move || {
+                    return analyze_vobsub_paths(&idx_path, &sub_path, &partess_cache, &options);
+                })
+                .await
+                .unwrap()
             }
+            None => return Err(tonic::Status::invalid_argument("Missing source.")),
+        };
+
+        return match result {
+            Ok(result) => Ok(tonic::Response::new(result)),
+            Err(err) => Err(error_to_status(err)),
         };
     }
+
+    async fn compare_subtitles(
+        &self,
+        request: tonic::Request<pb::CompareSubtitlesRequest>,
+    ) -> tonic::Result<tonic::Response<pb::CompareSubtitlesResponse>> {
+        let request = request.into_inner();
+        let ripped = self.resolve_documents(request.ripped)?;
+        let references = self.resolve_documents(request.references)?;
+        let result = tokio::task::spawn_blocking(move || {
+            return compare_srt_documents(load_documents(ripped)?, load_documents(references)?);
+        })
+        .await
+        .unwrap();
+
+        return match result {
+            Ok(result) => Ok(tonic::Response::new(result)),
+            Err(err) => Err(error_to_status(err)),
+        };
+    }
+
+    async fn align_subtitles(
+        &self,
+        request: tonic::Request<pb::AlignSubtitlesRequest>,
+    ) -> tonic::Result<tonic::Response<pb::AlignSubtitlesResponse>> {
+        let request = request.into_inner();
+        let documents = [request.subtitles, request.target]
+            .into_iter()
+            .map(|document| {
+                return document
+                    .ok_or_else(|| tonic::Status::invalid_argument("Missing subtitle document."));
+            })
+            .collect::<tonic::Result<Vec<_>>>()?;
+        let documents = self.resolve_documents(documents)?;
+        let result = tokio::task::spawn_blocking(move || {
+            let documents = load_documents(documents)?;
+            return align_srt_documents(&documents[0].1, &documents[1].1);
+        })
+        .await
+        .unwrap();
+
+        return match result {
+            Ok(result) => Ok(tonic::Response::new(result)),
+            Err(err) => Err(error_to_status(err)),
+        };
+    }
+
+    async fn match_signature(
+        &self,
+        request: tonic::Request<pb::MatchSignatureRequest>,
+    ) -> tonic::Result<tonic::Response<pb::MatchSignatureResponse>> {
+        let request = request.into_inner();
+        let (Some(first), Some(second)) = (request.first, request.second) else {
+            return Err(tonic::Status::invalid_argument("Missing media details."));
+        };
+        return match match_signature(&first, &second) {
+            Some(result) => Ok(tonic::Response::new(result)),
+            None => Err(tonic::Status::invalid_argument(
+                "The files have no track hash algorithm in common.",
+            )),
+        };
+    }
+
+    async fn find_shared_ranges(
+        &self,
+        request: tonic::Request<pb::FindSharedRangesRequest>,
+    ) -> tonic::Result<tonic::Response<pb::FindSharedRangesResponse>> {
+        let request = request.into_inner();
+        let (Some(first), Some(second)) = (request.first, request.second) else {
+            return Err(tonic::Status::invalid_argument("Missing media details."));
+        };
+        return match find_shared_video(&first, &second) {
+            Some(result) => Ok(tonic::Response::new(result)),
+            None => Err(tonic::Status::invalid_argument(
+                "Both files need a video track.",
+            )),
+        };
+    }
+
+    async fn compare_audio_fingerprints(
+        &self,
+        request: tonic::Request<pb::CompareAudioFingerprintsRequest>,
+    ) -> tonic::Result<tonic::Response<pb::CompareAudioFingerprintsResponse>> {
+        let request = request.into_inner();
+        let (Some(first), Some(second)) = (request.first, request.second) else {
+            return Err(tonic::Status::invalid_argument(
+                "Missing audio fingerprint.",
+            ));
+        };
+        let result = tokio::task::spawn_blocking(move || {
+            return compare_audio_fingerprints(&first, &second, request.max_offset_seconds);
+        })
+        .await
+        .unwrap();
+        return match result {
+            Some(result) => Ok(tonic::Response::new(result)),
+            None => Err(tonic::Status::invalid_argument(
+                "The fingerprints are from different versions.",
+            )),
+        };
+    }
+
+    async fn quick_fingerprint(
+        &self,
+        request: tonic::Request<pb::QuickFingerprintRequest>,
+    ) -> tonic::Result<tonic::Response<pb::QuickFingerprintResponse>> {
+        let request = request.into_inner();
+        let blob_path = self.blob_path(&request.blob_id)?;
+        let result = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(blob_path)?;
+            return fingerprint_file(std::io::BufReader::new(file));
+        })
+        .await
+        .unwrap();
+
+        return match result {
+            Ok(result) => Ok(tonic::Response::new(result)),
+            Err(err) => Err(error_to_status(err)),
+        };
+    }
+}
+
+enum SrtSource {
+    Blob(PathBuf),
+    Text(String),
+}
+fn error_to_status(err: ExtractDetailsError) -> tonic::Status {
+    return match err {
+        // Handle special-case I/O error for non-existent file
+        ExtractDetailsError::Io(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => {
+            tonic::Status::not_found(io_err.to_string())
+        }
+        // Pull out I/O errors as they may not be safe to disclose
+        ExtractDetailsError::Io(err) => {
+            eprintln!("{err}");
+            tonic::Status::internal("An internal error occurred")
+        }
+        // Malformed reference subtitles are the caller's problem
+        ExtractDetailsError::SrtParseError(err) => tonic::Status::invalid_argument(err.to_string()),
+        // So are tracks that can't be decoded
+        err @ (ExtractDetailsError::UnsupportedSubtitleTrack(_)
+        | ExtractDetailsError::DuplicateSubtitleTrack(_)) => {
+            tonic::Status::invalid_argument(err.to_string())
+        }
+        // Other errors are safe to disclose
+        err => {
+            eprintln!("{err}");
+            tonic::Status::internal(err.to_string())
+        }
+    };
+}
+
+fn load_documents(
+    documents: Vec<(String, SrtSource)>,
+) -> Result<Vec<(String, String)>, ExtractDetailsError> {
+    return documents
+        .into_iter()
+        .map(|(id, source)| {
+            let srt = match source {
+                SrtSource::Blob(path) => std::fs::read_to_string(path)?,
+                SrtSource::Text(srt) => srt,
+            };
+            return Ok((id, srt));
+        })
+        .collect();
+}
+
+fn analyze_vobsub_paths(
+    idx_path: &Path,
+    sub_path: &Path,
+    partess_cache: &PartessCache,
+    options: &SubtitleOptions,
+) -> Result<pb::AnalyzeSubtitleFileResponse, ExtractDetailsError> {
+    let idx_data = std::fs::read(idx_path)?;
+    let sub_file = std::fs::File::open(sub_path)?;
+    return analyze_vobsub_files(&idx_data, sub_file, partess_cache, options);
+}
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs the gRPC server (default)
+    Serve,
+    /// OCRs a raw PGS (.sup) file, printing SRT to stdout
+    OcrSup {
+        file: PathBuf,
+        /// Cleans up SDH annotations and OCR noise
+        #[arg(long)]
+        normalize: bool,
+        /// Only prints forced subtitles
+        #[arg(long)]
+        forced_only: bool,
+        /// Cleans up bitmaps before OCR
+        #[arg(long)]
+        preprocess: bool,
+    },
+    /// OCRs a VobSub .idx/.sub pair, printing SRT to stdout
+    OcrVobsub {
+        idx: PathBuf,
+        sub: PathBuf,
+        /// Cleans up SDH annotations and OCR noise
+        #[arg(long)]
+        normalize: bool,
+        /// Only prints forced subtitles
+        #[arg(long)]
+        forced_only: bool,
+        /// Cleans up bitmaps before OCR
+        #[arg(long)]
+        preprocess: bool,
+    },
 }
 
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => return serve().await,
+        Command::OcrSup {
+            file,
+            normalize,
+            forced_only,
+            preprocess,
+        } => {
+            let file = std::io::BufReader::new(std::fs::File::open(file)?);
+            let options = SubtitleOptions {
+                normalization: normalize.then(NormalizeOptions::default),
+                preprocessing: preprocess.then(PreprocessOptions::default),
+                forced_only,
+                ..Default::default()
+            };
+            let result = analyze_sup_file(file, &PartessCache::new(OcrCache::new()), &options)?;
+            print!("{}", result.subtitles);
+        }
+        Command::OcrVobsub {
+            idx,
+            sub,
+            normalize,
+            forced_only,
+            preprocess,
+        } => {
+            let options = SubtitleOptions {
+                normalization: normalize.then(NormalizeOptions::default),
+                preprocessing: preprocess.then(PreprocessOptions::default),
+                forced_only,
+                ..Default::default()
+            };
+            let result =
+                analyze_vobsub_paths(&idx, &sub, &PartessCache::new(OcrCache::new()), &options)?;
+            print!("{}", result.subtitles);
+        }
+    }
+    return Ok(());
+}
+
+async fn serve() -> anyhow::Result<()> {
     let addr = match std::env::var("LISTEN_ADDR") {
         Ok(val) => val.parse()?,
         Err(_) => "[::]:50051".parse()?,
@@ -77,7 +419,12 @@ pub async fn main() -> anyhow::Result<()> {
         Ok(val) => PathBuf::from(val),
         Err(_) => PathBuf::from("/mnt/mediacorral/blobs"),
     };
-    let provider = MediaAnalysisServiceProvider::new(blob_dir);
+    // OCR results are only kept in memory unless a directory is given
+    let ocr_cache = match std::env::var("OCR_CACHE_DIR") {
+        Ok(val) => OcrCache::with_disk_tier(PathBuf::from(val))?,
+        Err(_) => OcrCache::new(),
+    };
+    let provider = MediaAnalysisServiceProvider::new(blob_dir, ocr_cache);
 
     let reflection = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::mediacorral::FILE_DESCRIPTOR_SET)
@@ -0,0 +1,173 @@
+//! Decodes AAC with Symphonia, which handles AAC-LC with up to two channels.
+//! Spectral band replication and parametric stereo only rebuild detail above
+//! what fingerprints look at, so HE-AAC tracks have just their AAC-LC core
+//! decoded, at the core's sample rate.
+
+use matroska_demuxer::TrackEntry;
+use symphonia_core::{
+    audio::layouts::{CHANNEL_LAYOUT_MONO, CHANNEL_LAYOUT_STEREO},
+    codecs::audio::{
+        AudioCodecParameters, AudioDecoder, AudioDecoderOptions, well_known::CODEC_ID_AAC,
+    },
+    packet::PacketRef,
+    units::{Duration, Timestamp},
+};
+
+use crate::utils::video::bit_reader::BitReader;
+
+const OBJECT_TYPE_LC: u32 = 2;
+/// Spectral band replication
+const OBJECT_TYPE_SBR: u32 = 5;
+/// Parametric stereo, on top of spectral band replication
+const OBJECT_TYPE_PS: u32 = 29;
+/// Indexed by `samplingFrequencyIndex`
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+pub struct AacDecoder {
+    decoder: symphonia_codec_aac::AacDecoder,
+    sample_rate: u32,
+    planes: Vec<Vec<f32>>,
+}
+impl AacDecoder {
+    pub fn new(track: &TrackEntry) -> Option<Self> {
+        let (sample_rate, channels) = match track.codec_private() {
+            Some(config) => parse_audio_specific_config(config)?,
+            // Older files name the profile in the codec ID instead, and give
+            // the core's sample rate
+            None => {
+                let audio = track.audio()?;
+                (audio.sampling_frequency() as u32, audio.channels().get())
+            }
+        };
+        return Self::open(sample_rate, channels);
+    }
+
+    fn open(sample_rate: u32, channels: u64) -> Option<Self> {
+        let layout = match channels {
+            1 => CHANNEL_LAYOUT_MONO,
+            2 => CHANNEL_LAYOUT_STEREO,
+            _ => return None,
+        };
+        let mut params = AudioCodecParameters::new();
+        params
+            .for_codec(CODEC_ID_AAC)
+            .with_sample_rate(sample_rate)
+            .with_channels(layout);
+        let decoder =
+            symphonia_codec_aac::AacDecoder::try_new(&params, &AudioDecoderOptions::default())
+                .ok()?;
+        return Some(Self {
+            decoder,
+            sample_rate,
+            planes: Vec::new(),
+        });
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    /// Decodes a raw data block, and appends its samples averaged across
+    /// channels
+    pub fn decode(&mut self, data: &[u8], output: &mut Vec<f32>) -> Option<()> {
+        let packet = PacketRef::new(0, Timestamp::new(0), Duration::new(0), data);
+        let buffer = self.decoder.decode_ref(&packet).ok()?;
+        buffer.copy_to_vecs_planar(&mut self.planes);
+        let start = output.len();
+        output.resize(start + buffer.frames(), 0.0);
+        for plane in &self.planes {
+            for (output, sample) in output[start..].iter_mut().zip(plane) {
+                *output += sample / self.planes.len() as f32;
+            }
+        }
+        return Some(());
+    }
+}
+
+/// Reads the core sample rate and channel count from an AudioSpecificConfig
+/// (ISO/IEC 14496-3 1.6.2.1)
+fn parse_audio_specific_config(config: &[u8]) -> Option<(u32, u64)> {
+    let mut reader = BitReader::new(config);
+    let mut object_type = read_object_type(&mut reader)?;
+    let sample_rate = read_sample_rate(&mut reader)?;
+    // Zero means a program config element, which Symphonia can't read
+    let channels = reader.read_bits(4)? as u64;
+    if matches!(object_type, OBJECT_TYPE_SBR | OBJECT_TYPE_PS) {
+        read_sample_rate(&mut reader)?; // extensionSamplingFrequency
+        object_type = read_object_type(&mut reader)?;
+    }
+    // Symphonia only decodes 1024 sample frames
+    if object_type != OBJECT_TYPE_LC || reader.read_bit()? {
+        return None;
+    }
+    return Some((sample_rate, channels));
+}
+
+fn read_object_type(reader: &mut BitReader) -> Option<u32> {
+    return match reader.read_bits(5)? {
+        31 => Some(32 + reader.read_bits(6)?),
+        object_type => Some(object_type),
+    };
+}
+
+fn read_sample_rate(reader: &mut BitReader) -> Option<u32> {
+    return match reader.read_bits(4)? {
+        15 => reader.read_bits(24),
+        index => SAMPLE_RATES.get(index as usize).copied(),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Nine ADTS frames of a 1 kHz sine wave with an amplitude of 0.5, mono
+    /// at 48 kHz and 64 kbit/s
+    const TONE: &[u8] = include_bytes!("../../../test_data/tone_1khz.aac");
+
+    #[test]
+    fn decode_tone() {
+        let mut decoder = AacDecoder::open(48000, 1).unwrap();
+        let mut output = Vec::new();
+        let mut cursor = 0;
+        while cursor < TONE.len() {
+            let header = &TONE[cursor..cursor + 7];
+            let length = ((header[3] as usize & 3) << 11)
+                | ((header[4] as usize) << 3)
+                | (header[5] as usize >> 5);
+            let frame = &TONE[cursor + 7..cursor + length];
+            assert_eq!(decoder.decode(frame, &mut output), Some(()));
+            cursor += length;
+        }
+        assert_eq!(output.len(), 9 * 1024);
+
+        // Past the encoder's delay, the output should be the same tone
+        let samples = &output[2048..8 * 1024];
+        let (mut real, mut imaginary, mut energy) = (0.0, 0.0, 0.0);
+        for (n, sample) in samples.iter().enumerate() {
+            let angle = std::f64::consts::TAU * 1000.0 * n as f64 / 48000.0;
+            real += *sample as f64 * angle.cos();
+            imaginary += *sample as f64 * angle.sin();
+            energy += (*sample as f64).powi(2);
+        }
+        let amplitude = 2.0 * f64::hypot(real, imaginary) / samples.len() as f64;
+        let tone_energy = amplitude.powi(2) / 2.0 * samples.len() as f64;
+        assert!((amplitude - 0.5).abs() < 0.03, "amplitude {amplitude}");
+        assert!(tone_energy / energy > 0.99);
+    }
+
+    #[test]
+    fn parse_config() {
+        // AAC-LC, 44.1 kHz, stereo
+        assert_eq!(parse_audio_specific_config(&[0x12, 0x10]), Some((44100, 2)));
+        // HE-AAC, with a 24 kHz core for 48 kHz, stereo
+        assert_eq!(
+            parse_audio_specific_config(&[0x2B, 0x11, 0x88, 0x00]),
+            Some((24000, 2))
+        );
+        // AAC Main
+        assert_eq!(parse_audio_specific_config(&[0x0A, 0x10]), None);
+    }
+}
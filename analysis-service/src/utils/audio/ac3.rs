@@ -0,0 +1,250 @@
+//! Reads AC-3 and E-AC-3 sync frame headers (ETSI TS 102 366 4.4 and E.1.2).
+//! A Matroska block can hold several E-AC-3 sync frames, one per substream.
+
+use super::{AudioDetails, AudioFormat};
+use crate::utils::video::bit_reader::BitReader;
+
+const SYNC_WORD: [u8; 2] = [0x0B, 0x77];
+
+/// Nominal bit rates in kbit/s, indexed by `frmsizecod / 2`
+const AC3_BITRATES: [u32; 19] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640,
+];
+const SAMPLE_RATES: [u32; 3] = [48000, 44100, 32000];
+const REDUCED_SAMPLE_RATES: [u32; 3] = [24000, 22050, 16000];
+
+const STREAM_TYPE_INDEPENDENT: u32 = 0;
+const STREAM_TYPE_DEPENDENT: u32 = 1;
+const STREAM_TYPE_CONVERTED: u32 = 2;
+
+/// Audio coding mode for two full-range channels
+const ACMOD_STEREO: u32 = 2;
+/// Audio coding mode for two front and two surround channels
+const ACMOD_2F2R: u32 = 6;
+
+/// Reads every sync frame in a block
+pub fn parse_ac3_block(data: &[u8], details: &mut AudioDetails) {
+    let mut cursor = 0;
+    // Samples per channel in the first E-AC-3 frame, which every substream
+    // in the block covers
+    let mut eac3_samples = None;
+    while let Some(frame) = data.get(cursor..) {
+        if frame.len() < 6 || frame[..2] != SYNC_WORD {
+            break;
+        }
+        let bsid = frame[5] >> 3;
+        let frame_size = match bsid {
+            0..=8 => parse_ac3_frame(frame, details),
+            11..=16 => parse_eac3_frame(frame, details).map(|(frame_size, samples)| {
+                eac3_samples.get_or_insert(samples);
+                return frame_size;
+            }),
+            _ => None,
+        };
+        match frame_size {
+            Some(frame_size) if frame_size > 0 => cursor += frame_size,
+            _ => break,
+        }
+    }
+    // E-AC-3 has no nominal bit rate, so count every substream's frames
+    if let (Some(samples), Some(sample_rate)) = (eac3_samples, details.sample_rate) {
+        details.bitrate =
+            Some((cursor.min(data.len()) * 8 * sample_rate as usize / samples) as u32);
+    }
+}
+
+/// Returns the frame size in bytes
+fn parse_ac3_frame(frame: &[u8], details: &mut AudioDetails) -> Option<usize> {
+    let frame_size = ac3_frame_size(frame)?;
+    let sample_rate = SAMPLE_RATES[(frame[4] >> 6) as usize];
+    let bitrate = AC3_BITRATES[(frame[4] & 0x3F) as usize / 2];
+    // Dependent E-AC-3 frames can follow, so only fill in what's missing
+    details.format.get_or_insert(AudioFormat::Ac3);
+    details.sample_rate.get_or_insert(sample_rate);
+    details.bitrate.get_or_insert(bitrate * 1000);
+    return Some(frame_size);
+}
+
+/// Returns the size in bytes of the AC-3 sync frame at the start of `frame`
+pub fn ac3_frame_size(frame: &[u8]) -> Option<usize> {
+    if frame.len() < 5 || frame[..2] != SYNC_WORD {
+        return None;
+    }
+    let fscod = (frame[4] >> 6) as usize;
+    let frmsizecod = (frame[4] & 0x3F) as usize;
+    let sample_rate = *SAMPLE_RATES.get(fscod)? as usize;
+    let bitrate = *AC3_BITRATES.get(frmsizecod / 2)? as usize;
+    return Some(match sample_rate {
+        44100 => 2 * (bitrate * 1000 * 1536 / 8 / 44100 / 2 + (frmsizecod & 1)),
+        _ => bitrate * 1000 * 1536 / 8 / sample_rate,
+    });
+}
+
+/// Returns the frame size in bytes, and the number of samples per channel
+fn parse_eac3_frame(frame: &[u8], details: &mut AudioDetails) -> Option<(usize, usize)> {
+    let mut reader = BitReader::new(&frame[2..]);
+    let stream_type = reader.read_bits(2)?;
+    reader.skip_bits(3)?; // substreamid
+    let frame_size = (reader.read_bits(11)? as usize + 1) * 2;
+    let fscod = reader.read_bits(2)? as usize;
+    let (sample_rate, blocks) = match fscod {
+        3 => (*REDUCED_SAMPLE_RATES.get(reader.read_bits(2)? as usize)?, 6),
+        _ => (
+            SAMPLE_RATES[fscod],
+            [1, 2, 3, 6][reader.read_bits(2)? as usize],
+        ),
+    };
+    let acmod = reader.read_bits(3)?;
+    let lfeon = reader.read_bit()?;
+    reader.skip_bits(5)?; // bsid
+
+    if stream_type != STREAM_TYPE_DEPENDENT {
+        details.format = Some(AudioFormat::Eac3);
+        details.sample_rate = Some(sample_rate);
+    }
+    // A short frame still has a usable size
+    if skip_eac3_bsi(&mut reader, stream_type, acmod, lfeon, blocks, fscod) == Some(true)
+        && extension_type_a(&mut reader) == Some(true)
+    {
+        // Joint object coding, used for Atmos
+        details.object_audio = true;
+    }
+    // Each audio block is 256 samples
+    return Some((frame_size, 256 * blocks));
+}
+
+/// Skips the rest of the bit stream information up to `addbsie`, and
+/// returns that flag
+fn skip_eac3_bsi(
+    reader: &mut BitReader,
+    stream_type: u32,
+    acmod: u32,
+    lfeon: bool,
+    blocks: usize,
+    fscod: usize,
+) -> Option<bool> {
+    // Dual mono has a second set of these fields
+    let programs = if acmod == 0 { 2 } else { 1 };
+    for _ in 0..programs {
+        reader.skip_bits(5)?; // dialnorm
+        if reader.read_bit()? {
+            reader.skip_bits(8)?; // compr
+        }
+    }
+    if stream_type == STREAM_TYPE_DEPENDENT && reader.read_bit()? {
+        reader.skip_bits(16)?; // chanmap
+    }
+    if reader.read_bit()? {
+        // mixmdate
+        if acmod > ACMOD_STEREO {
+            reader.skip_bits(2)?; // dmixmod
+            if acmod & 1 == 1 {
+                reader.skip_bits(6)?; // ltrtcmixlev, lorocmixlev
+            }
+            if acmod & 4 == 4 {
+                reader.skip_bits(6)?; // ltrtsurmixlev, lorosurmixlev
+            }
+        }
+        if lfeon && reader.read_bit()? {
+            reader.skip_bits(5)?; // lfemixlevcod
+        }
+        if stream_type == STREAM_TYPE_INDEPENDENT {
+            for _ in 0..programs {
+                if reader.read_bit()? {
+                    reader.skip_bits(6)?; // pgmscl
+                }
+            }
+            if reader.read_bit()? {
+                reader.skip_bits(6)?; // extpgmscl
+            }
+            match reader.read_bits(2)? {
+                1 => reader.skip_bits(5)?,
+                2 => reader.skip_bits(12)?,
+                3 => {
+                    let mixdeflen = reader.read_bits(5)? as usize;
+                    reader.skip_bits((mixdeflen + 2) * 8)?;
+                }
+                _ => {}
+            }
+            if acmod < ACMOD_STEREO {
+                for _ in 0..programs {
+                    if reader.read_bit()? {
+                        reader.skip_bits(14)?; // panmean, paninfo
+                    }
+                }
+            }
+            if reader.read_bit()? {
+                // frmmixcfginfoe
+                for _ in 0..blocks {
+                    if blocks == 1 || reader.read_bit()? {
+                        reader.skip_bits(5)?; // blkmixcfginfo
+                    }
+                }
+            }
+        }
+    }
+    if reader.read_bit()? {
+        // infomdate
+        reader.skip_bits(5)?; // bsmod, copyrightb, origbs
+        if acmod == ACMOD_STEREO {
+            reader.skip_bits(4)?; // dsurmod, dheadphonmod
+        }
+        if acmod >= ACMOD_2F2R {
+            reader.skip_bits(2)?; // dsurexmod
+        }
+        for _ in 0..programs {
+            if reader.read_bit()? {
+                reader.skip_bits(8)?; // mixlevel, roomtyp, adconvtyp
+            }
+        }
+        if fscod < 3 {
+            reader.skip_bits(1)?; // sourcefscod
+        }
+    }
+    if stream_type == STREAM_TYPE_INDEPENDENT && blocks != 6 {
+        reader.skip_bits(1)?; // convsync
+    }
+    if stream_type == STREAM_TYPE_CONVERTED && (blocks == 6 || reader.read_bit()?) {
+        reader.skip_bits(6)?; // frmsizecod
+    }
+    return reader.read_bit();
+}
+
+/// Reads `flag_ec3_extension_type_a`, the last bit of the first byte of
+/// additional bit stream information
+fn extension_type_a(reader: &mut BitReader) -> Option<bool> {
+    reader.skip_bits(6)?; // addbsil
+    reader.skip_bits(7)?;
+    return reader.read_bit();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ac3_448k() {
+        // 48kHz, frmsizecod 30, bsid 8
+        let mut frame = vec![0x0B, 0x77, 0x00, 0x00, 0x1E, 0x40];
+        frame.resize(1792, 0);
+        let mut details = AudioDetails::default();
+        parse_ac3_block(&frame, &mut details);
+        assert_eq!(details.format, Some(AudioFormat::Ac3));
+        assert_eq!(details.sample_rate, Some(48000));
+        assert_eq!(details.bitrate, Some(448_000));
+    }
+
+    #[test]
+    fn detect_joint_object_coding() {
+        // Independent, 768 kbit/s frame, 48kHz, 6 blocks, 3/2 with LFE, bsid 16
+        // then dialnorm, no compr, mixmdate or infomdate, and two bytes of
+        // additional info with extension type A set
+        let mut frame = vec![0x0B, 0x77, 0x05, 0xFF, 0x3F, 0x87, 0xC4, 0x10, 0x10];
+        frame.resize(3072, 0);
+        let mut details = AudioDetails::default();
+        parse_ac3_block(&frame, &mut details);
+        assert_eq!(details.format, Some(AudioFormat::Eac3));
+        assert_eq!(details.bitrate, Some(768_000));
+        assert!(details.object_audio);
+    }
+}
@@ -0,0 +1,139 @@
+//! Reads DTS core frame headers (ETSI TS 102 114 5.3) and looks for the
+//! extensions in the extension substream that follows them. Matroska keeps
+//! the core and its extension substream in the same block.
+
+use super::{AudioDetails, AudioFormat};
+use crate::utils::video::bit_reader::BitReader;
+
+const CORE_SYNC: [u8; 4] = [0x7F, 0xFE, 0x80, 0x01];
+const SUBSTREAM_SYNC: [u8; 4] = [0x64, 0x58, 0x20, 0x25];
+/// Lossless extension, used by DTS-HD Master Audio
+const XLL_SYNC: [u8; 4] = [0x41, 0xA2, 0x95, 0x47];
+/// Extended bit rate extension, used by DTS-HD High Resolution Audio
+const XBR_SYNC: [u8; 4] = [0x65, 0x5E, 0x31, 0x5E];
+/// Low bit rate extension, used by DTS Express
+const LBR_SYNC: [u8; 4] = [0x0A, 0x80, 0x19, 0x21];
+/// Object audio inside the lossless extension, used by DTS:X and IMAX
+/// Enhanced
+const XLL_X_SYNCS: [[u8; 4]; 2] = [[0x02, 0x00, 0x08, 0x50], [0xF1, 0x40, 0x00, 0xD0]];
+
+const SAMPLE_RATES: [u32; 16] = [
+    0, 8000, 16000, 32000, 0, 0, 11025, 22050, 44100, 0, 0, 12000, 24000, 48000, 0, 0,
+];
+/// Nominal bit rates in bit/s. The rest are open or reserved.
+const BITRATES: [u32; 25] = [
+    32000, 56000, 64000, 96000, 112000, 128000, 192000, 224000, 256000, 320000, 384000, 448000,
+    512000, 576000, 640000, 768000, 960000, 1024000, 1152000, 1280000, 1344000, 1408000, 1411200,
+    1472000, 1536000,
+];
+/// Source PCM resolution. 4 and 7 are reserved.
+const BIT_DEPTHS: [u8; 7] = [16, 16, 20, 20, 0, 24, 24];
+
+/// Channel extensions signaled in the core header, which make a stream
+/// DTS-ES
+const EXT_AUDIO_XCH: u32 = 0;
+const EXT_AUDIO_XXCH: u32 = 6;
+
+pub fn parse_dts_block(data: &[u8], details: &mut AudioDetails) {
+    let mut substream = data;
+    let mut core_samples = None;
+    if data.starts_with(&CORE_SYNC) {
+        let Some((frame_size, samples)) = parse_core_header(&data[4..], details) else {
+            return;
+        };
+        core_samples = Some(samples);
+        substream = data.get(frame_size..).unwrap_or_default();
+    }
+    if !substream.starts_with(&SUBSTREAM_SYNC) {
+        return;
+    }
+
+    let contains = |sync: &[u8; 4]| substream.windows(4).any(|window| window == sync);
+    if contains(&XLL_SYNC) {
+        details.format = Some(AudioFormat::DtsHdMa);
+        details.lossless = true;
+        // Variable, so there's no meaningful nominal rate
+        details.bitrate = None;
+        details.object_audio |= XLL_X_SYNCS.iter().any(contains);
+    } else if contains(&XBR_SYNC) {
+        details.format = Some(AudioFormat::DtsHdHra);
+        // The core and its extension together
+        if let (Some(samples), Some(sample_rate)) = (core_samples, details.sample_rate) {
+            details.bitrate = Some((data.len() * 8 * sample_rate as usize / samples) as u32);
+        }
+    } else if contains(&LBR_SYNC) && core_samples.is_none() {
+        details.format = Some(AudioFormat::DtsExpress);
+    }
+}
+
+/// Reads the core frame header after the sync word. Returns the frame size in
+/// bytes, and the number of samples per channel.
+fn parse_core_header(header: &[u8], details: &mut AudioDetails) -> Option<(usize, usize)> {
+    let mut reader = BitReader::new(header);
+    reader.skip_bits(7)?; // FTYPE, SHORT, CPF
+    let crc_present = header.first()? & 0x02 != 0;
+    let blocks = reader.read_bits(7)? as usize + 1;
+    let frame_size = reader.read_bits(14)? as usize + 1;
+    reader.skip_bits(6)?; // AMODE
+    let sample_rate = SAMPLE_RATES[reader.read_bits(4)? as usize];
+    let bitrate = BITRATES.get(reader.read_bits(5)? as usize).copied();
+    reader.skip_bits(5)?; // reserved, DYNF, TIMEF, AUXF, HDCD
+    let ext_audio_id = reader.read_bits(3)?;
+    let ext_audio = reader.read_bit()?;
+    reader.skip_bits(4)?; // ASPF, LFF, HFLAG
+    if crc_present {
+        reader.skip_bits(16)?; // HCRC
+    }
+    reader.skip_bits(7)?; // FILTS, VERNUM, CHIST
+    let bit_depth = BIT_DEPTHS.get(reader.read_bits(3)? as usize).copied();
+
+    details.format = Some(match ext_audio_id {
+        EXT_AUDIO_XCH | EXT_AUDIO_XXCH if ext_audio => AudioFormat::DtsEs,
+        _ => AudioFormat::Dts,
+    });
+    details.sample_rate = (sample_rate > 0).then_some(sample_rate);
+    details.bit_depth = bit_depth.filter(|bit_depth| *bit_depth > 0);
+    details.bitrate = bitrate;
+    // Each block is 32 samples
+    return Some((frame_size, blocks * 32));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A 48kHz, 24-bit, 1536 kbit/s core frame
+    fn core_frame() -> Vec<u8> {
+        // FTYPE 1, SHORT 31, CPF 0, NBLKS 15, FSIZE 2012, AMODE 9, SFREQ 13,
+        // RATE 24, then everything else clear except PCMR 6
+        let mut frame = vec![
+            0x7F, 0xFE, 0x80, 0x01, 0xFC, 0x3C, 0x7D, 0xC2, 0x77, 0x00, 0x00, 0x01, 0x80,
+        ];
+        frame.resize(2013, 0);
+        return frame;
+    }
+
+    #[test]
+    fn parse_dts_core() {
+        let mut details = AudioDetails::default();
+        parse_dts_block(&core_frame(), &mut details);
+        assert_eq!(details.format, Some(AudioFormat::Dts));
+        assert_eq!(details.sample_rate, Some(48000));
+        assert_eq!(details.bit_depth, Some(24));
+        assert_eq!(details.bitrate, Some(1536000));
+    }
+
+    #[test]
+    fn detect_lossless_extension() {
+        let mut frame = core_frame();
+        frame.extend(SUBSTREAM_SYNC);
+        frame.extend([0; 16]);
+        frame.extend(XLL_SYNC);
+        frame.extend([0; 64]);
+        let mut details = AudioDetails::default();
+        parse_dts_block(&frame, &mut details);
+        assert_eq!(details.format, Some(AudioFormat::DtsHdMa));
+        assert!(details.lossless);
+        assert!(!details.object_audio);
+    }
+}
@@ -0,0 +1,353 @@
+//! A spectral fingerprint of decoded audio, in the style of Chromaprint and
+//! Haitsma and Kalker's "Highly Robust Audio Fingerprinting System".
+//!
+//! Mono audio is resampled to `SAMPLE_RATE` by averaging, then cut into
+//! Hann-windowed frames of `FRAME_SIZE` samples, `HOP_SIZE` apart. Each
+//! frame's spectrum is summed into `BANDS` bands, spaced evenly on a log
+//! scale between 300 and 2000 Hz, where most of what survives lossy coding
+//! is. Bit `m` of a frame's 32-bit sub-fingerprint is set when the log
+//! energy difference between bands `m` and `m + 1` grew since the previous
+//! frame. Energies are floored first, so near-silent bands give zero bits
+//! rather than noise.
+//! Any change to this scheme bumps `FINGERPRINT_VERSION`.
+
+use std::collections::HashMap;
+
+pub const FINGERPRINT_VERSION: u32 = 1;
+pub const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = 1365;
+/// One more than the bits in a sub-fingerprint
+const BANDS: usize = 33;
+const MIN_FREQUENCY: f64 = 300.0;
+const MAX_FREQUENCY: f64 = 2000.0;
+/// About 60 dB below a full scale tone
+const ENERGY_FLOOR: f32 = 1.0;
+
+/// Seconds between consecutive sub-fingerprints
+pub fn frame_seconds() -> f64 {
+    return HOP_SIZE as f64 / SAMPLE_RATE as f64;
+}
+
+/// An in-place radix-2 FFT of a fixed size
+struct Fft {
+    /// `e^(-2πik/n)` for the first half of the circle
+    twiddles: Vec<(f32, f32)>,
+    bit_reversed: Vec<usize>,
+}
+impl Fft {
+    fn new(size: usize) -> Self {
+        let bits = size.trailing_zeros();
+        return Self {
+            twiddles: (0..size / 2)
+                .map(|k| {
+                    let angle = -2.0 * std::f64::consts::PI * k as f64 / size as f64;
+                    return (angle.cos() as f32, angle.sin() as f32);
+                })
+                .collect(),
+            bit_reversed: (0..size)
+                .map(|i| i.reverse_bits() >> (usize::BITS - bits))
+                .collect(),
+        };
+    }
+
+    fn transform(&self, real: &mut [f32], imaginary: &mut [f32]) {
+        let size = real.len();
+        for (i, &j) in self.bit_reversed.iter().enumerate() {
+            if i < j {
+                real.swap(i, j);
+                imaginary.swap(i, j);
+            }
+        }
+        let mut length = 2;
+        while length <= size {
+            let stride = size / length;
+            for start in (0..size).step_by(length) {
+                for k in 0..length / 2 {
+                    let (cos, sin) = self.twiddles[k * stride];
+                    let (a, b) = (start + k, start + k + length / 2);
+                    let re = real[b] * cos - imaginary[b] * sin;
+                    let im = real[b] * sin + imaginary[b] * cos;
+                    real[b] = real[a] - re;
+                    imaginary[b] = imaginary[a] - im;
+                    real[a] += re;
+                    imaginary[a] += im;
+                }
+            }
+            length *= 2;
+        }
+    }
+}
+
+/// Computes sub-fingerprints as mono samples come in
+pub struct AudioFingerprinter {
+    input_rate: u64,
+    /// Counts up by `SAMPLE_RATE` per input sample, emitting a resampled
+    /// sample per `input_rate`
+    phase: u64,
+    sum: f32,
+    count: u32,
+    /// Resampled samples not yet past the last frame
+    buffer: Vec<f32>,
+    window: Vec<f32>,
+    fft: Fft,
+    real: Vec<f32>,
+    imaginary: Vec<f32>,
+    /// The FFT bins where each band starts, and where the last one ends
+    band_edges: Vec<usize>,
+    previous: Option<[f32; BANDS]>,
+    hashes: Vec<u32>,
+}
+impl AudioFingerprinter {
+    pub fn new(input_rate: u32) -> Self {
+        let bin_width = SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+        let band_edges = (0..=BANDS)
+            .map(|band| {
+                let ratio = (MAX_FREQUENCY / MIN_FREQUENCY).powf(band as f64 / BANDS as f64);
+                return (MIN_FREQUENCY * ratio / bin_width).round() as usize;
+            })
+            .collect();
+        return Self {
+            input_rate: input_rate.max(1) as u64,
+            phase: 0,
+            sum: 0.0,
+            count: 0,
+            buffer: Vec::with_capacity(FRAME_SIZE * 2),
+            window: (0..FRAME_SIZE)
+                .map(|i| {
+                    let angle = 2.0 * std::f64::consts::PI * i as f64 / FRAME_SIZE as f64;
+                    return (0.5 - 0.5 * angle.cos()) as f32;
+                })
+                .collect(),
+            fft: Fft::new(FRAME_SIZE),
+            real: vec![0.0; FRAME_SIZE],
+            imaginary: vec![0.0; FRAME_SIZE],
+            band_edges,
+            previous: None,
+            hashes: Vec::new(),
+        };
+    }
+
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            self.sum += sample;
+            self.count += 1;
+            self.phase += SAMPLE_RATE as u64;
+            // Upsampling repeats samples
+            while self.phase >= self.input_rate {
+                self.phase -= self.input_rate;
+                self.buffer.push(self.sum / self.count as f32);
+            }
+            if self.phase < SAMPLE_RATE as u64 {
+                self.sum = 0.0;
+                self.count = 0;
+            }
+        }
+        let mut start = 0;
+        while self.buffer.len() - start >= FRAME_SIZE {
+            self.process_frame(start);
+            start += HOP_SIZE;
+        }
+        self.buffer.drain(..start);
+    }
+
+    fn process_frame(&mut self, start: usize) {
+        let frame = &self.buffer[start..start + FRAME_SIZE];
+        for ((real, sample), weight) in self.real.iter_mut().zip(frame).zip(&self.window) {
+            *real = sample * weight;
+        }
+        self.imaginary.fill(0.0);
+        self.fft.transform(&mut self.real, &mut self.imaginary);
+
+        let mut energies = [0.0; BANDS];
+        for (band, energy) in energies.iter_mut().enumerate() {
+            let bins = self.band_edges[band]..self.band_edges[band + 1];
+            let sum: f32 = bins
+                .map(|bin| {
+                    self.real[bin] * self.real[bin] + self.imaginary[bin] * self.imaginary[bin]
+                })
+                .sum();
+            *energy = sum.max(ENERGY_FLOOR).ln();
+        }
+        if let Some(previous) = self.previous {
+            let mut hash = 0;
+            for band in 0..BANDS - 1 {
+                let current = energies[band] - energies[band + 1];
+                let before = previous[band] - previous[band + 1];
+                if current - before > 0.0 {
+                    hash |= 1 << band;
+                }
+            }
+            self.hashes.push(hash);
+        }
+        self.previous = Some(energies);
+    }
+
+    /// The partial frame at the end is left out
+    pub fn finish(self) -> Vec<u32> {
+        return self.hashes;
+    }
+}
+
+/// Halves this common, like those of silence, say nothing about the offset
+const COMMON_HASH_LIMIT: usize = 64;
+/// How many of the most voted offsets get compared bit by bit
+const CANDIDATE_OFFSETS: usize = 8;
+/// About ten seconds
+const MIN_OVERLAP: usize = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FingerprintMatch {
+    /// Sub-fingerprint `i` of the second lines up with `i + offset` of the
+    /// first
+    pub offset: i64,
+    /// The share of bits that agree where they overlap. Unrelated audio
+    /// scores around 0.5 to 0.6, and the same audio at another sample rate
+    /// or volume well over 0.75.
+    pub similarity: f64,
+    /// How many sub-fingerprints overlap
+    pub compared: usize,
+}
+
+fn compare_at(first: &[u32], second: &[u32], offset: i64) -> FingerprintMatch {
+    let start = (-offset).max(0) as usize;
+    let end = (first.len() as i64 - offset).clamp(0, second.len() as i64) as usize;
+    let (mut errors, mut compared) = (0, 0);
+    for j in start..end.max(start) {
+        errors += (first[(j as i64 + offset) as usize] ^ second[j]).count_ones() as u64;
+        compared += 1;
+    }
+    let similarity = match compared {
+        0 => 0.0,
+        _ => 1.0 - errors as f64 / (32 * compared) as f64,
+    };
+    return FingerprintMatch {
+        offset,
+        similarity,
+        compared,
+    };
+}
+
+/// The lower and upper 16 bits, tagged so they don't match each other
+fn halves(hash: u32) -> [u32; 2] {
+    return [hash & 0xffff, (hash >> 16) | 0x10000];
+}
+
+/// Finds how the second fingerprint best lines up with the first, trying
+/// the offsets where the most sub-fingerprint halves agree exactly. Whole
+/// sub-fingerprints rarely survive frames that don't line up or lossy
+/// coding, but halves often do. Offsets are limited to `max_offset` either
+/// way, if given.
+pub fn compare_fingerprints(
+    first: &[u32],
+    second: &[u32],
+    max_offset: Option<u64>,
+) -> Option<FingerprintMatch> {
+    let mut positions: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (j, hash) in second.iter().enumerate() {
+        for half in halves(*hash) {
+            positions.entry(half).or_default().push(j);
+        }
+    }
+    let in_range = |offset: i64| {
+        return max_offset.is_none_or(|max_offset| offset.unsigned_abs() <= max_offset);
+    };
+    let mut votes: HashMap<i64, u32> = HashMap::new();
+    for (i, hash) in first.iter().enumerate() {
+        for half in halves(*hash) {
+            let Some(matches) = positions.get(&half) else {
+                continue;
+            };
+            if matches.len() > COMMON_HASH_LIMIT {
+                continue;
+            }
+            for &j in matches {
+                let offset = i as i64 - j as i64;
+                if in_range(offset) {
+                    *votes.entry(offset).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    let mut candidates: Vec<(i64, u32)> = votes.into_iter().collect();
+    // Ties go to the smaller offset, so the result doesn't depend on hashing
+    candidates.sort_by_key(|(offset, votes)| (std::cmp::Reverse(*votes), offset.abs(), *offset));
+    candidates.truncate(CANDIDATE_OFFSETS);
+    // Heavily transcoded audio may not agree anywhere exactly
+    if !candidates.iter().any(|(offset, _)| *offset == 0) {
+        candidates.push((0, 0));
+    }
+
+    let min_overlap = MIN_OVERLAP.min(first.len()).min(second.len()).max(1);
+    return candidates
+        .into_iter()
+        .map(|(offset, _)| compare_at(first, second, offset))
+        .filter(|result| result.compared >= min_overlap)
+        .max_by(|a, b| a.similarity.total_cmp(&b.similarity));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Notes that fade in and out over half a second, in three voices that
+    /// start a sixth of a second apart. The same seed gives the same notes.
+    fn notes(seed: u64, seconds: usize, sample_rate: usize) -> Vec<f32> {
+        let note_length = sample_rate / 2;
+        let mut state = seed;
+        let mut samples = vec![0.0; seconds * sample_rate];
+        for voice in 0..3 {
+            let mut start = voice * note_length / 3;
+            while start < samples.len() {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let frequency = (300 + (state >> 33) % 1700) as f64;
+                let end = (start + note_length).min(samples.len());
+                for (i, sample) in samples[start..end].iter_mut().enumerate() {
+                    let time = (start + i) as f64 / sample_rate as f64;
+                    let fade = (i as f64 / note_length as f64 * std::f64::consts::PI).sin();
+                    let tone = (time * frequency * std::f64::consts::TAU).sin();
+                    *sample += (0.3 * fade * fade * tone) as f32;
+                }
+                start += note_length;
+            }
+        }
+        return samples;
+    }
+
+    fn fingerprint(samples: &[f32], sample_rate: u32) -> Vec<u32> {
+        let mut fingerprinter = AudioFingerprinter::new(sample_rate);
+        // In uneven pieces, like decoded frames
+        for chunk in samples.chunks(1152) {
+            fingerprinter.push_samples(chunk);
+        }
+        return fingerprinter.finish();
+    }
+
+    #[test]
+    fn find_clip_within_track() {
+        let track = notes(1, 120, 48000);
+        let track_fingerprint = fingerprint(&track, 48000);
+        assert_eq!(
+            track_fingerprint.len(),
+            (120 * SAMPLE_RATE as usize - FRAME_SIZE) / HOP_SIZE
+        );
+
+        // Thirty seconds from a minute in, quieter and at another rate
+        let clip: Vec<f32> = notes(1, 120, 44100)[60 * 44100..90 * 44100]
+            .iter()
+            .map(|sample| sample * 0.5)
+            .collect();
+        let result =
+            compare_fingerprints(&track_fingerprint, &fingerprint(&clip, 44100), None).unwrap();
+        let offset_seconds = result.offset as f64 * frame_seconds();
+        assert!((offset_seconds - 60.0).abs() < 0.2, "{result:?}");
+        assert!(result.similarity > 0.75, "{result:?}");
+
+        let other = fingerprint(&notes(2, 30, 48000), 48000);
+        let result = compare_fingerprints(&track_fingerprint, &other, None).unwrap();
+        assert!(result.similarity < 0.7, "{result:?}");
+        assert!(compare_fingerprints(&track_fingerprint, &other, Some(0)).is_some());
+    }
+}
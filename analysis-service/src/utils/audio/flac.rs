@@ -0,0 +1,441 @@
+//! Decodes FLAC frames (RFC 9639). Matroska keeps the `fLaC` marker and the
+//! metadata blocks in CodecPrivate and one frame in each block. The frame
+//! CRCs aren't checked, since Matroska has its own.
+
+use crate::utils::video::bit_reader::BitReader;
+
+const MARKER: &[u8; 4] = b"fLaC";
+const SYNC_CODE: u32 = 0b11_1111_1111_1110;
+
+/// Independent channels are numbered below this
+const CHANNELS_LEFT_SIDE: u32 = 8;
+const CHANNELS_SIDE_RIGHT: u32 = 9;
+const CHANNELS_MID_SIDE: u32 = 10;
+
+/// What the STREAMINFO block says about the stream
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_sample: u32,
+}
+impl StreamInfo {
+    pub fn parse(codec_private: &[u8]) -> Option<Self> {
+        let block = codec_private.strip_prefix(MARKER)?;
+        // STREAMINFO always comes first. Skip its header and the block and
+        // frame size bounds.
+        if block.first()? & 0x7f != 0 {
+            return None;
+        }
+        let mut reader = BitReader::new(block.get(4 + 10..4 + 18)?);
+        let sample_rate = reader.read_bits(20)?;
+        let channels = reader.read_bits(3)? + 1;
+        let bits_per_sample = reader.read_bits(5)? + 1;
+        return Some(Self {
+            sample_rate,
+            channels,
+            bits_per_sample,
+        });
+    }
+}
+
+pub struct FlacDecoder {
+    info: StreamInfo,
+    /// Reused between frames
+    channels: Vec<Vec<i64>>,
+}
+impl FlacDecoder {
+    pub fn new(codec_private: &[u8]) -> Option<Self> {
+        return Some(Self {
+            info: StreamInfo::parse(codec_private)?,
+            channels: Vec::new(),
+        });
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.info.sample_rate;
+    }
+
+    /// Appends the frame's samples, averaged across channels. Returns `None`
+    /// for frames that can't be decoded, which are left out entirely.
+    pub fn decode(&mut self, data: &[u8], output: &mut Vec<f32>) -> Option<()> {
+        let mut reader = BitReader::new(data);
+        let header = read_frame_header(&mut reader, &self.info)?;
+        let channel_count = match header.channel_assignment {
+            0..CHANNELS_LEFT_SIDE => header.channel_assignment + 1,
+            CHANNELS_LEFT_SIDE..=CHANNELS_MID_SIDE => 2,
+            _ => return None,
+        } as usize;
+        self.channels.resize_with(channel_count, Vec::new);
+        for (channel, samples) in self.channels.iter_mut().enumerate() {
+            // The side channel needs an extra bit
+            let extra_bit = match header.channel_assignment {
+                CHANNELS_LEFT_SIDE | CHANNELS_MID_SIDE => channel == 1,
+                CHANNELS_SIDE_RIGHT => channel == 0,
+                _ => false,
+            };
+            let bits_per_sample = header.bits_per_sample + extra_bit as u32;
+            read_subframe(&mut reader, header.block_size, bits_per_sample, samples)?;
+        }
+        decorrelate(header.channel_assignment, &mut self.channels);
+
+        let scale = (1u64 << (header.bits_per_sample - 1)) as f32 * channel_count as f32;
+        for i in 0..header.block_size {
+            let sum: i64 = self.channels.iter().map(|samples| samples[i]).sum();
+            output.push(sum as f32 / scale);
+        }
+        return Some(());
+    }
+}
+
+struct FrameHeader {
+    block_size: usize,
+    channel_assignment: u32,
+    bits_per_sample: u32,
+}
+
+fn read_frame_header(reader: &mut BitReader, info: &StreamInfo) -> Option<FrameHeader> {
+    if reader.read_bits(14)? != SYNC_CODE {
+        return None;
+    }
+    // Reserved and blocking strategy
+    reader.skip_bits(2)?;
+    let block_size_code = reader.read_bits(4)?;
+    let sample_rate_code = reader.read_bits(4)?;
+    let channel_assignment = reader.read_bits(4)?;
+    let bits_per_sample = match reader.read_bits(3)? {
+        0 => info.bits_per_sample,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return None,
+    };
+    reader.skip_bits(1)?;
+    // The frame or sample number, coded like UTF-8
+    match (reader.read_bits(8)? as u8).leading_ones() {
+        0 => {}
+        length @ 2..=7 => reader.skip_bits(8 * (length as usize - 1))?,
+        _ => return None,
+    }
+    let block_size = match block_size_code {
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => reader.read_bits(8)? as usize + 1,
+        7 => reader.read_bits(16)? as usize + 1,
+        8..=15 => 256 << (block_size_code - 8),
+        _ => return None,
+    };
+    match sample_rate_code {
+        12 => reader.skip_bits(8)?,
+        13 | 14 => reader.skip_bits(16)?,
+        15 => return None,
+        _ => {}
+    }
+    // CRC-8
+    reader.skip_bits(8)?;
+    return Some(FrameHeader {
+        block_size,
+        channel_assignment,
+        bits_per_sample,
+    });
+}
+
+fn read_subframe(
+    reader: &mut BitReader,
+    block_size: usize,
+    bits_per_sample: u32,
+    samples: &mut Vec<i64>,
+) -> Option<()> {
+    samples.clear();
+    if reader.read_bit()? {
+        return None;
+    }
+    let subframe_type = reader.read_bits(6)?;
+    let wasted_bits = match reader.read_bit()? {
+        true => reader.read_unary()? + 1,
+        false => 0,
+    };
+    let bits_per_sample = bits_per_sample.checked_sub(wasted_bits)?;
+    match subframe_type {
+        // Constant
+        0 => {
+            let value = read_sample(reader, bits_per_sample)?;
+            samples.resize(block_size, value);
+        }
+        // Verbatim
+        1 => {
+            for _ in 0..block_size {
+                samples.push(read_sample(reader, bits_per_sample)?);
+            }
+        }
+        // Fixed prediction
+        8..=12 => {
+            let order = (subframe_type - 8) as usize;
+            read_warm_up(reader, order, block_size, bits_per_sample, samples)?;
+            read_residual(reader, block_size, order, samples)?;
+            restore_fixed(order, samples);
+        }
+        // Linear prediction
+        32..=63 => {
+            let order = (subframe_type - 31) as usize;
+            read_warm_up(reader, order, block_size, bits_per_sample, samples)?;
+            let precision = match reader.read_bits(4)? {
+                15 => return None,
+                precision => precision + 1,
+            };
+            let shift = reader.read_signed_bits(5)?;
+            if shift < 0 {
+                return None;
+            }
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(reader.read_signed_bits(precision)? as i64);
+            }
+            read_residual(reader, block_size, order, samples)?;
+            restore_lpc(&coefficients, shift as u32, samples);
+        }
+        _ => return None,
+    }
+    if wasted_bits > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= wasted_bits;
+        }
+    }
+    return Some(());
+}
+
+/// Reads a sample of up to 33 bits, as a side channel of 32-bit audio has
+fn read_sample(reader: &mut BitReader, bits: u32) -> Option<i64> {
+    if bits <= 32 {
+        return Some(reader.read_signed_bits(bits)? as i64);
+    }
+    let high = reader.read_signed_bits(bits - 32)? as i64;
+    return Some((high << 32) | reader.read_bits(32)? as i64);
+}
+
+fn read_warm_up(
+    reader: &mut BitReader,
+    order: usize,
+    block_size: usize,
+    bits_per_sample: u32,
+    samples: &mut Vec<i64>,
+) -> Option<()> {
+    if order > block_size {
+        return None;
+    }
+    for _ in 0..order {
+        samples.push(read_sample(reader, bits_per_sample)?);
+    }
+    return Some(());
+}
+
+/// Appends the Rice coded residual after the warm-up samples
+fn read_residual(
+    reader: &mut BitReader,
+    block_size: usize,
+    order: usize,
+    samples: &mut Vec<i64>,
+) -> Option<()> {
+    let (parameter_bits, escape) = match reader.read_bits(2)? {
+        0 => (4, 15),
+        1 => (5, 31),
+        _ => return None,
+    };
+    let partition_order = reader.read_bits(4)?;
+    let partitions = 1 << partition_order;
+    if !block_size.is_multiple_of(partitions) || block_size / partitions < order {
+        return None;
+    }
+    for partition in 0..partitions {
+        let mut count = block_size / partitions;
+        if partition == 0 {
+            count -= order;
+        }
+        let parameter = reader.read_bits(parameter_bits)?;
+        if parameter == escape {
+            let bits = reader.read_bits(5)?;
+            for _ in 0..count {
+                samples.push(reader.read_signed_bits(bits)? as i64);
+            }
+            continue;
+        }
+        for _ in 0..count {
+            let quotient = reader.read_unary()? as u64;
+            let value = (quotient << parameter) | reader.read_bits(parameter)? as u64;
+            // Zigzag coded
+            samples.push((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+    }
+    return Some(());
+}
+
+/// Turns the residual after the warm-up samples into samples
+fn restore_fixed(order: usize, samples: &mut [i64]) {
+    for i in order..samples.len() {
+        let prediction = match order {
+            0 => 0,
+            1 => samples[i - 1],
+            2 => 2 * samples[i - 1] - samples[i - 2],
+            3 => 3 * samples[i - 1] - 3 * samples[i - 2] + samples[i - 3],
+            _ => 4 * samples[i - 1] - 6 * samples[i - 2] + 4 * samples[i - 3] - samples[i - 4],
+        };
+        samples[i] += prediction;
+    }
+}
+
+fn restore_lpc(coefficients: &[i64], shift: u32, samples: &mut [i64]) {
+    let order = coefficients.len();
+    for i in order..samples.len() {
+        let prediction: i64 = coefficients
+            .iter()
+            .zip(samples[i - order..i].iter().rev())
+            .map(|(coefficient, sample)| coefficient * sample)
+            .sum();
+        samples[i] += prediction >> shift;
+    }
+}
+
+fn decorrelate(channel_assignment: u32, channels: &mut [Vec<i64>]) {
+    let [first, second] = channels else {
+        return;
+    };
+    let pairs = first.iter_mut().zip(second.iter_mut());
+    match channel_assignment {
+        CHANNELS_LEFT_SIDE => {
+            for (left, side) in pairs {
+                *side = *left - *side;
+            }
+        }
+        CHANNELS_SIDE_RIGHT => {
+            for (side, right) in pairs {
+                *side += *right;
+            }
+        }
+        CHANNELS_MID_SIDE => {
+            for (mid, side) in pairs {
+                let sum = (*mid << 1) | (*side & 1);
+                (*mid, *side) = ((sum + *side) >> 1, (sum - *side) >> 1);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Just enough of an encoder to build test frames
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: u32,
+    }
+    impl BitWriter {
+        fn write(&mut self, value: u64, count: u32) {
+            for i in (0..count).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = ((value >> i) & 1) as u8;
+                *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn write_signed(&mut self, value: i64, count: u32) {
+            self.write(value as u64 & ((1 << count) - 1), count);
+        }
+
+        fn write_rice(&mut self, value: i64, parameter: u32) {
+            let zigzag = ((value << 1) ^ (value >> 63)) as u64;
+            let quotient = zigzag >> parameter;
+            self.write(1, quotient as u32 + 1);
+            self.write(zigzag, parameter);
+        }
+    }
+
+    fn stream_info() -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // Last block, STREAMINFO, 34 bytes
+        writer.write(0x80_000022, 32);
+        writer.write(16, 16);
+        writer.write(16, 16);
+        writer.write(0, 48);
+        writer.write(44100, 20);
+        writer.write(1, 3);
+        writer.write(15, 5);
+        writer.write(0, 36);
+        // MD5
+        writer.write(0, 64);
+        writer.write(0, 64);
+        return [MARKER.as_slice(), &writer.bytes].concat();
+    }
+
+    /// A 16-sample mid/side frame, with mid in fixed order 2 and side
+    /// constant
+    fn frame(mid: &[i64], side: i64) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write(SYNC_CODE as u64, 14);
+        writer.write(0, 2);
+        // Block size from an 8-bit field, sample rate from STREAMINFO
+        writer.write(6, 4);
+        writer.write(0, 4);
+        writer.write(CHANNELS_MID_SIDE as u64, 4);
+        // 16 bits per sample
+        writer.write(4, 3);
+        writer.write(0, 1);
+        // Frame number 0
+        writer.write(0, 8);
+        writer.write(mid.len() as u64 - 1, 8);
+        writer.write(0, 8);
+
+        // Fixed order 2, no wasted bits
+        writer.write(0, 1);
+        writer.write(8 + 2, 6);
+        writer.write(0, 1);
+        writer.write_signed(mid[0], 16);
+        writer.write_signed(mid[1], 16);
+        // Rice with 4-bit parameters, one partition
+        writer.write(0, 2);
+        writer.write(0, 4);
+        writer.write(3, 4);
+        for i in 2..mid.len() {
+            writer.write_rice(mid[i] - (2 * mid[i - 1] - mid[i - 2]), 3);
+        }
+
+        // Constant
+        writer.write(0, 8);
+        writer.write_signed(side, 17);
+        return writer.bytes;
+    }
+
+    #[test]
+    fn decode_mid_side_frame() {
+        let info = stream_info();
+        assert_eq!(
+            StreamInfo::parse(&info),
+            Some(StreamInfo {
+                sample_rate: 44100,
+                channels: 2,
+                bits_per_sample: 16,
+            })
+        );
+        let left: Vec<i64> = (0..16).map(|i| i * i * 20 - 1000).collect();
+        let right: Vec<i64> = left.iter().map(|sample| sample - 301).collect();
+        // Mid drops its low bit, which side carries
+        let mid: Vec<i64> = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+
+        let mut decoder = FlacDecoder::new(&info).unwrap();
+        let mut output = Vec::new();
+        decoder.decode(&frame(&mid, 301), &mut output).unwrap();
+        assert_eq!(decoder.channels[0], left);
+        assert_eq!(decoder.channels[1], right);
+        assert_eq!(output.len(), 16);
+        assert_eq!(output[0], (-1000.0 - 1301.0) / 2.0 / 32768.0);
+        assert!(decoder.decode(&[0xff, 0xf8, 0x69], &mut output).is_none());
+    }
+}
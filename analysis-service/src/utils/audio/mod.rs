@@ -0,0 +1,237 @@
+//! Identifies what an audio track really holds. Matroska codec IDs like
+//! `A_DTS` or `A_TRUEHD` don't say whether a track is lossless or carries
+//! object audio, but the first few frame headers do.
+//!
+//! PCM, FLAC, AC-3 and AAC tracks are also decoded, for their audio
+//! fingerprints. E-AC-3, DTS and TrueHD have no decoders, and neither does
+//! AAC with more than two channels, so those tracks go without.
+
+use std::{sync::mpsc::Receiver, time::Duration};
+
+use aac::AacDecoder;
+use ac3_decoder::Ac3Decoder;
+use fingerprint::AudioFingerprinter;
+use flac::FlacDecoder;
+use matroska_demuxer::TrackEntry;
+use pcm::PcmDecoder;
+
+use crate::utils::hashing::worker::Worker;
+
+pub mod aac;
+pub mod ac3;
+pub mod ac3_decoder;
+pub mod dts;
+pub mod fingerprint;
+pub mod flac;
+pub mod pcm;
+pub mod truehd;
+
+/// How many frames of each track are checked
+const PROBE_FRAMES: usize = 16;
+/// Frames waiting on a `FingerprintWorker`
+const FINGERPRINT_BACKLOG: usize = 64;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AudioFormat {
+    Ac3,
+    Eac3,
+    TrueHd,
+    Dts,
+    /// DTS with discrete extra surround channels
+    DtsEs,
+    /// DTS-HD High Resolution Audio
+    DtsHdHra,
+    /// DTS-HD Master Audio
+    DtsHdMa,
+    DtsExpress,
+    Aac,
+    Flac,
+    Opus,
+    Vorbis,
+    Mp3,
+    Pcm,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioDetails {
+    pub format: Option<AudioFormat>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    /// Nominal bit rate in bit/s, for formats that have one
+    pub bitrate: Option<u32>,
+    pub lossless: bool,
+    /// Atmos or DTS:X
+    pub object_audio: bool,
+}
+
+/// Collects `AudioDetails` from the first few frames of a track
+pub struct AudioProbe {
+    parser: Option<fn(&[u8], &mut AudioDetails)>,
+    frames: usize,
+    details: AudioDetails,
+    /// What the Matroska headers say, for anything the frames don't
+    track_sample_rate: Option<u32>,
+    track_bit_depth: Option<u8>,
+}
+impl AudioProbe {
+    pub fn new(track: &TrackEntry) -> Self {
+        let codec_id = track.codec_id();
+        let format = match codec_id {
+            "A_AC3" | "A_AC3/BSID9" | "A_AC3/BSID10" => Some(AudioFormat::Ac3),
+            "A_EAC3" => Some(AudioFormat::Eac3),
+            "A_TRUEHD" => Some(AudioFormat::TrueHd),
+            "A_DTS" => Some(AudioFormat::Dts),
+            "A_DTS/EXPRESS" => Some(AudioFormat::DtsExpress),
+            "A_DTS/LOSSLESS" => Some(AudioFormat::DtsHdMa),
+            "A_FLAC" => Some(AudioFormat::Flac),
+            "A_OPUS" => Some(AudioFormat::Opus),
+            "A_VORBIS" => Some(AudioFormat::Vorbis),
+            "A_MPEG/L3" => Some(AudioFormat::Mp3),
+            _ if codec_id.starts_with("A_AAC") => Some(AudioFormat::Aac),
+            _ if codec_id.starts_with("A_PCM") => Some(AudioFormat::Pcm),
+            _ => None,
+        };
+        let parser: Option<fn(&[u8], &mut AudioDetails)> = match format {
+            Some(AudioFormat::Ac3 | AudioFormat::Eac3) => Some(ac3::parse_ac3_block),
+            Some(AudioFormat::TrueHd) => Some(truehd::parse_truehd_block),
+            Some(AudioFormat::Dts | AudioFormat::DtsExpress | AudioFormat::DtsHdMa) => {
+                Some(dts::parse_dts_block)
+            }
+            _ => None,
+        };
+        let audio = track.audio();
+        return Self {
+            parser,
+            frames: 0,
+            details: AudioDetails {
+                format,
+                lossless: matches!(
+                    format,
+                    Some(
+                        AudioFormat::TrueHd
+                            | AudioFormat::DtsHdMa
+                            | AudioFormat::Flac
+                            | AudioFormat::Pcm
+                    )
+                ),
+                ..Default::default()
+            },
+            // The output rate includes spectral band replication
+            track_sample_rate: audio.map(|audio| {
+                return audio
+                    .output_sampling_frequency()
+                    .unwrap_or(audio.sampling_frequency()) as u32;
+            }),
+            track_bit_depth: audio
+                .and_then(|audio| audio.bit_depth())
+                .map(|bit_depth| bit_depth.get() as u8),
+        };
+    }
+
+    pub fn push_frame(&mut self, data: &[u8]) {
+        let Some(parser) = self.parser else {
+            return;
+        };
+        if self.frames >= PROBE_FRAMES {
+            return;
+        }
+        self.frames += 1;
+        parser(data, &mut self.details);
+    }
+
+    pub fn finish(self) -> AudioDetails {
+        let mut details = self.details;
+        if let Some(sample_rate) = self.track_sample_rate.filter(|rate| *rate > 0) {
+            details.sample_rate.get_or_insert(sample_rate);
+        }
+        // Lossy formats don't have a bit depth, whatever the muxer wrote
+        if details.lossless
+            && let Some(bit_depth) = self.track_bit_depth
+        {
+            details.bit_depth.get_or_insert(bit_depth);
+        }
+        return details;
+    }
+}
+
+/// Decodes a track to mono samples
+enum AudioDecoder {
+    Pcm(PcmDecoder),
+    Flac(FlacDecoder),
+    Ac3(Box<Ac3Decoder>),
+    Aac(Box<AacDecoder>),
+}
+impl AudioDecoder {
+    fn decode(&mut self, data: &[u8], output: &mut Vec<f32>) {
+        match self {
+            Self::Pcm(decoder) => decoder.decode(data, output),
+            Self::Flac(decoder) => {
+                // Frames that fail to decode are skipped
+                let _ = decoder.decode(data, output);
+            }
+            Self::Ac3(decoder) => {
+                let _ = decoder.decode(data, output);
+            }
+            Self::Aac(decoder) => {
+                let _ = decoder.decode(data, output);
+            }
+        }
+    }
+}
+
+/// Decodes and fingerprints a track on its own thread
+pub struct FingerprintWorker {
+    worker: Worker<Vec<u8>, Vec<u32>>,
+}
+impl FingerprintWorker {
+    /// Returns `None` for tracks that can't be decoded
+    pub fn new(track: &TrackEntry) -> Option<Self> {
+        let (mut decoder, sample_rate) = match track.codec_id() {
+            "A_FLAC" => {
+                let decoder = FlacDecoder::new(track.codec_private()?)?;
+                let sample_rate = decoder.sample_rate();
+                (AudioDecoder::Flac(decoder), sample_rate)
+            }
+            "A_AC3" => {
+                let sample_rate = track.audio()?.sampling_frequency() as u32;
+                (AudioDecoder::Ac3(Box::default()), sample_rate)
+            }
+            codec_id if codec_id.starts_with("A_AAC") => {
+                let decoder = AacDecoder::new(track)?;
+                let sample_rate = decoder.sample_rate();
+                (AudioDecoder::Aac(Box::new(decoder)), sample_rate)
+            }
+            _ => {
+                let decoder = PcmDecoder::new(track)?;
+                let sample_rate = track.audio()?.sampling_frequency() as u32;
+                (AudioDecoder::Pcm(decoder), sample_rate)
+            }
+        };
+        if sample_rate == 0 {
+            return None;
+        }
+        let worker = Worker::spawn(FINGERPRINT_BACKLOG, move |frames: Receiver<Vec<u8>>| {
+            let mut fingerprinter = AudioFingerprinter::new(sample_rate);
+            let mut samples = Vec::new();
+            for data in frames {
+                samples.clear();
+                decoder.decode(&data, &mut samples);
+                fingerprinter.push_samples(&samples);
+            }
+            return fingerprinter.finish();
+        });
+        return Some(Self { worker });
+    }
+
+    pub fn push_frame(&mut self, data: &[u8]) {
+        self.worker.send(data.to_vec());
+    }
+
+    pub fn blocked(&self) -> Duration {
+        return self.worker.blocked();
+    }
+
+    pub fn finish(self) -> Vec<u32> {
+        return self.worker.finish();
+    }
+}
@@ -0,0 +1,74 @@
+//! Reads the interleaved samples of Matroska's PCM codec IDs.
+
+use matroska_demuxer::TrackEntry;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum SampleFormat {
+    /// Unsigned at 8 bits, signed otherwise
+    IntLittle,
+    IntBig,
+    Float,
+}
+
+pub struct PcmDecoder {
+    format: SampleFormat,
+    bytes_per_sample: usize,
+    channels: usize,
+}
+impl PcmDecoder {
+    pub fn new(track: &TrackEntry) -> Option<Self> {
+        let format = match track.codec_id() {
+            "A_PCM/INT/LIT" => SampleFormat::IntLittle,
+            "A_PCM/INT/BIG" => SampleFormat::IntBig,
+            "A_PCM/FLOAT/IEEE" => SampleFormat::Float,
+            _ => return None,
+        };
+        let audio = track.audio()?;
+        let bit_depth = audio.bit_depth()?.get();
+        let supported = match format {
+            SampleFormat::Float => matches!(bit_depth, 32 | 64),
+            _ => matches!(bit_depth, 8 | 16 | 24 | 32),
+        };
+        if !supported {
+            return None;
+        }
+        return Some(Self {
+            format,
+            bytes_per_sample: bit_depth as usize / 8,
+            channels: audio.channels().get() as usize,
+        });
+    }
+
+    fn read_sample(&self, bytes: &[u8]) -> f32 {
+        let mut value = [0; 8];
+        match self.format {
+            SampleFormat::Float if bytes.len() == 4 => {
+                return f32::from_le_bytes(bytes.try_into().unwrap());
+            }
+            SampleFormat::Float => return f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+            SampleFormat::IntLittle if bytes.len() == 1 => {
+                return (bytes[0] as f32 - 128.0) / 128.0;
+            }
+            // Into the top bytes of an i64, so the sign carries over
+            SampleFormat::IntLittle => value[8 - bytes.len()..].copy_from_slice(bytes),
+            SampleFormat::IntBig => {
+                for (target, byte) in value.iter_mut().rev().zip(bytes) {
+                    *target = *byte;
+                }
+            }
+        }
+        return i64::from_le_bytes(value) as f32 / i64::MAX as f32;
+    }
+
+    /// Appends the block's samples, averaged across channels
+    pub fn decode(&self, data: &[u8], output: &mut Vec<f32>) {
+        let frame_size = self.bytes_per_sample * self.channels;
+        for frame in data.chunks_exact(frame_size) {
+            let sum: f32 = frame
+                .chunks_exact(self.bytes_per_sample)
+                .map(|sample| self.read_sample(sample))
+                .sum();
+            output.push(sum / self.channels as f32);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Reads the major sync of Dolby TrueHD access units. Not every access unit
+//! has one, but a stream starts with one and repeats it often.
+
+use super::AudioDetails;
+
+const FORMAT_SYNC: [u8; 4] = [0xF8, 0x72, 0x6F, 0xBA];
+const SIGNATURE: [u8; 2] = [0xB7, 0x52];
+/// Set in `substream_info` when there's a 16 channel presentation, which only
+/// Atmos uses
+const SIXTEEN_CHANNEL_PRESENTATION: u8 = 0x80;
+
+pub fn parse_truehd_block(data: &[u8], details: &mut AudioDetails) {
+    // The access unit header comes first
+    let Some(major_sync) = data.get(4..22) else {
+        return;
+    };
+    if major_sync[..4] != FORMAT_SYNC || major_sync[8..10] != SIGNATURE {
+        return;
+    }
+    let rate_bits = major_sync[4] >> 4;
+    if rate_bits & 0x07 <= 2 {
+        let base = if rate_bits & 0x08 == 0 { 48000 } else { 44100 };
+        details.sample_rate = Some(base << (rate_bits & 0x07));
+    }
+    details.object_audio |= major_sync[17] & SIXTEEN_CHANNEL_PRESENTATION != 0;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_atmos() {
+        let mut access_unit = vec![0x00, 0x00, 0x00, 0x00];
+        access_unit.extend(FORMAT_SYNC);
+        access_unit.extend([0x00, 0x00, 0x00, 0x00]);
+        access_unit.extend(SIGNATURE);
+        access_unit.extend([0x00; 6]);
+        // Four substreams, with a 16 channel presentation
+        access_unit.extend([0x40, 0x80]);
+        let mut details = AudioDetails::default();
+        parse_truehd_block(&access_unit, &mut details);
+        assert_eq!(details.sample_rate, Some(48000));
+        assert!(details.object_audio);
+    }
+}
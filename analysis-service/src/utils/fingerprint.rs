@@ -0,0 +1,168 @@
+//! A fingerprint that only reads a few clusters, for identifying a file
+//! before committing to a full analysis.
+//!
+//! The file is sampled at `SAMPLE_POINTS` timestamps, at the middle of each
+//! equal slice of the duration. The demuxer seeks to each one using the Cues
+//! if there are any, or by skipping from cluster to cluster otherwise. From
+//! there, up to `FRAMES_PER_POINT` frames of each track are hashed, reading
+//! no more than `MAX_FRAMES_PER_POINT` frames in total. Each track's frames
+//! go into one BLAKE3 hash, each prefixed with the sample point index and
+//! its length.
+//!
+//! The fingerprint is a BLAKE3 hash over `FINGERPRINT_VERSION`, the duration
+//! in milliseconds, each track's layout, then each track's sampled frame
+//! count and hash, with tracks in track number order. The same file always
+//! gives the same fingerprint, and any change to this scheme bumps the
+//! version.
+
+use std::io::{ErrorKind, Read, Seek};
+
+use matroska_demuxer::{DemuxError, Frame, MatroskaFile, TrackEntry, TrackType};
+
+pub const FINGERPRINT_VERSION: u32 = 1;
+pub const SAMPLE_POINTS: u32 = 16;
+const FRAMES_PER_POINT: u32 = 2;
+/// Keeps sparse tracks like subtitles from dragging the reads on
+const MAX_FRAMES_PER_POINT: u32 = 256;
+
+/// The frames sampled from one track
+#[derive(Debug, Clone)]
+pub struct SampledTrack {
+    pub track_number: u64,
+    pub sampled_frames: u32,
+    pub digest: [u8; 32],
+}
+
+#[derive(Debug, Clone)]
+pub struct QuickFingerprint {
+    pub digest: [u8; 32],
+    /// In nanoseconds
+    pub duration: u64,
+    /// In track number order
+    pub tracks: Vec<SampledTrack>,
+}
+
+struct TrackSampler {
+    track_number: u64,
+    hasher: blake3::Hasher,
+    sampled_frames: u32,
+    /// Frames sampled since the last seek
+    point_frames: u32,
+}
+
+/// Matroska's own track type codes
+fn track_type_code(track_type: TrackType) -> u8 {
+    return match track_type {
+        TrackType::Video => 1,
+        TrackType::Audio => 2,
+        TrackType::Complex => 3,
+        TrackType::Logo => 16,
+        TrackType::Subtitle => 17,
+        TrackType::Buttons => 18,
+        TrackType::Control => 32,
+        TrackType::Metadata => 33,
+        TrackType::Unknown => 0,
+    };
+}
+
+/// The properties of a track that don't depend on how it was muxed
+fn hash_layout(hasher: &mut blake3::Hasher, track: &TrackEntry) {
+    hasher.update(&[track_type_code(track.track_type())]);
+    let codec_id = track.codec_id().as_bytes();
+    hasher.update(&(codec_id.len() as u32).to_be_bytes());
+    hasher.update(codec_id);
+    if let Some(video) = track.video() {
+        hasher.update(&video.pixel_width().get().to_be_bytes());
+        hasher.update(&video.pixel_height().get().to_be_bytes());
+    }
+    if let Some(audio) = track.audio() {
+        hasher.update(&audio.channels().get().to_be_bytes());
+        hasher.update(&(audio.sampling_frequency().round() as u64).to_be_bytes());
+    }
+}
+
+/// Expects the duration in the file's timestamp scale, as the `Info`
+/// element gives it
+pub fn quick_fingerprint<R: Read + Seek>(
+    mkv_file: &mut MatroskaFile<R>,
+    duration: f64,
+) -> Result<QuickFingerprint, DemuxError> {
+    let timestamp_scale = mkv_file.info().timestamp_scale().get();
+    let mut tracks: Vec<&TrackEntry> = mkv_file.tracks().iter().collect();
+    tracks.sort_by_key(|track| track.track_number().get());
+    let mut fingerprint = blake3::Hasher::new();
+    fingerprint.update(&FINGERPRINT_VERSION.to_be_bytes());
+    let duration_ns = duration.round() as u64 * timestamp_scale;
+    fingerprint.update(&(duration_ns / 1_000_000).to_be_bytes());
+    for track in &tracks {
+        hash_layout(&mut fingerprint, track);
+    }
+    let mut samplers: Vec<TrackSampler> = tracks
+        .iter()
+        .map(|track| TrackSampler {
+            track_number: track.track_number().get(),
+            hasher: blake3::Hasher::new(),
+            sampled_frames: 0,
+            point_frames: 0,
+        })
+        .collect();
+
+    let mut frame = Frame::default();
+    for point in 0..SAMPLE_POINTS {
+        let timestamp = duration * (2 * point + 1) as f64 / (2 * SAMPLE_POINTS) as f64;
+        mkv_file.seek(timestamp as u64)?;
+        for sampler in samplers.iter_mut() {
+            sampler.point_frames = 0;
+        }
+        for _ in 0..MAX_FRAMES_PER_POINT {
+            match mkv_file.next_frame(&mut frame) {
+                Ok(true) => {}
+                Ok(false) => break,
+                // Cut off, so this is as far as the file goes
+                Err(DemuxError::IoError(error)) if error.kind() == ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(error) => return Err(error),
+            }
+            let Some(sampler) = samplers
+                .iter_mut()
+                .find(|sampler| sampler.track_number == frame.track)
+            else {
+                continue;
+            };
+            if sampler.point_frames < FRAMES_PER_POINT {
+                sampler.hasher.update(&point.to_be_bytes());
+                sampler
+                    .hasher
+                    .update(&(frame.data.len() as u64).to_be_bytes());
+                sampler.hasher.update(&frame.data);
+                sampler.point_frames += 1;
+                sampler.sampled_frames += 1;
+            }
+            if samplers
+                .iter()
+                .all(|sampler| sampler.point_frames >= FRAMES_PER_POINT)
+            {
+                break;
+            }
+        }
+    }
+
+    let tracks: Vec<SampledTrack> = samplers
+        .into_iter()
+        .map(|sampler| SampledTrack {
+            track_number: sampler.track_number,
+            sampled_frames: sampler.sampled_frames,
+            digest: sampler.hasher.finalize().into(),
+        })
+        .collect();
+    for track in &tracks {
+        fingerprint.update(&track.sampled_frames.to_be_bytes());
+        fingerprint.update(&track.digest);
+    }
+    return Ok(QuickFingerprint {
+        digest: fingerprint.finalize().into(),
+        duration: duration_ns,
+        tracks,
+    });
+}
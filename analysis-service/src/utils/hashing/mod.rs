@@ -0,0 +1,372 @@
+use std::{
+    cell::RefCell,
+    io::{Read, Seek, SeekFrom},
+    rc::Rc,
+    sync::mpsc as smpsc,
+    time::Duration,
+};
+
+use matroska_demuxer::Frame;
+use sha2::{Digest, Sha256};
+use worker::Worker;
+
+use crate::utils::segments::{Segment, SegmentHasher};
+
+pub mod worker;
+
+/// How much to read at a time when hashing bytes the demuxer skipped
+const CATCH_UP_BUFFER: usize = 1 << 16;
+/// Skipped bytes are only read right away up to this far back. Anything
+/// further is likely read by the demuxer later.
+const CATCH_UP_LIMIT: u64 = 64 << 20;
+/// How many frames a track's hash worker can fall behind by
+const TRACK_BACKLOG: usize = 32;
+/// The file is sent to its hash worker in chunks of this size
+const FILE_CHUNK: usize = 1 << 20;
+/// How many chunks the file hash worker can fall behind by
+const FILE_BACKLOG: usize = 8;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+    Blake3,
+}
+impl HashAlgorithm {
+    /// Identifies the algorithm in responses and databases
+    pub fn id(&self) -> &'static str {
+        return match self {
+            Self::Md5 => "md5",
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        };
+    }
+}
+
+/// Which hashes `extract_details` computes
+#[derive(Debug, Clone)]
+pub struct HashOptions {
+    /// Computed for each track, on top of the MD5 hash every track gets
+    pub track_algorithms: Vec<HashAlgorithm>,
+    /// Computed over the whole file, if set
+    pub file_algorithm: Option<HashAlgorithm>,
+}
+impl Default for HashOptions {
+    fn default() -> Self {
+        return Self {
+            track_algorithms: vec![HashAlgorithm::Blake3],
+            file_algorithm: Some(HashAlgorithm::Blake3),
+        };
+    }
+}
+
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        return match algorithm {
+            HashAlgorithm::Md5 => Self::Md5(md5::Context::new()),
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        };
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        return match self {
+            Self::Md5(_) => HashAlgorithm::Md5,
+            Self::Sha256(_) => HashAlgorithm::Sha256,
+            Self::Blake3(_) => HashAlgorithm::Blake3,
+        };
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(context) => context.consume(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        return match self {
+            Self::Md5(context) => context.compute().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        };
+    }
+}
+
+/// Hashes a track with MD5, which identified tracks before the stronger
+/// algorithms were added, and any others requested
+pub struct TrackHasher {
+    hashers: Vec<Hasher>,
+}
+impl TrackHasher {
+    pub fn new(options: &HashOptions) -> Self {
+        let mut hashers = vec![Hasher::new(HashAlgorithm::Md5)];
+        for algorithm in &options.track_algorithms {
+            if !hashers
+                .iter()
+                .any(|hasher| hasher.algorithm() == *algorithm)
+            {
+                hashers.push(Hasher::new(*algorithm));
+            }
+        }
+        return Self { hashers };
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for hasher in self.hashers.iter_mut() {
+            hasher.update(data);
+        }
+    }
+
+    /// Every digest, starting with MD5
+    pub fn finish(self) -> Vec<(HashAlgorithm, Vec<u8>)> {
+        return self
+            .hashers
+            .into_iter()
+            .map(|hasher| (hasher.algorithm(), hasher.finish()))
+            .collect();
+    }
+}
+
+/// What a track's hash worker produces
+pub struct TrackHashes {
+    pub digests: Vec<(HashAlgorithm, Vec<u8>)>,
+    /// Only for tracks hashed in segments
+    pub segments: Option<Vec<Segment>>,
+}
+
+/// Runs a `TrackHasher`, and a `SegmentHasher` if asked to, on its own
+/// thread
+pub struct TrackHashWorker {
+    worker: Worker<Frame, TrackHashes>,
+}
+impl TrackHashWorker {
+    pub fn new(options: &HashOptions, segments: bool) -> Self {
+        let mut track_hasher = TrackHasher::new(options);
+        let mut segment_hasher = segments.then(SegmentHasher::new);
+        let worker = Worker::spawn(TRACK_BACKLOG, move |frames: smpsc::Receiver<Frame>| {
+            for frame in frames {
+                track_hasher.update(&frame.data);
+                if let Some(segment_hasher) = segment_hasher.as_mut() {
+                    segment_hasher.push_frame(&frame);
+                }
+            }
+            return TrackHashes {
+                digests: track_hasher.finish(),
+                segments: segment_hasher.map(SegmentHasher::finish),
+            };
+        });
+        return Self { worker };
+    }
+
+    /// Expects the frame's timestamp in nanoseconds
+    pub fn push_frame(&mut self, frame: &Frame) {
+        self.worker.send(frame.clone());
+    }
+
+    pub fn blocked(&self) -> Duration {
+        return self.worker.blocked();
+    }
+
+    pub fn finish(self) -> TrackHashes {
+        return self.worker.finish();
+    }
+}
+
+/// Hashes the whole file, returning its digest
+type FileHashWorker = Worker<Vec<u8>, (HashAlgorithm, Vec<u8>)>;
+
+/// Hashes a file as the demuxer reads it. The demuxer seeks over elements it
+/// doesn't need, and back again when sampling, so bytes are hashed in order
+/// from the furthest point hashed so far. Small skips are read separately
+/// once the demuxer goes past them. Far jumps, like to Cues at the end of the
+/// file, are left until the demuxer comes back, so the file isn't read twice.
+pub struct HashingReader<R> {
+    shared: Rc<RefCell<HashingState<R>>>,
+}
+struct HashingState<R> {
+    inner: R,
+    /// Unset when no file hash was requested
+    worker: Option<FileHashWorker>,
+    /// Bytes waiting to be sent to the worker
+    pending: Vec<u8>,
+    /// Where the demuxer is
+    position: u64,
+    /// Everything before this has been hashed
+    hashed: u64,
+}
+impl<R: Read + Seek> HashingState<R> {
+    fn hash(&mut self, data: &[u8]) {
+        let Some(worker) = self.worker.as_mut() else {
+            return;
+        };
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= FILE_CHUNK {
+            worker.send(std::mem::replace(
+                &mut self.pending,
+                Vec::with_capacity(FILE_CHUNK),
+            ));
+        }
+    }
+
+    /// Hashes everything up to `target`, leaving the position unchanged
+    fn catch_up(&mut self, target: Option<u64>) -> std::io::Result<()> {
+        if self.worker.is_none() {
+            return Ok(());
+        }
+        if target.is_some_and(|target| target <= self.hashed) {
+            return Ok(());
+        }
+        self.inner.seek(SeekFrom::Start(self.hashed))?;
+        let mut buffer = vec![0; CATCH_UP_BUFFER];
+        loop {
+            let wanted = match target {
+                Some(target) => ((target - self.hashed) as usize).min(buffer.len()),
+                None => buffer.len(),
+            };
+            if wanted == 0 {
+                break;
+            }
+            let read = self.inner.read(&mut buffer[..wanted])?;
+            if read == 0 {
+                break;
+            }
+            self.hash(&buffer[..read]);
+            self.hashed += read as u64;
+        }
+        self.inner.seek(SeekFrom::Start(self.position))?;
+        return Ok(());
+    }
+}
+impl<R: Read + Seek> HashingReader<R> {
+    pub fn new(
+        mut inner: R,
+        algorithm: Option<HashAlgorithm>,
+    ) -> std::io::Result<(Self, FileHash<R>)> {
+        let position = inner.stream_position()?;
+        let worker = algorithm.map(|algorithm| {
+            return Worker::spawn(FILE_BACKLOG, move |chunks: smpsc::Receiver<Vec<u8>>| {
+                let mut hasher = Hasher::new(algorithm);
+                for chunk in chunks {
+                    hasher.update(&chunk);
+                }
+                return (algorithm, hasher.finish());
+            });
+        });
+        let shared = Rc::new(RefCell::new(HashingState {
+            inner,
+            worker,
+            pending: Vec::new(),
+            position,
+            hashed: 0,
+        }));
+        let handle = FileHash {
+            shared: shared.clone(),
+        };
+        return Ok((Self { shared }, handle));
+    }
+}
+impl<R: Read + Seek> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.shared.borrow_mut();
+        let state = &mut *state;
+        let start = state.position;
+        if start <= state.hashed + CATCH_UP_LIMIT {
+            state.catch_up(Some(start))?;
+        }
+        let read = state.inner.read(buf)?;
+        state.position += read as u64;
+        let (hashed, end) = (state.hashed, state.position);
+        if state.worker.is_some() && (start..end).contains(&hashed) {
+            state.hash(&buf[(hashed - start) as usize..read]);
+            state.hashed = end;
+        }
+        return Ok(read);
+    }
+}
+impl<R: Read + Seek> Seek for HashingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let mut state = self.shared.borrow_mut();
+        state.position = state.inner.seek(pos)?;
+        return Ok(state.position);
+    }
+}
+
+/// The other half of a `HashingReader`
+pub struct FileHash<R> {
+    shared: Rc<RefCell<HashingState<R>>>,
+}
+impl<R: Read + Seek> FileHash<R> {
+    /// How long reads waited on the hash worker
+    pub fn blocked(&self) -> Duration {
+        return self
+            .shared
+            .borrow()
+            .worker
+            .as_ref()
+            .map_or(Duration::ZERO, Worker::blocked);
+    }
+
+    /// Hashes whatever the demuxer didn't read, and returns the digest
+    pub fn finish(self) -> std::io::Result<Option<(HashAlgorithm, Vec<u8>)>> {
+        let mut state = self.shared.borrow_mut();
+        state.catch_up(None)?;
+        let Some(mut worker) = state.worker.take() else {
+            return Ok(None);
+        };
+        worker.send(std::mem::take(&mut state.pending));
+        return Ok(Some(worker.finish()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn hash_file_out_of_order() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let (mut reader, file_hash) =
+            HashingReader::new(Cursor::new(data.clone()), Some(HashAlgorithm::Sha256)).unwrap();
+        let mut buffer = [0; 1000];
+        // Skip ahead like the demuxer does over unneeded elements, then come
+        // back and read some of it again
+        reader.read_exact(&mut buffer).unwrap();
+        reader.seek(SeekFrom::Start(150_000)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        reader.seek(SeekFrom::Start(500)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data[500..1500]);
+
+        let (algorithm, digest) = file_hash.finish().unwrap().unwrap();
+        assert_eq!(algorithm, HashAlgorithm::Sha256);
+        assert_eq!(digest, Sha256::digest(&data).to_vec());
+    }
+
+    fn hex_digest(algorithm: HashAlgorithm, data: &[u8]) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        return hex::encode(hasher.finish());
+    }
+
+    #[test]
+    fn known_answers() {
+        assert_eq!(
+            hex_digest(HashAlgorithm::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex_digest(HashAlgorithm::Blake3, b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+}
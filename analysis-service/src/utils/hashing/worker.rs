@@ -0,0 +1,53 @@
+use std::{
+    sync::mpsc as smpsc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Runs a consumer on its own thread, fed through a bounded channel. The
+/// sender only blocks when the consumer falls behind, and that time is
+/// counted so callers can tell when hashing is the bottleneck.
+pub struct Worker<T, R> {
+    sender: smpsc::SyncSender<T>,
+    handle: JoinHandle<R>,
+    blocked: Duration,
+}
+impl<T: Send + 'static, R: Send + 'static> Worker<T, R> {
+    pub fn spawn<F>(backlog: usize, run: F) -> Self
+    where
+        F: FnOnce(smpsc::Receiver<T>) -> R + Send + 'static,
+    {
+        let (sender, receiver) = smpsc::sync_channel(backlog);
+        let handle = std::thread::spawn(move || run(receiver));
+        return Self {
+            sender,
+            handle,
+            blocked: Duration::ZERO,
+        };
+    }
+
+    pub fn send(&mut self, item: T) {
+        // A worker that stopped early panicked, which `finish` passes on
+        let item = match self.sender.try_send(item) {
+            Ok(()) | Err(smpsc::TrySendError::Disconnected(_)) => return,
+            Err(smpsc::TrySendError::Full(item)) => item,
+        };
+        let started = Instant::now();
+        let _ = self.sender.send(item);
+        self.blocked += started.elapsed();
+    }
+
+    /// How long `send` waited on a full backlog
+    pub fn blocked(&self) -> Duration {
+        return self.blocked;
+    }
+
+    /// Waits for the worker to drain its backlog
+    pub fn finish(self) -> R {
+        drop(self.sender);
+        return match self.handle.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    rc::Rc,
+};
+
+use matroska_demuxer::{Frame, TrackEntry, TrackType};
+
+/// A forward jump longer than this many frame durations is a gap
+const GAP_FRAME_DURATIONS: u64 = 10;
+/// Gaps at least this long are errors rather than warnings
+const SEVERE_GAP_NS: u64 = 1_000_000_000;
+/// Only this many gaps are reported per track
+const MAX_GAPS_PER_TRACK: usize = 16;
+/// How far video timestamps can go backwards for B-frame reordering, in
+/// frame durations
+const REORDER_FRAME_DURATIONS: u64 = 8;
+/// A track ending this much before the container is fine
+const EARLY_END_TOLERANCE_NS: u64 = 2_000_000_000;
+/// A track missing more than this fraction of the container is an error
+const SEVERE_EARLY_END_FRACTION: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IssueKind {
+    /// Frames are missing partway through a track
+    TimestampGap,
+    /// Timestamps went backwards further than reordering explains
+    NonMonotonic,
+    /// The track stops well before the container's duration
+    TrackEndsEarly,
+    /// The file ends partway through an element
+    Truncated,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntegrityIssue {
+    pub kind: IssueKind,
+    pub severity: Severity,
+    /// Unset for issues with the whole file
+    pub track_number: Option<u64>,
+    /// Where the issue starts, in nanoseconds. For truncation, this is the
+    /// last timestamp read.
+    pub timestamp: u64,
+    /// How long the gap or backwards step is, or how much of the track is
+    /// missing, in nanoseconds
+    pub duration: u64,
+    /// How many times this happened. Only non-monotonic timestamps are
+    /// grouped.
+    pub occurrences: u64,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntegrityReport {
+    /// The worst severity of any issue
+    pub severity: Severity,
+    pub issues: Vec<IntegrityIssue>,
+}
+
+/// Tracks one video or audio track's timestamps
+struct TrackTimeline {
+    video: bool,
+    /// From the track's default duration, or else the shortest step seen
+    frame_duration: Option<u64>,
+    fixed_frame_duration: bool,
+    /// The latest timestamp seen
+    latest: Option<u64>,
+    gaps: Vec<IntegrityIssue>,
+    /// The first backwards step, and how many there were
+    non_monotonic: Option<IntegrityIssue>,
+}
+
+/// Watches timestamps during the frame pass for signs of a bad rip
+pub struct IntegrityChecker {
+    timelines: HashMap<u64, TrackTimeline>,
+    last_timestamp: u64,
+    truncated_block: bool,
+}
+impl IntegrityChecker {
+    /// Subtitle tracks are sparse and their cues can overlap, so only video
+    /// and audio tracks are checked
+    pub fn new(tracks: &[TrackEntry]) -> Self {
+        let timelines = tracks
+            .iter()
+            .filter(|track| matches!(track.track_type(), TrackType::Video | TrackType::Audio))
+            .map(|track| {
+                let frame_duration = track.default_duration().map(|duration| duration.get());
+                return (
+                    track.track_number().get(),
+                    TrackTimeline {
+                        video: track.track_type() == TrackType::Video,
+                        frame_duration,
+                        fixed_frame_duration: frame_duration.is_some(),
+                        latest: None,
+                        gaps: Vec::new(),
+                        non_monotonic: None,
+                    },
+                );
+            })
+            .collect();
+        return Self {
+            timelines,
+            last_timestamp: 0,
+            truncated_block: false,
+        };
+    }
+
+    /// Expects the frame's timestamp in nanoseconds
+    pub fn push_frame(&mut self, frame: &Frame) {
+        self.last_timestamp = self.last_timestamp.max(frame.timestamp);
+        let Some(timeline) = self.timelines.get_mut(&frame.track) else {
+            return;
+        };
+        let Some(latest) = timeline.latest else {
+            timeline.latest = Some(frame.timestamp);
+            return;
+        };
+
+        if frame.timestamp > latest {
+            let step = frame.timestamp - latest;
+            if let Some(frame_duration) = timeline.frame_duration
+                && step > frame_duration * GAP_FRAME_DURATIONS
+                && timeline.gaps.len() < MAX_GAPS_PER_TRACK
+            {
+                timeline.gaps.push(IntegrityIssue {
+                    kind: IssueKind::TimestampGap,
+                    severity: match step >= SEVERE_GAP_NS {
+                        true => Severity::Error,
+                        false => Severity::Warning,
+                    },
+                    track_number: Some(frame.track),
+                    timestamp: latest,
+                    duration: step,
+                    occurrences: 1,
+                    description: format!(
+                        "Track {}: {} gap at {}",
+                        frame.track,
+                        format_duration(step),
+                        format_timestamp(latest)
+                    ),
+                });
+            }
+            if !timeline.fixed_frame_duration {
+                timeline.frame_duration = Some(timeline.frame_duration.unwrap_or(step).min(step));
+            }
+            timeline.latest = Some(frame.timestamp);
+            return;
+        }
+
+        // Laced frames share their block's timestamp, so a repeat is fine
+        let step = latest - frame.timestamp;
+        let allowance = match timeline.video {
+            true => timeline.frame_duration.unwrap_or(0) * REORDER_FRAME_DURATIONS,
+            false => 0,
+        };
+        if step > allowance {
+            match timeline.non_monotonic.as_mut() {
+                Some(issue) => issue.occurrences += 1,
+                None => {
+                    timeline.non_monotonic = Some(IntegrityIssue {
+                        kind: IssueKind::NonMonotonic,
+                        severity: Severity::Warning,
+                        track_number: Some(frame.track),
+                        timestamp: frame.timestamp,
+                        duration: step,
+                        occurrences: 1,
+                        description: String::new(), // Written once the count is known
+                    })
+                }
+            }
+        }
+    }
+
+    /// Marks the last block as cut off by the end of the file
+    pub fn truncated_block(&mut self) {
+        self.truncated_block = true;
+    }
+
+    /// `duration` is the container's, in nanoseconds
+    pub fn finish(self, duration: u64, bounds: &ReadBounds) -> IntegrityReport {
+        let mut issues = Vec::new();
+        if self.truncated_block || bounds.overrun().is_some() {
+            let description = match bounds.overrun() {
+                Some(missing) => format!(
+                    "The file ends partway through an element, at least {missing} bytes short"
+                ),
+                None => String::from("The file ends partway through a block"),
+            };
+            issues.push(IntegrityIssue {
+                kind: IssueKind::Truncated,
+                severity: Severity::Error,
+                track_number: None,
+                timestamp: self.last_timestamp,
+                duration: duration.saturating_sub(self.last_timestamp),
+                occurrences: 1,
+                description,
+            });
+        }
+
+        let mut timelines: Vec<_> = self.timelines.into_iter().collect();
+        timelines.sort_by_key(|(track_number, _timeline)| *track_number);
+        for (track_number, timeline) in timelines {
+            issues.extend(timeline.gaps);
+            if let Some(mut issue) = timeline.non_monotonic {
+                issue.description = format!(
+                    "Track {track_number}: timestamps went backwards {} time(s), first at {}",
+                    issue.occurrences,
+                    format_timestamp(issue.timestamp)
+                );
+                issues.push(issue);
+            }
+
+            let end = timeline.latest.unwrap_or(0) + timeline.frame_duration.unwrap_or(0);
+            let missing = duration.saturating_sub(end);
+            if missing > EARLY_END_TOLERANCE_NS {
+                issues.push(IntegrityIssue {
+                    kind: IssueKind::TrackEndsEarly,
+                    severity: match missing as f64 > duration as f64 * SEVERE_EARLY_END_FRACTION {
+                        true => Severity::Error,
+                        false => Severity::Warning,
+                    },
+                    track_number: Some(track_number),
+                    timestamp: end,
+                    duration: missing,
+                    occurrences: 1,
+                    description: format!(
+                        "Track {track_number}: ends at {}, {} before the container",
+                        format_timestamp(end),
+                        format_duration(missing)
+                    ),
+                });
+            }
+        }
+
+        return IntegrityReport {
+            severity: issues
+                .iter()
+                .map(|issue| issue.severity)
+                .max()
+                .unwrap_or(Severity::Ok),
+            issues,
+        };
+    }
+}
+
+/// Wraps the file handed to the demuxer, and remembers the furthest position
+/// it was asked to seek to. The demuxer skips over elements by seeking past
+/// them, and treats reading past the end as the end of the file, so an
+/// element that extends past the end of the file only shows up here.
+pub struct BoundsTracker<R> {
+    inner: R,
+    furthest: Rc<Cell<u64>>,
+}
+impl<R: Seek> BoundsTracker<R> {
+    pub fn new(mut inner: R) -> std::io::Result<(Self, ReadBounds)> {
+        let length = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+        let furthest = Rc::new(Cell::new(0));
+        let bounds = ReadBounds {
+            length,
+            furthest: furthest.clone(),
+        };
+        return Ok((Self { inner, furthest }, bounds));
+    }
+}
+impl<R: Read> Read for BoundsTracker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.read(buf);
+    }
+}
+impl<R: Seek> Seek for BoundsTracker<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.inner.seek(pos)?;
+        self.furthest.set(self.furthest.get().max(position));
+        return Ok(position);
+    }
+}
+
+/// The other half of a `BoundsTracker`
+pub struct ReadBounds {
+    length: u64,
+    furthest: Rc<Cell<u64>>,
+}
+impl ReadBounds {
+    pub fn length(&self) -> u64 {
+        return self.length;
+    }
+
+    /// How many bytes past the end of the file the demuxer expected
+    pub fn overrun(&self) -> Option<u64> {
+        return self
+            .furthest
+            .get()
+            .checked_sub(self.length)
+            .filter(|missing| *missing > 0);
+    }
+}
+
+/// Formats nanoseconds as `HH:MM:SS.mmm`
+fn format_timestamp(timestamp: u64) -> String {
+    let milliseconds = timestamp / 1_000_000;
+    return format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        milliseconds / 3_600_000,
+        milliseconds / 60_000 % 60,
+        milliseconds / 1000 % 60,
+        milliseconds % 1000
+    );
+}
+
+/// Formats nanoseconds as seconds
+fn format_duration(duration: u64) -> String {
+    return format!("{:.3}s", duration as f64 / 1_000_000_000.0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(track: u64, timestamp_ms: u64) -> Frame {
+        return Frame {
+            track,
+            timestamp: timestamp_ms * 1_000_000,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn report_gaps_and_early_end() {
+        let mut checker = IntegrityChecker {
+            timelines: HashMap::new(),
+            last_timestamp: 0,
+            truncated_block: false,
+        };
+        for (track_number, video) in [(1, true), (2, false)] {
+            checker.timelines.insert(
+                track_number,
+                TrackTimeline {
+                    video,
+                    frame_duration: None,
+                    fixed_frame_duration: false,
+                    latest: None,
+                    gaps: Vec::new(),
+                    non_monotonic: None,
+                },
+            );
+        }
+        // Video at 25 fps with B-frames, and 5 seconds missing from 20s
+        for i in 0..1000u64 {
+            let reordered = match i % 3 {
+                1 => i + 1,
+                2 => i - 1,
+                _ => i,
+            };
+            if !(500..625).contains(&i) {
+                checker.push_frame(&frame(1, reordered * 40));
+            }
+        }
+        // Audio that stops at 30 seconds
+        for i in 0..1000 {
+            checker.push_frame(&frame(2, i * 32));
+        }
+        let (_tracker, bounds) = BoundsTracker::new(std::io::Cursor::new(vec![0; 16])).unwrap();
+        let report = checker.finish(40_000_000_000, &bounds);
+
+        let kinds: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.kind, issue.track_number, issue.severity))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (IssueKind::TimestampGap, Some(1), Severity::Error),
+                (IssueKind::TrackEndsEarly, Some(2), Severity::Error),
+            ]
+        );
+        assert_eq!(report.issues[0].duration, 5_040_000_000);
+        assert_eq!(report.severity, Severity::Error);
+    }
+
+    #[test]
+    fn detect_overrun() {
+        let (mut tracker, bounds) = BoundsTracker::new(std::io::Cursor::new(vec![0; 16])).unwrap();
+        tracker.seek(SeekFrom::Start(16)).unwrap();
+        assert_eq!(bounds.overrun(), None);
+        tracker.seek(SeekFrom::Start(40)).unwrap();
+        assert_eq!(bounds.overrun(), Some(24));
+    }
+}
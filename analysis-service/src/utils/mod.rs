@@ -1,18 +1,58 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Seek},
+    collections::{HashMap, HashSet},
+    io::{ErrorKind, Read, Seek},
+    time::{Duration, Instant},
 };
 
 use crate::{proto::mediacorral::analysis::v1 as pb, utils::subtitles::srt::format_subtitles_srt};
-use matroska_demuxer::{Frame, MatroskaFile, TrackType};
+use audio::{
+    AudioDetails, AudioFormat, AudioProbe, FingerprintWorker,
+    fingerprint::{FingerprintMatch, compare_fingerprints},
+};
+use fingerprint::{FINGERPRINT_VERSION, QuickFingerprint, SampledTrack, quick_fingerprint};
+use hashing::{HashAlgorithm, HashOptions, HashingReader, TrackHashWorker, TrackHashes};
+use integrity::{
+    BoundsTracker, IntegrityChecker, IntegrityIssue, IntegrityReport, IssueKind, Severity,
+};
+use matroska_demuxer::{DemuxError, Frame, MatroskaFile, TrackType};
+use read_ahead::{READ_AHEAD_SIZE, ReadAheadReader, ReadMetrics};
+use segments::{Segment, SharedRange, find_shared_ranges};
+use signature::{ContentSignature, MatchKind, SIGNATURE_VERSION, TrackKind, match_signatures};
+use stats::{TrackStats, TrackStatsCollector};
 use subtitles::{
-    StContext, get_subtitle_track,
+    StContext, Subtitle, SubtitleOptions,
+    alignment::{Alignment, estimate_alignment},
+    captions::CaptionExtractor,
+    compare::{PreparedSubtitles, score_matrix},
+    forced::CueCounter,
+    get_caption_track,
+    language::{
+        DetectedLanguage, LanguageSampler, declared_language, detect_subtitle_language,
+        language_mismatch,
+    },
+    mark_forced,
+    normalize::NormalizeOptions,
     ocr::{PartessCache, PartessError},
+    ocr_cache::OcrCacheStats,
     pgs::PgsError,
+    preprocess::PreprocessOptions,
+    selection::{SubtitleTracks, TrackRanking, rank_subtitle_tracks},
+    srt::{SrtParseError, last_end_timestamp, parse_srt_file},
+    standalone::{ocr_sup_file, ocr_vobsub_files},
     vobsub::VobsubError,
 };
+use video::{DolbyVisionProbe, DynamicRange, MasteringDisplay, VideoDetails};
 
+pub mod audio;
+pub mod fingerprint;
+pub mod hashing;
+pub mod integrity;
+pub mod read_ahead;
+pub mod segments;
+pub mod signature;
+pub mod stats;
 pub mod subtitles;
+pub mod video;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ExtractDetailsError {
@@ -30,6 +70,111 @@ pub enum ExtractDetailsError {
     DemuxError(#[from] matroska_demuxer::DemuxError),
     #[error("An error occurred while running OCR:\n{0}")]
     PartessError(#[from] PartessError),
+    #[error("An error occurred while parsing SRT subtitles:\n{0}")]
+    SrtParseError(#[from] SrtParseError),
+    #[error("Track {0} is not a supported subtitle or video track")]
+    UnsupportedSubtitleTrack(u64),
+    #[error("Track {0} was requested more than once")]
+    DuplicateSubtitleTrack(u64),
+}
+
+/// Converts subtitle settings from an RPC request
+pub fn map_subtitle_options(
+    normalization: Option<pb::SubtitleNormalization>,
+    review_threshold: Option<u32>,
+    forced_only: bool,
+    preprocessing: Option<pb::OcrPreprocessing>,
+) -> SubtitleOptions {
+    return SubtitleOptions {
+        normalization: normalization.map(map_normalization),
+        review_threshold: review_threshold.map(|threshold| threshold.min(100) as u8),
+        preprocessing: preprocessing.map(map_preprocessing),
+        forced_only,
+    };
+}
+
+fn map_preprocessing(preprocessing: pb::OcrPreprocessing) -> PreprocessOptions {
+    return PreprocessOptions {
+        scale: preprocessing.scale.clamp(1, 8),
+        threshold_radius: preprocessing.threshold_radius,
+        remove_outline: preprocessing.remove_outline,
+        split_regions: preprocessing.split_regions,
+    };
+}
+
+fn map_normalization(normalization: pb::SubtitleNormalization) -> NormalizeOptions {
+    return NormalizeOptions {
+        sound_descriptions: normalization.sound_descriptions,
+        speaker_labels: normalization.speaker_labels,
+        music_notes: normalization.music_notes,
+        dialogue_dashes: normalization.dialogue_dashes,
+        ocr_confusions: normalization.ocr_confusions,
+    };
+}
+
+/// Lists OCR confidence for each subtitle, keyed by SRT sequence number
+fn map_cue_confidence(subtitles: &[Subtitle]) -> Vec<pb::CueConfidence> {
+    return subtitles
+        .iter()
+        .enumerate()
+        .filter_map(|(i, subtitle)| {
+            let confidence = subtitle.confidence()?;
+            return Some(pb::CueConfidence {
+                cue_index: i as u32 + 1,
+                mean_confidence: confidence.mean as u32,
+                words: confidence
+                    .words
+                    .iter()
+                    .map(|word| pb::WordConfidence {
+                        text: word.text.clone(),
+                        confidence: word.confidence as u32,
+                        italic: word.italic,
+                    })
+                    .collect(),
+                review_image: confidence.review_image.clone(),
+            });
+        })
+        .collect();
+}
+
+/// Lists the on-screen position of each subtitle, keyed by SRT sequence number
+fn map_cue_layout(subtitles: &[Subtitle]) -> Vec<pb::CueLayout> {
+    return subtitles
+        .iter()
+        .enumerate()
+        .filter_map(|(i, subtitle)| {
+            let region = subtitle.region()?;
+            return Some(pb::CueLayout {
+                cue_index: i as u32 + 1,
+                alignment: region.alignment() as u32,
+                x: region.x,
+                y: region.y,
+                width: region.width,
+                height: region.height,
+                screen_width: region.screen_width,
+                screen_height: region.screen_height,
+            });
+        })
+        .collect();
+}
+
+/// Lists the SRT sequence numbers of forced subtitles
+fn map_forced_cues(subtitles: &[Subtitle]) -> Vec<u32> {
+    return subtitles
+        .iter()
+        .enumerate()
+        .filter(|(_i, subtitle)| subtitle.forced())
+        .map(|(i, _subtitle)| i as u32 + 1)
+        .collect();
+}
+
+fn map_ocr_cache_stats(stats: OcrCacheStats) -> pb::OcrCacheStats {
+    return pb::OcrCacheStats {
+        memory_hits: stats.memory_hits,
+        disk_hits: stats.disk_hits,
+        misses: stats.misses,
+        hit_rate: stats.hit_rate(),
+    };
 }
 
 fn map_stereo_mode(mode: matroska_demuxer::StereoMode) -> pb::VideoStereoMode {
@@ -75,14 +220,413 @@ fn map_stereo_mode(mode: matroska_demuxer::StereoMode) -> pb::VideoStereoMode {
     }
 }
 
+fn map_audio_format(format: Option<AudioFormat>) -> pb::AudioFormat {
+    return match format {
+        None => pb::AudioFormat::Unspecified,
+        Some(AudioFormat::Ac3) => pb::AudioFormat::Ac3,
+        Some(AudioFormat::Eac3) => pb::AudioFormat::Eac3,
+        Some(AudioFormat::TrueHd) => pb::AudioFormat::Truehd,
+        Some(AudioFormat::Dts) => pb::AudioFormat::Dts,
+        Some(AudioFormat::DtsEs) => pb::AudioFormat::DtsEs,
+        Some(AudioFormat::DtsHdHra) => pb::AudioFormat::DtsHdHra,
+        Some(AudioFormat::DtsHdMa) => pb::AudioFormat::DtsHdMa,
+        Some(AudioFormat::DtsExpress) => pb::AudioFormat::DtsExpress,
+        Some(AudioFormat::Aac) => pb::AudioFormat::Aac,
+        Some(AudioFormat::Flac) => pb::AudioFormat::Flac,
+        Some(AudioFormat::Opus) => pb::AudioFormat::Opus,
+        Some(AudioFormat::Vorbis) => pb::AudioFormat::Vorbis,
+        Some(AudioFormat::Mp3) => pb::AudioFormat::Mp3,
+        Some(AudioFormat::Pcm) => pb::AudioFormat::Pcm,
+    };
+}
+
+fn apply_audio_details(track: &mut pb::AudioTrack, details: AudioDetails) {
+    track.format = map_audio_format(details.format) as _;
+    track.sample_rate = details.sample_rate;
+    track.bit_depth = details.bit_depth.map(u32::from);
+    track.bitrate = details.bitrate;
+    track.lossless = details.lossless;
+    track.object_audio = details.object_audio;
+}
+
+fn map_track_stats(track_number: u64, stats: TrackStats) -> pb::TrackStats {
+    return pb::TrackStats {
+        track_number,
+        frame_count: stats.frame_count,
+        total_bytes: stats.total_bytes,
+        average_bitrate: stats.average_bitrate,
+        peak_bitrate: stats.peak_bitrate,
+        keyframe_count: stats.keyframe_count,
+        gop_lengths: stats
+            .gop_lengths
+            .into_iter()
+            .map(|(length, count)| pb::GopLengthCount { length, count })
+            .collect(),
+        first_timestamp_ns: stats.first_timestamp,
+        last_timestamp_ns: stats.last_timestamp,
+        largest_gap_ns: stats.largest_gap,
+    };
+}
+
+fn map_integrity_report(report: IntegrityReport) -> pb::IntegrityReport {
+    return pb::IntegrityReport {
+        severity: map_severity(report.severity) as _,
+        issues: report.issues.into_iter().map(map_integrity_issue).collect(),
+    };
+}
+
+fn map_integrity_issue(issue: IntegrityIssue) -> pb::IntegrityIssue {
+    return pb::IntegrityIssue {
+        kind: match issue.kind {
+            IssueKind::TimestampGap => pb::IntegrityIssueKind::TimestampGap,
+            IssueKind::NonMonotonic => pb::IntegrityIssueKind::NonMonotonic,
+            IssueKind::TrackEndsEarly => pb::IntegrityIssueKind::TrackEndsEarly,
+            IssueKind::Truncated => pb::IntegrityIssueKind::Truncated,
+        } as _,
+        severity: map_severity(issue.severity) as _,
+        track_number: issue.track_number,
+        timestamp_ns: issue.timestamp,
+        duration_ns: issue.duration,
+        occurrences: issue.occurrences,
+        description: issue.description,
+    };
+}
+
+fn map_severity(severity: Severity) -> pb::IntegritySeverity {
+    return match severity {
+        Severity::Ok => pb::IntegritySeverity::Ok,
+        Severity::Warning => pb::IntegritySeverity::Warning,
+        Severity::Error => pb::IntegritySeverity::Error,
+    };
+}
+
+fn map_dynamic_range(dynamic_range: Option<DynamicRange>) -> pb::VideoDynamicRange {
+    return match dynamic_range {
+        None => pb::VideoDynamicRange::Unspecified,
+        Some(DynamicRange::Sdr) => pb::VideoDynamicRange::Sdr,
+        Some(DynamicRange::Hdr10) => pb::VideoDynamicRange::Hdr10,
+        Some(DynamicRange::Pq) => pb::VideoDynamicRange::Pq,
+        Some(DynamicRange::Hlg) => pb::VideoDynamicRange::Hlg,
+    };
+}
+
+fn map_mastering_display(display: MasteringDisplay) -> pb::MasteringDisplay {
+    return pb::MasteringDisplay {
+        red_x: display.red.0,
+        red_y: display.red.1,
+        green_x: display.green.0,
+        green_y: display.green.1,
+        blue_x: display.blue.0,
+        blue_y: display.blue.1,
+        white_point_x: display.white_point.0,
+        white_point_y: display.white_point.1,
+        max_luminance: display.max_luminance,
+        min_luminance: display.min_luminance,
+    };
+}
+
+/// Converts hash settings from an RPC request
+pub fn map_hash_options(
+    track_algorithms: &[i32],
+    file_algorithm: i32,
+    skip_file_hash: bool,
+) -> HashOptions {
+    let defaults = HashOptions::default();
+    let track_algorithms: Vec<_> = track_algorithms
+        .iter()
+        .filter_map(|algorithm| map_hash_algorithm(*algorithm))
+        .collect();
+    return HashOptions {
+        track_algorithms: match track_algorithms.is_empty() {
+            true => defaults.track_algorithms,
+            false => track_algorithms,
+        },
+        file_algorithm: match skip_file_hash {
+            true => None,
+            false => map_hash_algorithm(file_algorithm).or(defaults.file_algorithm),
+        },
+    };
+}
+
+fn map_hash_algorithm(algorithm: i32) -> Option<HashAlgorithm> {
+    return match pb::HashAlgorithm::try_from(algorithm).ok()? {
+        pb::HashAlgorithm::Unspecified => None,
+        pb::HashAlgorithm::Md5 => Some(HashAlgorithm::Md5),
+        pb::HashAlgorithm::Sha256 => Some(HashAlgorithm::Sha256),
+        pb::HashAlgorithm::Blake3 => Some(HashAlgorithm::Blake3),
+    };
+}
+
+fn map_content_hash((algorithm, digest): (HashAlgorithm, Vec<u8>)) -> pb::ContentHash {
+    return pb::ContentHash {
+        algorithm: String::from(algorithm.id()),
+        digest,
+    };
+}
+
+/// Splits a track's digests into the MD5 for the `hash` field, and the full
+/// list
+fn map_track_hashes(digests: Vec<(HashAlgorithm, Vec<u8>)>) -> (Vec<u8>, Vec<pb::ContentHash>) {
+    let md5 = digests
+        .iter()
+        .find(|(algorithm, _digest)| *algorithm == HashAlgorithm::Md5)
+        .map(|(_algorithm, digest)| digest.clone())
+        .unwrap_or_default();
+    return (md5, digests.into_iter().map(map_content_hash).collect());
+}
+
+/// Track hash algorithms a signature can be built from, strongest first
+const SIGNATURE_ALGORITHMS: [HashAlgorithm; 3] = [
+    HashAlgorithm::Blake3,
+    HashAlgorithm::Sha256,
+    HashAlgorithm::Md5,
+];
+
+/// Finds a track's digest, falling back to the `hash` field for MD5 since
+/// older records have nothing else
+fn track_digest(
+    hash: &[u8],
+    hashes: &[pb::ContentHash],
+    algorithm: HashAlgorithm,
+) -> Option<Vec<u8>> {
+    if let Some(content_hash) = hashes
+        .iter()
+        .find(|content_hash| content_hash.algorithm == algorithm.id())
+    {
+        return Some(content_hash.digest.clone());
+    }
+    if algorithm == HashAlgorithm::Md5 && !hash.is_empty() {
+        return Some(hash.to_vec());
+    }
+    return None;
+}
+
+/// Signs each file with the strongest algorithm every track of every file
+/// was hashed with
+fn content_signatures(files: &[&pb::MediaDetails]) -> Option<Vec<ContentSignature>> {
+    return SIGNATURE_ALGORITHMS.into_iter().find_map(|algorithm| {
+        return files
+            .iter()
+            .map(|details| {
+                let video = details.video_tracks.iter().map(|track| {
+                    Some((
+                        TrackKind::Video,
+                        track_digest(&track.hash, &track.hashes, algorithm)?,
+                    ))
+                });
+                let audio = details.audio_tracks.iter().map(|track| {
+                    Some((
+                        TrackKind::Audio,
+                        track_digest(&track.hash, &track.hashes, algorithm)?,
+                    ))
+                });
+                let subtitle = details.subtitle_tracks.iter().map(|track| {
+                    Some((
+                        TrackKind::Subtitle,
+                        track_digest(&track.hash, &track.hashes, algorithm)?,
+                    ))
+                });
+                let tracks = video
+                    .chain(audio)
+                    .chain(subtitle)
+                    .collect::<Option<Vec<_>>>()?;
+                return Some(ContentSignature::new(algorithm, tracks));
+            })
+            .collect();
+    });
+}
+
+fn map_content_signature(signature: &ContentSignature) -> pb::ContentSignature {
+    return pb::ContentSignature {
+        algorithm: String::from(signature.algorithm.id()),
+        version: SIGNATURE_VERSION,
+        digest: signature.digest().to_vec(),
+    };
+}
+
+fn map_match_kind(kind: MatchKind) -> pb::SignatureMatchKind {
+    return match kind {
+        MatchKind::Full => pb::SignatureMatchKind::Full,
+        MatchKind::Subset => pb::SignatureMatchKind::Subset,
+        MatchKind::Superset => pb::SignatureMatchKind::Superset,
+        MatchKind::Partial => pb::SignatureMatchKind::Partial,
+        MatchKind::None => pb::SignatureMatchKind::None,
+    };
+}
+
+/// Reports how the track sets of two analyzed files relate. `None` if they
+/// have no track hash algorithm in common.
+pub fn match_signature(
+    first: &pb::MediaDetails,
+    second: &pb::MediaDetails,
+) -> Option<pb::MatchSignatureResponse> {
+    let signatures = content_signatures(&[first, second])?;
+    let result = match_signatures(&signatures[0], &signatures[1]);
+    return Some(pb::MatchSignatureResponse {
+        kind: map_match_kind(result.kind) as _,
+        algorithm: String::from(signatures[0].algorithm.id()),
+        shared_tracks: result.shared_tracks,
+        first_only_tracks: result.first_only_tracks,
+        second_only_tracks: result.second_only_tracks,
+    });
+}
+
+fn map_segment(segment: Segment) -> pb::VideoSegment {
+    return pb::VideoSegment {
+        start_ns: segment.start,
+        end_ns: segment.end,
+        digest: segment.digest.to_vec(),
+    };
+}
+
+fn map_video_segments(track: &pb::VideoTrack) -> Vec<Segment> {
+    return track
+        .segments
+        .iter()
+        .filter_map(|segment| {
+            let digest = segment.digest.as_slice().try_into().ok()?;
+            return Some(Segment {
+                start: segment.start_ns,
+                end: segment.end_ns,
+                digest,
+            });
+        })
+        .collect();
+}
+
+fn map_shared_range(range: SharedRange) -> pb::SharedRange {
+    return pb::SharedRange {
+        first_start_ns: range.first_start,
+        first_end_ns: range.first_end,
+        second_start_ns: range.second_start,
+        second_end_ns: range.second_end,
+        segment_count: range.segment_count,
+    };
+}
+
+/// Finds the video two analyzed files have in common, using their first
+/// video tracks' segments. `None` if either has no video.
+pub fn find_shared_video(
+    first: &pb::MediaDetails,
+    second: &pb::MediaDetails,
+) -> Option<pb::FindSharedRangesResponse> {
+    let first = map_video_segments(first.video_tracks.first()?);
+    let second = map_video_segments(second.video_tracks.first()?);
+    return Some(pb::FindSharedRangesResponse {
+        ranges: find_shared_ranges(&first, &second)
+            .into_iter()
+            .map(map_shared_range)
+            .collect(),
+    });
+}
+
+fn map_quick_fingerprint(fingerprint: QuickFingerprint) -> pb::QuickFingerprintResponse {
+    return pb::QuickFingerprintResponse {
+        fingerprint: fingerprint.digest.to_vec(),
+        version: FINGERPRINT_VERSION,
+        duration_ns: fingerprint.duration,
+        tracks: fingerprint
+            .tracks
+            .into_iter()
+            .map(map_sampled_track)
+            .collect(),
+    };
+}
+
+fn map_sampled_track(track: SampledTrack) -> pb::SampledTrack {
+    return pb::SampledTrack {
+        track_number: track.track_number,
+        sampled_frames: track.sampled_frames,
+        digest: track.digest.to_vec(),
+    };
+}
+
+fn map_audio_fingerprint(hashes: Vec<u32>) -> pb::AudioFingerprint {
+    return pb::AudioFingerprint {
+        version: audio::fingerprint::FINGERPRINT_VERSION,
+        frame_seconds: audio::fingerprint::frame_seconds(),
+        hashes,
+    };
+}
+
+fn map_audio_fingerprint_match(result: FingerprintMatch) -> pb::AudioFingerprintMatch {
+    let frame_seconds = audio::fingerprint::frame_seconds();
+    return pb::AudioFingerprintMatch {
+        offset_seconds: result.offset as f64 * frame_seconds,
+        similarity: result.similarity,
+        overlap_seconds: result.compared as f64 * frame_seconds,
+    };
+}
+
+/// Lines up two audio fingerprints. `None` if either is from another
+/// version of the scheme.
+pub fn compare_audio_fingerprints(
+    first: &pb::AudioFingerprint,
+    second: &pb::AudioFingerprint,
+    max_offset_seconds: Option<f64>,
+) -> Option<pb::CompareAudioFingerprintsResponse> {
+    let version = audio::fingerprint::FINGERPRINT_VERSION;
+    if first.version != version || second.version != version {
+        return None;
+    }
+    let max_offset = max_offset_seconds
+        .map(|seconds| (seconds.max(0.0) / audio::fingerprint::frame_seconds()).round() as u64);
+    return Some(pb::CompareAudioFingerprintsResponse {
+        best_match: compare_fingerprints(&first.hashes, &second.hashes, max_offset)
+            .map(map_audio_fingerprint_match),
+    });
+}
+
+/// Fingerprints an mkv file from a sample of its frames
+pub fn fingerprint_file<T>(mkv_file: T) -> Result<pb::QuickFingerprintResponse, ExtractDetailsError>
+where
+    T: Read + Seek,
+{
+    let mut mkv_file = MatroskaFile::open(mkv_file)?;
+    let Some(duration) = mkv_file.info().duration() else {
+        return Err(ExtractDetailsError::MissingRequiredProps);
+    };
+    let fingerprint = quick_fingerprint(&mut mkv_file, duration)?;
+    return Ok(map_quick_fingerprint(fingerprint));
+}
+
+fn map_analysis_metrics(
+    read: ReadMetrics,
+    hash_wait: Duration,
+    elapsed: Duration,
+    file_size: u64,
+) -> pb::AnalysisMetrics {
+    let mb_per_second = |bytes: u64, time: Duration| {
+        if time.is_zero() {
+            return 0.0;
+        }
+        return bytes as f64 / 1_000_000.0 / time.as_secs_f64();
+    };
+    return pb::AnalysisMetrics {
+        bytes_read: read.bytes_read,
+        file_size,
+        elapsed_seconds: elapsed.as_secs_f64(),
+        read_seconds: read.read_time.as_secs_f64(),
+        hash_wait_seconds: hash_wait.as_secs_f64(),
+        read_mb_per_second: mb_per_second(read.bytes_read, read.read_time),
+        analysis_mb_per_second: mb_per_second(file_size, elapsed),
+    };
+}
+
 pub fn extract_details<T>(
     mkv_file: T,
     partess_cache: &PartessCache,
-    st_track_number: u64,
+    st_tracks: &SubtitleTracks,
+    options: &SubtitleOptions,
+    hash_options: &HashOptions,
 ) -> Result<pb::AnalyzeMkvResponse, ExtractDetailsError>
 where
     T: Read + Seek,
 {
+    let started = Instant::now();
+    let (mkv_file, read_counter) = ReadAheadReader::new(mkv_file, READ_AHEAD_SIZE)?;
+    let (mkv_file, file_hash) = HashingReader::new(mkv_file, hash_options.file_algorithm)?;
+    let (mkv_file, read_bounds) = BoundsTracker::new(mkv_file)?;
     let mut mkv_file = MatroskaFile::open(mkv_file)?;
     let mut metadata = pb::MediaDetails::default();
     let info = mkv_file.info();
@@ -129,8 +673,18 @@ where
     }
 
     // Collect track metadata
-    let mut track_hashers: HashMap<u64, md5::Context> =
+    let mut track_hashers: HashMap<u64, TrackHashWorker> =
         HashMap::with_capacity(mkv_file.tracks().len());
+    let mut cue_counters: HashMap<u64, CueCounter> = HashMap::new();
+    let mut dolby_vision_probes: HashMap<u64, DolbyVisionProbe> = HashMap::new();
+    let mut audio_probes: HashMap<u64, AudioProbe> = HashMap::new();
+    let mut fingerprint_workers: HashMap<u64, FingerprintWorker> = HashMap::new();
+    let mut stats_collectors: HashMap<u64, TrackStatsCollector> = mkv_file
+        .tracks()
+        .iter()
+        .map(|track| (track.track_number().get(), TrackStatsCollector::new(track)))
+        .collect();
+    let mut integrity = IntegrityChecker::new(mkv_file.tracks());
     for track in mkv_file.tracks().into_iter() {
         match track.track_type() {
             TrackType::Video => {
@@ -141,7 +695,15 @@ where
                         continue;
                     }
                 };
-                track_hashers.insert(track.track_number().get(), md5::Context::new());
+                // Video is also hashed in segments
+                track_hashers.insert(
+                    track.track_number().get(),
+                    TrackHashWorker::new(hash_options, true),
+                );
+                if let Some(probe) = DolbyVisionProbe::new(track) {
+                    dolby_vision_probes.insert(track.track_number().get(), probe);
+                }
+                let details = VideoDetails::new(track);
                 metadata.video_tracks.push(pb::VideoTrack {
                     track_number: track.track_number().get(),
                     track_uid: track.track_uid().get(),
@@ -159,12 +721,33 @@ where
                         .unwrap_or(pb::VideoStereoMode::Mono) as _,
                     display_width: video_details.display_width().map(|i| i.get()).unwrap_or(0),
                     display_height: video_details.display_height().map(|i| i.get()).unwrap_or(0),
+                    dynamic_range: map_dynamic_range(details.dynamic_range()) as _,
+                    codec_profile: details.codec.profile,
+                    codec_level: details.codec.level,
+                    chroma_format: details.codec.chroma_format.map(String::from),
+                    bit_depth: details.codec.bit_depth.map(u32::from),
+                    pixel_width: details.codec.width.unwrap_or(0),
+                    pixel_height: details.codec.height.unwrap_or(0),
+                    frame_rate: details.codec.frame_rate,
+                    colour_primaries: details.primaries.map(u32::from),
+                    transfer_characteristics: details.transfer_characteristics.map(u32::from),
+                    matrix_coefficients: details.matrix_coefficients.map(u32::from),
+                    full_range: details.full_range,
+                    mastering_display: details.mastering_display.map(map_mastering_display),
+                    max_cll: details.max_cll,
+                    max_fall: details.max_fall,
                     hash: Vec::new(), // Will get overwritten after frame processing
+                    hashes: Vec::new(), // Will get overwritten after frame processing
+                    dolby_vision: false, // Will get overwritten after frame processing
+                    segments: Vec::new(), // Will get overwritten after frame processing
                 });
             }
             TrackType::Audio => {
                 // Instantiate track hasher
-                track_hashers.insert(track.track_number().get(), md5::Context::new());
+                track_hashers.insert(
+                    track.track_number().get(),
+                    TrackHashWorker::new(hash_options, false),
+                );
                 let audio_details = match track.audio() {
                     Some(value) => value,
                     None => {
@@ -172,6 +755,10 @@ where
                         continue;
                     }
                 };
+                audio_probes.insert(track.track_number().get(), AudioProbe::new(track));
+                if let Some(worker) = FingerprintWorker::new(track) {
+                    fingerprint_workers.insert(track.track_number().get(), worker);
+                }
                 metadata.audio_tracks.push(pb::AudioTrack {
                     track_number: track.track_number().get(),
                     track_uid: track.track_uid().get(),
@@ -184,12 +771,25 @@ where
                     language: track.language().map(String::from),
                     codec_id: String::from(track.codec_id()),
                     channels: audio_details.channels().get(),
-                    hash: Vec::new(), // Will be overwritten after frame processing
+                    hash: Vec::new(),   // Will be overwritten after frame processing
+                    hashes: Vec::new(), // Will be overwritten after frame processing
+                    // The rest will be overwritten after frame processing
+                    format: pb::AudioFormat::Unspecified as _,
+                    sample_rate: None,
+                    bit_depth: None,
+                    bitrate: None,
+                    lossless: false,
+                    object_audio: false,
+                    fingerprint: None,
                 });
             }
             TrackType::Subtitle => {
                 // Instantiate track hasher
-                track_hashers.insert(track.track_number().get(), md5::Context::new());
+                track_hashers.insert(
+                    track.track_number().get(),
+                    TrackHashWorker::new(hash_options, false),
+                );
+                cue_counters.insert(track.track_number().get(), CueCounter::new(track));
                 metadata.subtitle_tracks.push(pb::SubtitleTrack {
                     track_number: track.track_number().get(),
                     track_uid: track.track_uid().get(),
@@ -201,71 +801,462 @@ where
                     name: track.name().map(String::from),
                     language: track.language().map(String::from),
                     codec_id: String::from(track.codec_id()),
-                    hash: Vec::new(), // Will be overwritten after frame processing
+                    forced: track.flag_forced(),
+                    hash: Vec::new(),   // Will be overwritten after frame processing
+                    hashes: Vec::new(), // Will be overwritten after frame processing
+                    cue_count: 0,       // Will be overwritten after frame processing
+                    forced_cue_count: 0, // Will be overwritten after frame processing
+                    detected_language: None, // Will be overwritten after frame processing
                 });
             }
             _ => {}
         }
     }
 
-    // Get ideal subtitle track for analysis
-    let st_track = if st_track_number == 0 {
-        get_subtitle_track(mkv_file.tracks())?
-    } else {
-        mkv_file
+    // Get ideal subtitle tracks for analysis
+    let subtitle_ranking = match st_tracks {
+        SubtitleTracks::Auto => {
+            rank_subtitle_tracks(&mut mkv_file, partess_cache, options.forced_only)?
+        }
+        _ => Vec::new(),
+    };
+    let find_track = |track_number: u64| {
+        return mkv_file
+            .tracks()
+            .iter()
+            .filter(|track| {
+                StContext::supported_tracks(track) || CaptionExtractor::supported_tracks(track)
+            })
+            .find(|track| track.track_number().get() == track_number)
+            .ok_or(ExtractDetailsError::UnsupportedSubtitleTrack(track_number));
+    };
+    let selected_tracks: Vec<_> = match st_tracks {
+        SubtitleTracks::Auto => match subtitle_ranking.first() {
+            // The ranking includes untagged tracks detected as English
+            Some(ranking) => mkv_file
+                .tracks()
+                .iter()
+                .find(|track| track.track_number().get() == ranking.track_number)
+                .into_iter()
+                .collect(),
+            // Fall back to closed captions embedded in the video
+            None => get_caption_track(mkv_file.tracks()).into_iter().collect(),
+        },
+        SubtitleTracks::Track(track_number) => vec![find_track(*track_number)?],
+        SubtitleTracks::Tracks(track_numbers) => {
+            let mut requested = HashSet::new();
+            track_numbers
+                .iter()
+                .map(|track_number| {
+                    if !requested.insert(*track_number) {
+                        return Err(ExtractDetailsError::DuplicateSubtitleTrack(*track_number));
+                    }
+                    return find_track(*track_number);
+                })
+                .collect::<Result<_, _>>()?
+        }
+        SubtitleTracks::All => mkv_file
             .tracks()
             .iter()
             .filter(StContext::supported_tracks)
-            .find(|track| track.track_number().get() == st_track_number)
+            .filter(|track| track.flag_enabled())
+            .collect(),
     };
-    let st_track_number = st_track.map(|track| track.track_number().get());
-    let mut st_ctx = match st_track {
-        Some(st_track) => Some(StContext::new(st_track, partess_cache)?),
-        None => None,
+    let mut st_ctxs: HashMap<u64, SubtitleTrackContext> = HashMap::new();
+    for st_track in selected_tracks {
+        st_ctxs.insert(
+            st_track.track_number().get(),
+            SubtitleTrackContext {
+                st_ctx: StContext::new(st_track, partess_cache, options)?,
+                forced: st_track.flag_forced(),
+            },
+        );
+    }
+    // Detect the language of every other subtitle track from a sample
+    let mut detected_languages: HashMap<u64, DetectedLanguage> = subtitle_ranking
+        .iter()
+        .filter_map(|ranking| Some((ranking.track_number, ranking.detected_language?)))
+        .collect();
+    let mut language_samplers: HashMap<u64, LanguageSampler> = HashMap::new();
+    for track in mkv_file.tracks().iter().filter(StContext::supported_codecs) {
+        let track_number = track.track_number().get();
+        if st_ctxs.contains_key(&track_number) || detected_languages.contains_key(&track_number) {
+            continue;
+        }
+        language_samplers.insert(track_number, LanguageSampler::new(track, partess_cache)?);
+    }
+    // Tracks in the order they were requested or ranked, for the response
+    let st_track_numbers: Vec<u64> = match st_tracks {
+        SubtitleTracks::Auto | SubtitleTracks::Track(_) => st_ctxs.keys().copied().collect(),
+        SubtitleTracks::Tracks(track_numbers) => track_numbers.clone(),
+        SubtitleTracks::All => {
+            let mut track_numbers: Vec<_> = st_ctxs.keys().copied().collect();
+            track_numbers.sort();
+            track_numbers
+        }
     };
 
     // Frame processing loop
     let mut frame = Frame::default();
-    while mkv_file.next_frame(&mut frame)? {
+    loop {
+        match mkv_file.next_frame(&mut frame) {
+            Ok(true) => {}
+            Ok(false) => break,
+            // The last block was cut off
+            Err(DemuxError::IoError(error)) if error.kind() == ErrorKind::UnexpectedEof => {
+                integrity.truncated_block();
+                break;
+            }
+            Err(error) => return Err(error.into()),
+        }
         frame.timestamp = frame.timestamp * timestamp_scale;
         frame.duration = frame.duration.map(|duration| duration * timestamp_scale);
         // Process track
+        integrity.push_frame(&frame);
+        if let Some(collector) = stats_collectors.get_mut(&frame.track) {
+            collector.push_frame(&frame);
+        }
         if let Some(track_hasher) = track_hashers.get_mut(&frame.track) {
-            track_hasher.consume(&frame.data)
+            track_hasher.push_frame(&frame);
         }
-        if Some(frame.track) == st_track_number {
-            // Process subtitles
-            if let Some(ref mut st_ctx) = st_ctx {
-                st_ctx.process_frame(&mut frame)?;
-            }
+        if let Some(cue_counter) = cue_counters.get_mut(&frame.track) {
+            cue_counter.push_frame(&frame.data);
+        }
+        if let Some(probe) = dolby_vision_probes.get_mut(&frame.track) {
+            probe.push_frame(&frame.data);
+        }
+        if let Some(probe) = audio_probes.get_mut(&frame.track) {
+            probe.push_frame(&frame.data);
+        }
+        if let Some(worker) = fingerprint_workers.get_mut(&frame.track) {
+            worker.push_frame(&frame.data);
+        }
+        if let Some(sampler) = language_samplers.get_mut(&frame.track) {
+            sampler.push_frame(&frame)?;
+        }
+        // Process subtitles
+        if let Some(context) = st_ctxs.get_mut(&frame.track) {
+            context.st_ctx.process_frame(&mut frame)?;
         }
     }
 
+    let integrity = integrity.finish(duration_ns, &read_bounds);
+    metadata.hash_algorithm = String::from(HashAlgorithm::Md5.id());
+    let mut hash_wait = file_hash.blocked()
+        + track_hashers
+            .values()
+            .map(TrackHashWorker::blocked)
+            .sum::<Duration>()
+        + fingerprint_workers
+            .values()
+            .map(FingerprintWorker::blocked)
+            .sum::<Duration>();
+    let finishing = Instant::now();
+    metadata.file_hash = file_hash.finish()?.map(map_content_hash);
+    let mut track_hashes: HashMap<u64, TrackHashes> = track_hashers
+        .into_iter()
+        .map(|(track_number, worker)| (track_number, worker.finish()))
+        .collect();
+    let mut audio_fingerprints: HashMap<u64, Vec<u32>> = fingerprint_workers
+        .into_iter()
+        .map(|(track_number, worker)| (track_number, worker.finish()))
+        .collect();
+    hash_wait += finishing.elapsed();
+    let mut track_stats: Vec<_> = stats_collectors
+        .into_iter()
+        .map(|(track_number, collector)| map_track_stats(track_number, collector.finish()))
+        .collect();
+    track_stats.sort_by_key(|stats| stats.track_number);
+    metadata.track_stats = track_stats;
+
     // Collect hashes
     for track in metadata.video_tracks.iter_mut() {
-        if let Some(hasher) = track_hashers.remove(&track.track_number) {
-            track.hash = hasher.compute().to_vec();
+        if let Some(hashes) = track_hashes.remove(&track.track_number) {
+            (track.hash, track.hashes) = map_track_hashes(hashes.digests);
+            track.segments = hashes
+                .segments
+                .unwrap_or_default()
+                .into_iter()
+                .map(map_segment)
+                .collect();
+        }
+        if let Some(probe) = dolby_vision_probes.remove(&track.track_number) {
+            track.dolby_vision = probe.found();
         }
     }
     for track in metadata.audio_tracks.iter_mut() {
-        if let Some(hasher) = track_hashers.remove(&track.track_number) {
-            track.hash = hasher.compute().to_vec();
+        if let Some(hashes) = track_hashes.remove(&track.track_number) {
+            (track.hash, track.hashes) = map_track_hashes(hashes.digests);
+        }
+        if let Some(probe) = audio_probes.remove(&track.track_number) {
+            apply_audio_details(track, probe.finish());
         }
+        track.fingerprint = audio_fingerprints
+            .remove(&track.track_number)
+            .map(map_audio_fingerprint);
     }
     for track in metadata.subtitle_tracks.iter_mut() {
-        if let Some(hasher) = track_hashers.remove(&track.track_number) {
-            track.hash = hasher.compute().to_vec();
+        if let Some(hashes) = track_hashes.remove(&track.track_number) {
+            (track.hash, track.hashes) = map_track_hashes(hashes.digests);
+        }
+        if let Some(cue_counter) = cue_counters.remove(&track.track_number) {
+            let counts = cue_counter.counts();
+            track.cue_count = counts.cues;
+            track.forced_cue_count = counts.forced;
+        }
+    }
+    metadata.content_signature = content_signatures(&[&metadata])
+        .and_then(|signatures| signatures.first().map(map_content_signature));
+
+    let mut track_subtitles = Vec::with_capacity(st_ctxs.len());
+    for track_number in st_track_numbers {
+        let Some(context) = st_ctxs.remove(&track_number) else {
+            // Every selected track has a context
+            continue;
+        };
+        if let Some(subtitles) = aggregate_subtitles(
+            context,
+            track_number,
+            options,
+            duration_ns,
+            &mut detected_languages,
+        )? {
+            track_subtitles.push(subtitles);
         }
     }
+    for (track_number, sampler) in language_samplers {
+        if let Some(detected) = sampler.finish()? {
+            detected_languages.insert(track_number, detected);
+        }
+    }
+    for track in metadata.subtitle_tracks.iter_mut() {
+        let Some(detected) = detected_languages.get(&track.track_number) else {
+            continue;
+        };
+        let declared = mkv_file
+            .tracks()
+            .iter()
+            .find(|entry| entry.track_number().get() == track.track_number)
+            .and_then(declared_language);
+        track.detected_language = Some(pb::LanguageDetection {
+            language: String::from(detected.language),
+            confidence: detected.confidence,
+            mismatch: language_mismatch(declared, detected.language),
+        });
+    }
+    let selected_track = track_subtitles
+        .first()
+        .map(|subtitles| subtitles.track_number);
+    // Single track requests keep using the original field
+    let aggregated_subtitles = match st_tracks {
+        SubtitleTracks::Auto | SubtitleTracks::Track(_) => track_subtitles.pop(),
+        _ => None,
+    };
 
     return Ok(pb::AnalyzeMkvResponse {
         media_details: Some(metadata),
-        aggregated_subtitles: match st_ctx {
-            Some(st_ctx) => Some(pb::AggregatedSubtitles {
-                subtitles: format_subtitles_srt(st_ctx.collect()?, duration),
-                track_number: st_track_number.unwrap(),
-            }),
-            None => None,
-        },
+        aggregated_subtitles,
+        subtitle_track_ranking: map_track_ranking(subtitle_ranking, selected_track),
+        track_subtitles,
+        integrity: Some(map_integrity_report(integrity)),
+        metrics: Some(map_analysis_metrics(
+            read_counter.metrics(),
+            hash_wait,
+            started.elapsed(),
+            read_bounds.length(),
+        )),
+    });
+}
+
+/// A subtitle track being decoded by `extract_details`
+struct SubtitleTrackContext {
+    st_ctx: StContext,
+    /// Whether the whole track is flagged as forced
+    forced: bool,
+}
+
+/// Collects a decoded track for the response, and detects its language if
+/// it's a subtitle track
+fn aggregate_subtitles(
+    context: SubtitleTrackContext,
+    track_number: u64,
+    options: &SubtitleOptions,
+    duration_ns: u64,
+    detected_languages: &mut HashMap<u64, DetectedLanguage>,
+) -> Result<Option<pb::AggregatedSubtitles>, ExtractDetailsError> {
+    let closed_captions = matches!(context.st_ctx, StContext::Captions(_));
+    let cache_counter = context.st_ctx.ocr_cache_counter();
+    let mut subtitles = context.st_ctx.collect()?;
+    if !closed_captions && let Some(detected) = detect_subtitle_language(&subtitles) {
+        detected_languages.insert(track_number, detected);
+    }
+    if context.forced {
+        mark_forced(&mut subtitles);
+    }
+    let subtitles = options.apply(subtitles);
+    if closed_captions && subtitles.is_empty() {
+        // Most video tracks don't carry captions. Don't report an empty track.
+        return Ok(None);
+    }
+    return Ok(Some(pb::AggregatedSubtitles {
+        cue_confidence: map_cue_confidence(&subtitles),
+        cue_layout: map_cue_layout(&subtitles),
+        forced_cues: map_forced_cues(&subtitles),
+        ocr_cache: cache_counter.map(|cache_counter| map_ocr_cache_stats(cache_counter.stats())),
+        // Cue times are in µs
+        subtitles: format_subtitles_srt(subtitles, duration_ns / 1000),
+        track_number,
+        closed_captions,
+    }));
+}
+
+fn map_track_ranking(
+    rankings: Vec<TrackRanking>,
+    selected: Option<u64>,
+) -> Vec<pb::SubtitleTrackRanking> {
+    return rankings
+        .into_iter()
+        .map(|ranking| pb::SubtitleTrackRanking {
+            selected: Some(ranking.track_number) == selected,
+            track_number: ranking.track_number,
+            score: ranking.score,
+            reasons: ranking.reasons,
+            sampled_cues: ranking.sample.cues,
+            sampled_bytes: ranking.sample.bytes,
+        })
+        .collect();
+}
+
+/// Scores each ripped SRT document against each reference SRT document.
+/// Documents are given as `(id, srt_text)` pairs.
+pub fn compare_srt_documents(
+    ripped: Vec<(String, String)>,
+    references: Vec<(String, String)>,
+) -> Result<pb::CompareSubtitlesResponse, ExtractDetailsError> {
+    let prepare = |documents: &[(String, String)]| {
+        return documents
+            .iter()
+            .map(|(_id, srt)| {
+                let subtitles = parse_srt_file(srt.lines())?;
+                return Ok(PreparedSubtitles::new(&subtitles));
+            })
+            .collect::<Result<Vec<_>, ExtractDetailsError>>();
+    };
+    let matrix = score_matrix(&prepare(&ripped)?, &prepare(&references)?);
+
+    let rows = ripped
+        .into_iter()
+        .zip(matrix)
+        .map(|((ripped_id, _srt), scores)| pb::SubtitleScoreRow {
+            ripped_id,
+            scores: references
+                .iter()
+                .zip(scores)
+                .map(|((reference_id, _srt), score)| pb::SubtitleScore {
+                    reference_id: reference_id.clone(),
+                    score: score.score,
+                    text_similarity: score.text_similarity,
+                    timing_similarity: score.timing_similarity,
+                    alignment: score.alignment.map(map_alignment),
+                })
+                .collect(),
+        })
+        .collect();
+    return Ok(pb::CompareSubtitlesResponse { rows });
+}
+
+/// Re-times an SRT document to match the timing of another
+pub fn align_srt_documents(
+    subtitles: &str,
+    target: &str,
+) -> Result<pb::AlignSubtitlesResponse, ExtractDetailsError> {
+    let subtitles = parse_srt_file(subtitles.lines())?;
+    let target = parse_srt_file(target.lines())?;
+    let Some(alignment) = estimate_alignment(
+        &PreparedSubtitles::new(&subtitles),
+        &PreparedSubtitles::new(&target),
+    ) else {
+        return Ok(pb::AlignSubtitlesResponse {
+            alignment: None,
+            subtitles: None,
+        });
+    };
+    let retimed = alignment.retime(subtitles);
+    let end = last_end_timestamp(&retimed);
+    return Ok(pb::AlignSubtitlesResponse {
+        alignment: Some(map_alignment(alignment)),
+        subtitles: Some(format_subtitles_srt(retimed, end)),
     });
 }
+
+fn map_alignment(alignment: Alignment) -> pb::TimingAlignment {
+    return pb::TimingAlignment {
+        scale: alignment.scale,
+        offset_ms: alignment.offset / 1000.0,
+        confidence: alignment.confidence,
+    };
+}
+
+/// OCRs a raw PGS (.sup) file
+pub fn analyze_sup_file<T: Read>(
+    sup_file: T,
+    partess_cache: &PartessCache,
+    options: &SubtitleOptions,
+) -> Result<pb::AnalyzeSubtitleFileResponse, ExtractDetailsError> {
+    let (subtitles, cache_stats) = ocr_sup_file(sup_file, partess_cache, options)?;
+    return Ok(map_standalone_subtitles(subtitles, cache_stats));
+}
+
+/// OCRs a VobSub .idx/.sub pair
+pub fn analyze_vobsub_files<T: Read>(
+    idx_data: &[u8],
+    sub_file: T,
+    partess_cache: &PartessCache,
+    options: &SubtitleOptions,
+) -> Result<pb::AnalyzeSubtitleFileResponse, ExtractDetailsError> {
+    let (subtitles, cache_stats) = ocr_vobsub_files(idx_data, sub_file, partess_cache, options)?;
+    return Ok(map_standalone_subtitles(subtitles, cache_stats));
+}
+
+fn map_standalone_subtitles(
+    subtitles: Vec<Subtitle>,
+    cache_stats: OcrCacheStats,
+) -> pb::AnalyzeSubtitleFileResponse {
+    // Standalone files don't carry a duration of their own
+    let end = last_end_timestamp(&subtitles);
+    return pb::AnalyzeSubtitleFileResponse {
+        cue_confidence: map_cue_confidence(&subtitles),
+        cue_layout: map_cue_layout(&subtitles),
+        forced_cues: map_forced_cues(&subtitles),
+        ocr_cache: Some(map_ocr_cache_stats(cache_stats)),
+        subtitles: format_subtitles_srt(subtitles, end),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn last_cue_ends_with_file() {
+        // A PGS cue whose block has no duration
+        let subtitle: Subtitle = serde_json::from_str(
+            r#"{"timestamp": 2000000, "duration": null, "data": "The end",
+                "confidence": null, "region": null, "forced": false}"#,
+        )
+        .unwrap();
+        let context = SubtitleTrackContext {
+            st_ctx: StContext::Subrip(vec![subtitle]),
+            forced: false,
+        };
+        let options = SubtitleOptions::default();
+        let aggregated =
+            aggregate_subtitles(context, 3, &options, 5_000_000_000, &mut HashMap::new())
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            aggregated.subtitles,
+            "1\n00:00:02,000 --> 00:00:05,000\nThe end\n"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    cell::Cell,
+    io::{Read, Seek, SeekFrom},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// How much `ReadAheadReader` reads at a time. Network storage needs large
+/// reads to get anywhere near its throughput.
+pub const READ_AHEAD_SIZE: usize = 8 << 20;
+
+/// Reads the file in large blocks. Unlike `BufReader`, it keeps its buffer
+/// across seeks that land inside it, since the demuxer seeks back to every
+/// block's header after peeking at it.
+pub struct ReadAheadReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    /// Where the buffer starts in the file
+    buffer_start: u64,
+    /// Where the reader is, which can be outside the buffer
+    position: u64,
+    /// Where `inner` is, to skip needless seeks
+    inner_position: u64,
+    metrics: Rc<Cell<ReadMetrics>>,
+}
+impl<R: Read + Seek> ReadAheadReader<R> {
+    pub fn new(mut inner: R, capacity: usize) -> std::io::Result<(Self, ReadCounter)> {
+        let position = inner.stream_position()?;
+        let metrics = Rc::new(Cell::new(ReadMetrics::default()));
+        let counter = ReadCounter {
+            metrics: metrics.clone(),
+        };
+        let reader = Self {
+            inner,
+            buffer: Vec::with_capacity(capacity),
+            buffer_start: position,
+            position,
+            inner_position: position,
+            metrics,
+        };
+        return Ok((reader, counter));
+    }
+
+    /// Reads from `inner` at the current position, keeping count
+    fn read_inner(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let started = Instant::now();
+        if self.inner_position != self.position {
+            // Unknown until the seek succeeds
+            self.inner_position = u64::MAX;
+            self.inner.seek(SeekFrom::Start(self.position))?;
+            self.inner_position = self.position;
+        }
+        let read = self.inner.read(buf)?;
+        self.inner_position = self.position + read as u64;
+        let mut metrics = self.metrics.get();
+        metrics.bytes_read += read as u64;
+        metrics.read_time += started.elapsed();
+        self.metrics.set(metrics);
+        return Ok(read);
+    }
+
+    /// Returns whether anything was read. At the end of the file, the buffer
+    /// is kept, since the demuxer usually seeks back into it.
+    fn refill(&mut self) -> std::io::Result<bool> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let kept = buffer.len();
+        buffer.resize(buffer.capacity(), 0);
+        let read = self.read_inner(&mut buffer);
+        match read {
+            Ok(0) => buffer.truncate(kept),
+            Ok(read) => {
+                buffer.truncate(read);
+                self.buffer_start = self.position;
+            }
+            Err(_) => buffer.clear(),
+        }
+        self.buffer = buffer;
+        return Ok(read? > 0);
+    }
+}
+impl<R: Read + Seek> Read for ReadAheadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let buffered = self
+            .position
+            .checked_sub(self.buffer_start)
+            .filter(|offset| *offset < self.buffer.len() as u64);
+        let offset = match buffered {
+            Some(offset) => offset as usize,
+            // Reads this big gain nothing from the buffer
+            None if buf.len() >= self.buffer.capacity() => {
+                let read = self.read_inner(buf)?;
+                self.position += read as u64;
+                return Ok(read);
+            }
+            None => {
+                if !self.refill()? {
+                    return Ok(0);
+                }
+                0
+            }
+        };
+        let available = &self.buffer[offset..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.position += read as u64;
+        return Ok(read);
+    }
+}
+impl<R: Read + Seek> Seek for ReadAheadReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => {
+                self.position.checked_add_signed(offset).ok_or_else(|| {
+                    return std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Seeked before the start of the file",
+                    );
+                })?
+            }
+            SeekFrom::End(_) => {
+                self.inner_position = self.inner.seek(pos)?;
+                self.inner_position
+            }
+        };
+        return Ok(self.position);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadMetrics {
+    /// Bytes read from the underlying reader, counting rereads
+    pub bytes_read: u64,
+    /// Time spent waiting on the underlying reader
+    pub read_time: Duration,
+}
+
+/// The other half of a `ReadAheadReader`
+pub struct ReadCounter {
+    metrics: Rc<Cell<ReadMetrics>>,
+}
+impl ReadCounter {
+    pub fn metrics(&self) -> ReadMetrics {
+        return self.metrics.get();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn seek_within_buffer() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let (mut reader, counter) = ReadAheadReader::new(Cursor::new(data.clone()), 4096).unwrap();
+        let mut buffer = [0; 100];
+        // Peek, then go back, like the demuxer does with block headers
+        reader.read_exact(&mut buffer[..4]).unwrap();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data[..100]);
+        assert_eq!(counter.metrics().bytes_read, 4096);
+
+        // Reads spanning the end of the buffer
+        reader.seek(SeekFrom::Current(3950)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data[4050..4150]);
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 9990);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &data[9990..]);
+        // Hitting the end keeps the buffer
+        let bytes_read = counter.metrics().bytes_read;
+        reader.seek(SeekFrom::Start(9995)).unwrap();
+        reader.read_exact(&mut buffer[..5]).unwrap();
+        assert_eq!(&buffer[..5], &data[9995..]);
+        assert_eq!(counter.metrics().bytes_read, bytes_read);
+    }
+}
@@ -0,0 +1,219 @@
+//! Hashes a video track in segments of about ten seconds, so titles that
+//! reuse the same clips can be matched piece by piece. Boundaries are picked
+//! from the frames' content rather than their timestamps, so a clip gets the
+//! same segments wherever it starts in a title.
+
+use std::collections::HashMap;
+
+use matroska_demuxer::Frame;
+
+/// Segments are never cut shorter than this
+const MIN_SEGMENT_NS: u64 = 2_000_000_000;
+/// Segments are cut at the next candidate frame once they get this long
+const MAX_SEGMENT_NS: u64 = 30_000_000_000;
+/// The share of keyframes that start a segment. With the one second GOPs
+/// discs usually have, that's a segment about every ten seconds.
+const KEYFRAME_CUT_CHANCE: u64 = 8;
+/// The share of frames that start a segment when keyframes aren't flagged,
+/// as in files made of block groups
+const FRAME_CUT_CHANCE: u64 = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// In nanoseconds
+    pub start: u64,
+    pub end: u64,
+    pub digest: [u8; 32],
+}
+
+/// A cheap fingerprint of a frame, for deciding where to cut
+fn frame_fingerprint(data: &[u8]) -> u64 {
+    let mut value = data.len() as u64;
+    let head = &data[..data.len().min(32)];
+    let tail = &data[data.len().saturating_sub(32)..];
+    for &byte in head.iter().chain(tail) {
+        // FNV-1a
+        value ^= byte as u64;
+        value = value.wrapping_mul(0x100000001b3);
+    }
+    // Final mix from SplitMix64, so the low bits depend on every byte
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return value ^ (value >> 31);
+}
+
+struct OpenSegment {
+    start: u64,
+    hasher: blake3::Hasher,
+}
+
+/// Splits a video track into `Segment`s as frames go by
+pub struct SegmentHasher {
+    segments: Vec<Segment>,
+    current: Option<OpenSegment>,
+    /// Where the track ends, in nanoseconds
+    end: u64,
+}
+impl Default for SegmentHasher {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+impl SegmentHasher {
+    pub fn new() -> Self {
+        return Self {
+            segments: Vec::new(),
+            current: None,
+            end: 0,
+        };
+    }
+
+    fn starts_segment(&self, frame: &Frame) -> bool {
+        let Some(current) = self.current.as_ref() else {
+            return true;
+        };
+        let chance = match frame.is_keyframe {
+            Some(true) => KEYFRAME_CUT_CHANCE,
+            Some(false) => return false,
+            None => FRAME_CUT_CHANCE,
+        };
+        let length = frame.timestamp.saturating_sub(current.start);
+        if length < MIN_SEGMENT_NS {
+            return false;
+        }
+        return length >= MAX_SEGMENT_NS || frame_fingerprint(&frame.data).is_multiple_of(chance);
+    }
+
+    fn close_segment(&mut self, end: u64) {
+        if let Some(current) = self.current.take() {
+            self.segments.push(Segment {
+                start: current.start,
+                end: end.max(current.start),
+                digest: current.hasher.finalize().into(),
+            });
+        }
+    }
+
+    /// Expects the frame's timestamp in nanoseconds
+    pub fn push_frame(&mut self, frame: &Frame) {
+        if self.starts_segment(frame) {
+            self.close_segment(frame.timestamp);
+            self.current = Some(OpenSegment {
+                start: frame.timestamp,
+                hasher: blake3::Hasher::new(),
+            });
+        }
+        if let Some(current) = self.current.as_mut() {
+            // Length-prefixed, so moving bytes between frames changes the digest
+            current
+                .hasher
+                .update(&(frame.data.len() as u64).to_be_bytes());
+            current.hasher.update(&frame.data);
+        }
+        self.end = self.end.max(frame.timestamp + frame.duration.unwrap_or(0));
+    }
+
+    pub fn finish(mut self) -> Vec<Segment> {
+        self.close_segment(self.end);
+        return self.segments;
+    }
+}
+
+/// A stretch of content two tracks have in common, in nanoseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedRange {
+    pub first_start: u64,
+    pub first_end: u64,
+    pub second_start: u64,
+    pub second_end: u64,
+    pub segment_count: u32,
+}
+
+/// Finds every run of consecutive segments that appears in both tracks,
+/// ordered by where it starts in the first
+pub fn find_shared_ranges(first: &[Segment], second: &[Segment]) -> Vec<SharedRange> {
+    let mut positions: HashMap<&[u8; 32], Vec<usize>> = HashMap::new();
+    for (j, segment) in second.iter().enumerate() {
+        positions.entry(&segment.digest).or_default().push(j);
+    }
+
+    let mut ranges = Vec::new();
+    for (i, segment) in first.iter().enumerate() {
+        let Some(matches) = positions.get(&segment.digest) else {
+            continue;
+        };
+        for &j in matches {
+            // Already covered by the run starting before this one
+            if i > 0 && j > 0 && first[i - 1].digest == second[j - 1].digest {
+                continue;
+            }
+            let length = first[i..]
+                .iter()
+                .zip(&second[j..])
+                .take_while(|(a, b)| a.digest == b.digest)
+                .count();
+            ranges.push(SharedRange {
+                first_start: first[i].start,
+                first_end: first[i + length - 1].end,
+                second_start: second[j].start,
+                second_end: second[j + length - 1].end,
+                segment_count: length as u32,
+            });
+        }
+    }
+    return ranges;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A keyframe each second, with content that depends on `seed` and the
+    /// frame's position in the clip
+    fn clip(seed: u8, seconds: u64) -> Vec<Vec<u8>> {
+        return (0..seconds * 4)
+            .map(|i| {
+                let mut data = vec![seed; 100 + (i as usize * 37) % 400];
+                data[0] = (i % 256) as u8;
+                data[1] = (i / 256) as u8;
+                data
+            })
+            .collect();
+    }
+
+    fn segment(clips: &[&Vec<Vec<u8>>]) -> Vec<Segment> {
+        let mut hasher = SegmentHasher::new();
+        for (i, data) in clips.iter().flat_map(|clip| clip.iter()).enumerate() {
+            hasher.push_frame(&Frame {
+                timestamp: i as u64 * 250_000_000,
+                duration: Some(250_000_000),
+                is_keyframe: Some(i % 4 == 0),
+                data: data.clone(),
+                ..Default::default()
+            });
+        }
+        return hasher.finish();
+    }
+
+    #[test]
+    fn find_episode_in_play_all() {
+        let (first, second, third) = (clip(1, 300), clip(2, 300), clip(3, 300));
+        let episode = segment(&[&second]);
+        let play_all = segment(&[&first, &second, &third]);
+        assert!(episode.len() > 10);
+        assert!(episode.windows(2).all(|pair| pair[0].end == pair[1].start));
+
+        let ranges = find_shared_ranges(&play_all, &episode);
+        assert_eq!(ranges.len(), 1);
+        let range = &ranges[0];
+        // The play-all's segments running into and out of the episode don't
+        // line up with the episode's first and last, but the rest do
+        assert_eq!(range.segment_count as usize, episode.len() - 2);
+        assert_eq!(range.second_start, episode[1].start);
+        assert_eq!(range.first_start, range.second_start + 300_000_000_000);
+        assert_eq!(
+            range.first_end - range.first_start,
+            range.second_end - range.second_start
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::utils::hashing::HashAlgorithm;
+
+/// Bumped whenever the canonical encoding changes, since old digests won't
+/// match new ones anymore
+pub const SIGNATURE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum TrackKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+impl TrackKind {
+    fn tag(&self) -> u8 {
+        return match self {
+            Self::Video => b'V',
+            Self::Audio => b'A',
+            Self::Subtitle => b'S',
+        };
+    }
+}
+
+/// Identifies a file's content by its track hashes alone, so the container,
+/// track numbers and track order don't affect it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentSignature {
+    pub algorithm: HashAlgorithm,
+    /// Sorted, so equal track sets always encode the same way
+    tracks: Vec<(TrackKind, Vec<u8>)>,
+}
+impl ContentSignature {
+    /// Expects every track hash to come from `algorithm`
+    pub fn new(
+        algorithm: HashAlgorithm,
+        tracks: impl IntoIterator<Item = (TrackKind, Vec<u8>)>,
+    ) -> Self {
+        let mut tracks: Vec<_> = tracks.into_iter().collect();
+        tracks.sort();
+        return Self { algorithm, tracks };
+    }
+
+    /// BLAKE3 over the version, the algorithm, and each track's kind and
+    /// length-prefixed hash
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&SIGNATURE_VERSION.to_be_bytes());
+        let algorithm = self.algorithm.id().as_bytes();
+        hasher.update(&[algorithm.len() as u8]);
+        hasher.update(algorithm);
+        for (kind, hash) in &self.tracks {
+            hasher.update(&[kind.tag()]);
+            hasher.update(&(hash.len() as u32).to_be_bytes());
+            hasher.update(hash);
+        }
+        return hasher.finalize().into();
+    }
+
+    fn has_video(&self) -> bool {
+        return self
+            .tracks
+            .iter()
+            .any(|(kind, _)| *kind == TrackKind::Video);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MatchKind {
+    /// Same tracks
+    Full,
+    /// Every track of the first file is in the second
+    Subset,
+    /// Every track of the second file is in the first
+    Superset,
+    /// Some tracks in common, but each file has tracks the other lacks
+    Partial,
+    /// Nothing in common, or the video differs
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignatureMatch {
+    pub kind: MatchKind,
+    pub shared_tracks: u32,
+    pub first_only_tracks: u32,
+    pub second_only_tracks: u32,
+}
+
+/// Compares the track sets of two signatures built with the same algorithm.
+/// A shared audio or subtitle track isn't enough to tie two files together,
+/// so files that both have video must share a video track to match at all.
+pub fn match_signatures(first: &ContentSignature, second: &ContentSignature) -> SignatureMatch {
+    // Identical tracks can appear more than once, so count them
+    let mut remaining: HashMap<&(TrackKind, Vec<u8>), u32> = HashMap::new();
+    for track in &second.tracks {
+        *remaining.entry(track).or_insert(0) += 1;
+    }
+    let mut shared_tracks = 0;
+    let mut shared_video = false;
+    for track in &first.tracks {
+        if let Some(count) = remaining.get_mut(track)
+            && *count > 0
+        {
+            *count -= 1;
+            shared_tracks += 1;
+            shared_video |= track.0 == TrackKind::Video;
+        }
+    }
+    let first_only_tracks = first.tracks.len() as u32 - shared_tracks;
+    let second_only_tracks = second.tracks.len() as u32 - shared_tracks;
+
+    let kind = if shared_tracks == 0 || (first.has_video() && second.has_video() && !shared_video) {
+        MatchKind::None
+    } else {
+        match (first_only_tracks, second_only_tracks) {
+            (0, 0) => MatchKind::Full,
+            (0, _) => MatchKind::Subset,
+            (_, 0) => MatchKind::Superset,
+            _ => MatchKind::Partial,
+        }
+    };
+    return SignatureMatch {
+        kind,
+        shared_tracks,
+        first_only_tracks,
+        second_only_tracks,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn signature(tracks: &[(TrackKind, u8)]) -> ContentSignature {
+        return ContentSignature::new(
+            HashAlgorithm::Blake3,
+            tracks.iter().map(|(kind, hash)| (*kind, vec![*hash; 32])),
+        );
+    }
+
+    #[test]
+    fn match_track_subsets() {
+        use TrackKind::*;
+        let original = signature(&[(Video, 1), (Audio, 2), (Audio, 3), (Subtitle, 4)]);
+        // Reordered by a different remux
+        let reordered = signature(&[(Subtitle, 4), (Audio, 3), (Video, 1), (Audio, 2)]);
+        assert_eq!(original.digest(), reordered.digest());
+        assert_eq!(
+            match_signatures(&original, &reordered).kind,
+            MatchKind::Full
+        );
+
+        // Commentary dropped
+        let dropped = signature(&[(Video, 1), (Audio, 2), (Subtitle, 4)]);
+        assert_ne!(original.digest(), dropped.digest());
+        let result = match_signatures(&dropped, &original);
+        assert_eq!(result.kind, MatchKind::Subset);
+        assert_eq!(
+            (
+                result.shared_tracks,
+                result.first_only_tracks,
+                result.second_only_tracks
+            ),
+            (3, 0, 1)
+        );
+        assert_eq!(
+            match_signatures(&original, &dropped).kind,
+            MatchKind::Superset
+        );
+
+        let partial = signature(&[(Video, 1), (Audio, 2), (Subtitle, 5)]);
+        assert_eq!(
+            match_signatures(&original, &partial).kind,
+            MatchKind::Partial
+        );
+
+        // Same audio over different video
+        let other_video = signature(&[(Video, 6), (Audio, 2), (Audio, 3), (Subtitle, 4)]);
+        assert_eq!(
+            match_signatures(&original, &other_video).kind,
+            MatchKind::None
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use matroska_demuxer::{Frame, TrackEntry, TrackType};
+
+/// Peak bit rates are measured over this window
+const PEAK_WINDOW_NS: u64 = 1_000_000_000;
+
+/// Frame and bit rate statistics for one track
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackStats {
+    pub frame_count: u64,
+    pub total_bytes: u64,
+    /// In bit/s, over the span between the first and last frames
+    pub average_bitrate: Option<f64>,
+    /// The highest bit rate in any one second window, in bit/s
+    pub peak_bitrate: Option<f64>,
+    /// Only known for files made of simple blocks
+    pub keyframe_count: Option<u64>,
+    /// How many groups of pictures have each length, in frames. Only counted
+    /// for video tracks, and the last group is left out since it may be cut
+    /// short.
+    pub gop_lengths: BTreeMap<u64, u64>,
+    /// Earliest and latest frame timestamps, in nanoseconds
+    pub first_timestamp: Option<u64>,
+    pub last_timestamp: Option<u64>,
+    /// The longest stretch between two consecutive frames, in nanoseconds
+    pub largest_gap: u64,
+}
+
+/// Collects `TrackStats` as frames go by
+pub struct TrackStatsCollector {
+    stats: TrackStats,
+    video: bool,
+    /// Frames inside the peak bit rate window, as timestamp and size
+    window: VecDeque<(u64, u64)>,
+    window_bytes: u64,
+    peak_window_bytes: u64,
+    previous_timestamp: Option<u64>,
+    /// Frames since the last keyframe, once one has been seen
+    gop_frames: Option<u64>,
+}
+impl TrackStatsCollector {
+    pub fn new(track: &TrackEntry) -> Self {
+        return Self {
+            stats: TrackStats::default(),
+            video: track.track_type() == TrackType::Video,
+            window: VecDeque::new(),
+            window_bytes: 0,
+            peak_window_bytes: 0,
+            previous_timestamp: None,
+            gop_frames: None,
+        };
+    }
+
+    /// Expects the frame's timestamp in nanoseconds
+    pub fn push_frame(&mut self, frame: &Frame) {
+        let size = frame.data.len() as u64;
+        let stats = &mut self.stats;
+        stats.frame_count += 1;
+        stats.total_bytes += size;
+        stats.first_timestamp = Some(stats.first_timestamp.map_or(frame.timestamp, |first| {
+            return first.min(frame.timestamp);
+        }));
+        stats.last_timestamp = Some(stats.last_timestamp.map_or(frame.timestamp, |last| {
+            return last.max(frame.timestamp);
+        }));
+        // Frames with B-frames come in decode order, so timestamps can go
+        // backwards briefly. Only forward jumps count as gaps.
+        if let Some(previous) = self.previous_timestamp {
+            stats.largest_gap = stats
+                .largest_gap
+                .max(frame.timestamp.saturating_sub(previous));
+        }
+        self.previous_timestamp = Some(frame.timestamp);
+
+        if let Some(keyframe) = frame.is_keyframe {
+            let keyframes = stats.keyframe_count.get_or_insert(0);
+            if keyframe {
+                *keyframes += 1;
+                if self.video
+                    && let Some(length) = self.gop_frames
+                {
+                    *stats.gop_lengths.entry(length).or_insert(0) += 1;
+                }
+                self.gop_frames = Some(0);
+            }
+            if let Some(length) = self.gop_frames.as_mut() {
+                *length += 1;
+            }
+        }
+
+        self.window.push_back((frame.timestamp, size));
+        self.window_bytes += size;
+        while let Some((timestamp, size)) = self.window.front().copied() {
+            if timestamp + PEAK_WINDOW_NS > frame.timestamp {
+                break;
+            }
+            self.window.pop_front();
+            self.window_bytes -= size;
+        }
+        self.peak_window_bytes = self.peak_window_bytes.max(self.window_bytes);
+    }
+
+    pub fn finish(self) -> TrackStats {
+        let mut stats = self.stats;
+        if let (Some(first), Some(last)) = (stats.first_timestamp, stats.last_timestamp)
+            && last > first
+        {
+            let seconds = (last - first) as f64 / 1_000_000_000.0;
+            stats.average_bitrate = Some(stats.total_bytes as f64 * 8.0 / seconds);
+            // A track shorter than the window would overstate its peak
+            let window_seconds = seconds.min(PEAK_WINDOW_NS as f64 / 1_000_000_000.0);
+            stats.peak_bitrate = Some(self.peak_window_bytes as f64 * 8.0 / window_seconds);
+        }
+        return stats;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(timestamp_ms: u64, size: usize, keyframe: bool) -> Frame {
+        return Frame {
+            timestamp: timestamp_ms * 1_000_000,
+            data: vec![0; size],
+            is_keyframe: Some(keyframe),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn collect_gops_and_peak() {
+        let mut collector = TrackStatsCollector {
+            stats: TrackStats::default(),
+            video: true,
+            window: VecDeque::new(),
+            window_bytes: 0,
+            peak_window_bytes: 0,
+            previous_timestamp: None,
+            gop_frames: None,
+        };
+        // Two 4 frame GOPs at 2 fps, a 3 second gap, then a burst
+        for i in 0..8 {
+            collector.push_frame(&frame(i * 500, 1000, i % 4 == 0));
+        }
+        collector.push_frame(&frame(7000, 5000, true));
+        collector.push_frame(&frame(7500, 5000, false));
+        let stats = collector.finish();
+
+        assert_eq!(stats.frame_count, 10);
+        assert_eq!(stats.total_bytes, 18000);
+        assert_eq!(stats.keyframe_count, Some(3));
+        assert_eq!(stats.gop_lengths, BTreeMap::from([(4, 2)]));
+        assert_eq!(stats.first_timestamp, Some(0));
+        assert_eq!(stats.last_timestamp, Some(7_500_000_000));
+        assert_eq!(stats.largest_gap, 3_500_000_000);
+        assert_eq!(stats.peak_bitrate, Some(80000.0));
+    }
+}
@@ -0,0 +1,201 @@
+//! Timing alignment between two subtitle tracks of the same content.
+//!
+//! Tracks from different releases are rarely in sync. Studio logos add a
+//! constant offset, and PAL releases run 4% fast, which adds a drift that
+//! grows over the course of the video. This fits a linear transform
+//! (`target = source * scale + offset`) using cues whose text appears
+//! exactly once in both tracks as anchors.
+//!
+//! Least squares alone is thrown off by bad anchors (OCR errors that
+//! happen to collide, repeated lines, etc), so a coarse fit is done first
+//! against the framerate ratios seen in practice, using the median offset
+//! for each. Only the anchors that agree with the best coarse fit are used
+//! to refine it.
+
+use super::{Subtitle, compare::PreparedSubtitles};
+
+/// How far (in µs) an anchor may stray from the fitted transform
+const ANCHOR_TOLERANCE: f64 = 750_000.0;
+/// Anchors needed before trusting a least squares refinement
+const MIN_REFINE_ANCHORS: usize = 8;
+/// Speed ratios between common framerates (23.976, 24, 25)
+const CANDIDATE_SCALES: [f64; 7] = [
+    1.0,
+    25.0 / (24_000.0 / 1001.0),
+    (24_000.0 / 1001.0) / 25.0,
+    24.0 / (24_000.0 / 1001.0),
+    (24_000.0 / 1001.0) / 24.0,
+    25.0 / 24.0,
+    24.0 / 25.0,
+];
+
+/// A linear transform from one track's timeline onto another's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    pub scale: f64,
+    /// Offset in µs, applied after scaling
+    pub offset: f64,
+    /// Fraction of cues that agree with the transform, from 0 to 1
+    pub confidence: f64,
+}
+impl Alignment {
+    pub fn apply(&self, timestamp: u64) -> u64 {
+        return (timestamp as f64 * self.scale + self.offset)
+            .max(0.0)
+            .round() as u64;
+    }
+
+    /// Moves subtitles onto the target timeline
+    pub fn retime(&self, subtitles: Vec<Subtitle>) -> Vec<Subtitle> {
+        return subtitles
+            .into_iter()
+            .map(|subtitle| Subtitle {
+                timestamp: self.apply(subtitle.timestamp),
+                duration: subtitle
+                    .duration
+                    .map(|duration| (duration as f64 * self.scale).round() as u64),
+                data: subtitle.data,
+                confidence: subtitle.confidence,
+                region: subtitle.region,
+                forced: subtitle.forced,
+            })
+            .collect();
+    }
+}
+
+/// Estimates the transform from `source` timestamps to `target` timestamps.
+///
+/// Returns `None` if the tracks have no lines in common.
+pub fn estimate_alignment(
+    source: &PreparedSubtitles,
+    target: &PreparedSubtitles,
+) -> Option<Alignment> {
+    // Lines that only appear once in each track make unambiguous anchors
+    let anchors: Vec<(f64, f64)> = source
+        .cues
+        .iter()
+        .filter_map(|(text, source_starts)| {
+            let target_starts = target.cues.get(text)?;
+            if source_starts.len() != 1 || target_starts.len() != 1 {
+                return None;
+            }
+            return Some((source_starts[0] as f64, target_starts[0] as f64));
+        })
+        .collect();
+    if anchors.is_empty() {
+        return None;
+    }
+
+    let (mut scale, mut offset, mut inliers) = CANDIDATE_SCALES
+        .iter()
+        .map(|&scale| {
+            let mut offsets: Vec<f64> = anchors
+                .iter()
+                .map(|(source, target)| target - source * scale)
+                .collect();
+            offsets.sort_unstable_by(f64::total_cmp);
+            let offset = offsets[offsets.len() / 2];
+            return (scale, offset, find_inliers(&anchors, scale, offset));
+        })
+        // Ties go to the earlier (more common) scale
+        .reduce(|best, candidate| match candidate.2.len() > best.2.len() {
+            true => candidate,
+            false => best,
+        })
+        .unwrap();
+
+    if inliers.len() >= MIN_REFINE_ANCHORS
+        && let Some((refined_scale, refined_offset)) = fit_least_squares(&inliers)
+    {
+        let refined_inliers = find_inliers(&anchors, refined_scale, refined_offset);
+        if refined_inliers.len() >= inliers.len() {
+            scale = refined_scale;
+            offset = refined_offset;
+            inliers = refined_inliers;
+        }
+    }
+
+    let possible = source.cue_count.min(target.cue_count);
+    return Some(Alignment {
+        scale,
+        offset,
+        confidence: (inliers.len() as f64 / possible as f64).min(1.0),
+    });
+}
+
+fn find_inliers(anchors: &[(f64, f64)], scale: f64, offset: f64) -> Vec<(f64, f64)> {
+    return anchors
+        .iter()
+        .copied()
+        .filter(|(source, target)| (source * scale + offset - target).abs() <= ANCHOR_TOLERANCE)
+        .collect();
+}
+
+/// Ordinary least squares fit of `target = source * scale + offset`
+fn fit_least_squares(anchors: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let count = anchors.len() as f64;
+    let mean_source = anchors.iter().map(|(source, _)| source).sum::<f64>() / count;
+    let mean_target = anchors.iter().map(|(_, target)| target).sum::<f64>() / count;
+    let mut covariance = 0.0;
+    let mut variance = 0.0;
+    for (source, target) in anchors {
+        covariance += (source - mean_source) * (target - mean_target);
+        variance += (source - mean_source).powi(2);
+    }
+    if variance == 0.0 {
+        return None;
+    }
+    let scale = covariance / variance;
+    return Some((scale, mean_target - scale * mean_source));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimate_pal_drift() {
+        let lines: Vec<String> = (0..20)
+            .map(|i| format!("This is line number {i}"))
+            .collect();
+        let target: Vec<Subtitle> = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| Subtitle {
+                timestamp: 10_000_000 + i as u64 * 60_000_000,
+                duration: Some(2_000_000),
+                data: line.clone(),
+                confidence: None,
+                region: None,
+                forced: false,
+            })
+            .collect();
+        // Sped up to 25fps, with a 3 second logo
+        let scale = 25.0 / (24_000.0 / 1001.0);
+        let mut source: Vec<Subtitle> = target
+            .iter()
+            .map(|subtitle| Subtitle {
+                timestamp: ((subtitle.timestamp + 3_000_000) as f64 / scale) as u64,
+                duration: subtitle.duration,
+                data: subtitle.data.clone(),
+                confidence: None,
+                region: None,
+                forced: false,
+            })
+            .collect();
+        // An anchor that doesn't line up with anything
+        source[4].timestamp += 30_000_000;
+
+        let alignment = estimate_alignment(
+            &PreparedSubtitles::new(&source),
+            &PreparedSubtitles::new(&target),
+        )
+        .unwrap();
+        assert!((alignment.scale - scale).abs() < 1e-6);
+        assert!((alignment.offset + 3_000_000.0).abs() < 1_000.0);
+        assert!((alignment.confidence - 0.95).abs() < 1e-9);
+
+        let retimed = alignment.retime(source);
+        assert!(retimed[10].timestamp.abs_diff(target[10].timestamp) < 1_000);
+    }
+}
@@ -0,0 +1,394 @@
+//! Line-21 (CEA-608) caption decoder. Only data channel 1 (CC1) is decoded,
+//! since that's where the main program captions live.
+//!
+//! Written from the tables in CEA-608-E and the overview here:
+//! http://www.theneitherworld.com/mcpoodle/SCC_TOOLS/DOCS/CC_CODES.HTML
+
+use super::CueBuilder;
+
+const ROWS: usize = 15;
+const COLS: usize = 32;
+
+/// Characters that differ from ASCII in the basic character set
+fn basic_char(byte: u8) -> char {
+    return match byte {
+        0x27 => '’',
+        0x2A => 'á',
+        0x5C => 'é',
+        0x5E => 'í',
+        0x5F => 'ó',
+        0x60 => 'ú',
+        0x7B => 'ç',
+        0x7C => '÷',
+        0x7D => 'Ñ',
+        0x7E => 'ñ',
+        0x7F => '█',
+        byte => byte as char,
+    };
+}
+
+/// Special North American character set (0x11 0x30..=0x3F)
+const SPECIAL_CHARS: [char; 16] = [
+    '®', '°', '½', '¿', '™', '¢', '£', '♪', 'à', ' ', 'è', 'â', 'ê', 'î', 'ô', 'û',
+];
+
+/// Extended Western European character set (0x12 0x20..=0x3F)
+const EXTENDED_CHARS_1: [char; 32] = [
+    'Á', 'É', 'Ó', 'Ú', 'Ü', 'ü', '‘', '¡', '*', '\'', '—', '©', '℠', '•', '“', '”', 'À', 'Â', 'Ç',
+    'È', 'Ê', 'Ë', 'ë', 'Î', 'Ï', 'ï', 'Ô', 'Ù', 'ù', 'Û', '«', '»',
+];
+
+/// Extended Western European character set (0x13 0x20..=0x3F)
+const EXTENDED_CHARS_2: [char; 32] = [
+    'Ã', 'ã', 'Í', 'Ì', 'ì', 'Ò', 'ò', 'Õ', 'õ', '{', '}', '\\', '^', '_', '|', '~', 'Ä', 'ä', 'Ö',
+    'ö', 'ß', '¥', '¤', '¦', 'Å', 'å', 'Ø', 'ø', '┌', '┐', '└', '┘',
+];
+
+/// Maps the first byte of a preamble address code to its rows (1-based), by
+/// the state of bit 5 in the second byte.
+const PAC_ROWS: [[usize; 2]; 8] = [
+    [11, 11],
+    [1, 2],
+    [3, 4],
+    [12, 13],
+    [14, 15],
+    [5, 6],
+    [7, 8],
+    [9, 10],
+];
+
+#[derive(Clone)]
+struct Memory {
+    cells: [[Option<char>; COLS]; ROWS],
+}
+impl Memory {
+    fn new() -> Self {
+        return Self {
+            cells: [[None; COLS]; ROWS],
+        };
+    }
+    fn clear(&mut self) {
+        self.cells = [[None; COLS]; ROWS];
+    }
+    fn row_text(&self, row: usize) -> String {
+        let text: String = self.cells[row]
+            .iter()
+            .map(|cell| cell.unwrap_or(' '))
+            .collect();
+        return String::from(text.trim());
+    }
+    fn text(&self) -> String {
+        return (0..ROWS)
+            .map(|row| self.row_text(row))
+            .filter(|row| !row.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Mode {
+    PopOn,
+    PaintOn,
+    RollUp(usize),
+}
+
+pub struct Cea608Decoder {
+    displayed: Memory,
+    non_displayed: Memory,
+    mode: Mode,
+    row: usize,
+    col: usize,
+    /// Control codes are transmitted twice for redundancy
+    last_control: Option<[u8; 2]>,
+    /// Whether the most recent control code selected data channel 1
+    channel_1: bool,
+    /// When the roll-up row currently being written was started
+    line_start: Option<u64>,
+    cues: CueBuilder,
+}
+impl Cea608Decoder {
+    pub fn new() -> Self {
+        return Self {
+            displayed: Memory::new(),
+            non_displayed: Memory::new(),
+            mode: Mode::PopOn,
+            row: ROWS - 1,
+            col: 0,
+            last_control: None,
+            channel_1: true,
+            line_start: None,
+            cues: CueBuilder::default(),
+        };
+    }
+
+    /// Feeds a field 1 byte pair to the decoder. Timestamps are in microseconds.
+    pub fn push_pair(&mut self, timestamp: u64, bytes: [u8; 2]) {
+        // Strip parity bits
+        let b1 = bytes[0] & 0x7F;
+        let b2 = bytes[1] & 0x7F;
+        if b1 == 0 && b2 == 0 {
+            return;
+        }
+
+        if (0x10..=0x1F).contains(&b1) {
+            if self.last_control == Some([b1, b2]) {
+                self.last_control = None;
+                return;
+            }
+            self.last_control = Some([b1, b2]);
+            self.channel_1 = b1 & 0x08 == 0;
+            if !self.channel_1 {
+                return;
+            }
+            self.process_control(timestamp, b1, b2);
+        } else {
+            self.last_control = None;
+            if !self.channel_1 {
+                return;
+            }
+            for byte in [b1, b2] {
+                if byte >= 0x20 {
+                    self.write_char(timestamp, basic_char(byte));
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> CueBuilder {
+        self.complete_line();
+        return self.cues;
+    }
+
+    fn process_control(&mut self, timestamp: u64, b1: u8, b2: u8) {
+        match (b1, b2) {
+            // Mid-row style change, which is displayed as a space
+            (0x11, 0x20..=0x2F) => self.write_char(timestamp, ' '),
+            (0x11, 0x30..=0x3F) => {
+                self.write_char(timestamp, SPECIAL_CHARS[(b2 - 0x30) as usize]);
+            }
+            // Extended characters replace the standard character sent before them
+            (0x12, 0x20..=0x3F) => {
+                self.backspace(timestamp);
+                self.write_char(timestamp, EXTENDED_CHARS_1[(b2 - 0x20) as usize]);
+            }
+            (0x13, 0x20..=0x3F) => {
+                self.backspace(timestamp);
+                self.write_char(timestamp, EXTENDED_CHARS_2[(b2 - 0x20) as usize]);
+            }
+            (0x14 | 0x15, 0x20..=0x2F) => self.process_command(timestamp, b2),
+            // Tab offsets
+            (0x17, 0x21..=0x23) => {
+                self.col = (self.col + (b2 - 0x20) as usize).min(COLS - 1);
+            }
+            // Preamble address codes
+            (0x10..=0x17, 0x40..=0x7F) => {
+                let row = PAC_ROWS[(b1 & 0x07) as usize][((b2 & 0x20) != 0) as usize] - 1;
+                if matches!(self.mode, Mode::RollUp(_)) && row != self.row {
+                    self.complete_line();
+                }
+                self.row = row;
+                self.col = if b2 & 0x10 != 0 {
+                    ((b2 & 0x0E) >> 1) as usize * 4
+                } else {
+                    0
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn process_command(&mut self, timestamp: u64, command: u8) {
+        match command {
+            // Resume caption loading
+            0x20 => {
+                self.complete_line();
+                self.mode = Mode::PopOn;
+            }
+            // Backspace
+            0x21 => self.backspace(timestamp),
+            // Delete to end of row
+            0x24 => {
+                let col = self.col;
+                let row = self.row;
+                self.memory_mut().cells[row][col..].fill(None);
+                self.update_display(timestamp);
+            }
+            // Roll-up captions, 2-4 rows
+            0x25..=0x27 => {
+                let rows = (command - 0x23) as usize;
+                if !matches!(self.mode, Mode::RollUp(_)) {
+                    self.displayed.clear();
+                    self.non_displayed.clear();
+                    self.cues.display(timestamp, String::new());
+                    self.row = ROWS - 1;
+                    self.col = 0;
+                }
+                self.mode = Mode::RollUp(rows);
+            }
+            // Resume direct captioning
+            0x29 => {
+                self.complete_line();
+                self.mode = Mode::PaintOn;
+            }
+            // Erase displayed memory
+            0x2C => {
+                self.complete_line();
+                self.displayed.clear();
+                self.cues.display(timestamp, String::new());
+            }
+            // Carriage return
+            0x2D => match self.mode {
+                Mode::RollUp(rows) => {
+                    self.complete_line();
+                    let top = (self.row + 1).saturating_sub(rows);
+                    for row in top..self.row {
+                        self.displayed.cells[row] = self.displayed.cells[row + 1];
+                    }
+                    self.displayed.cells[self.row] = [None; COLS];
+                    self.col = 0;
+                }
+                Mode::PaintOn => {
+                    self.row = (self.row + 1).min(ROWS - 1);
+                    self.col = 0;
+                }
+                Mode::PopOn => {}
+            },
+            // Erase non-displayed memory
+            0x2E => self.non_displayed.clear(),
+            // End of caption: flip memories
+            0x2F => {
+                std::mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.mode = Mode::PopOn;
+                self.cues.display(timestamp, self.displayed.text());
+            }
+            _ => {}
+        }
+    }
+
+    /// The memory that characters are currently written into
+    fn memory_mut(&mut self) -> &mut Memory {
+        return match self.mode {
+            Mode::PopOn => &mut self.non_displayed,
+            Mode::PaintOn | Mode::RollUp(_) => &mut self.displayed,
+        };
+    }
+
+    fn write_char(&mut self, timestamp: u64, character: char) {
+        let (row, col) = (self.row, self.col);
+        self.memory_mut().cells[row][col] = Some(character);
+        self.col = (col + 1).min(COLS - 1);
+        match self.mode {
+            Mode::RollUp(_) => {
+                self.line_start.get_or_insert(timestamp);
+            }
+            Mode::PaintOn => self.update_display(timestamp),
+            Mode::PopOn => {}
+        }
+    }
+
+    fn backspace(&mut self, timestamp: u64) {
+        if self.col == 0 {
+            return;
+        }
+        self.col -= 1;
+        let (row, col) = (self.row, self.col);
+        self.memory_mut().cells[row][col] = None;
+        self.update_display(timestamp);
+    }
+
+    fn update_display(&mut self, timestamp: u64) {
+        if self.mode == Mode::PaintOn {
+            self.cues.display(timestamp, self.displayed.text());
+        }
+    }
+
+    /// Emits the roll-up row being written as its own cue
+    fn complete_line(&mut self) {
+        if let Some(line_start) = self.line_start.take() {
+            let text = self.displayed.row_text(self.row);
+            if !text.is_empty() {
+                self.cues.line(line_start, text);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::subtitles::srt::format_subtitles_srt;
+
+    /// Builds byte pairs with odd parity like a real line-21 stream
+    fn with_parity(b1: u8, b2: u8) -> [u8; 2] {
+        let parity = |byte: u8| {
+            if byte.count_ones().is_multiple_of(2) {
+                byte | 0x80
+            } else {
+                byte
+            }
+        };
+        return [parity(b1), parity(b2)];
+    }
+
+    fn push_text(decoder: &mut Cea608Decoder, timestamp: u64, text: &str) {
+        for pair in text.as_bytes().chunks(2) {
+            decoder.push_pair(
+                timestamp,
+                with_parity(pair[0], pair.get(1).copied().unwrap_or(0)),
+            );
+        }
+    }
+
+    fn push_control(decoder: &mut Cea608Decoder, timestamp: u64, b1: u8, b2: u8) {
+        // Sent twice, as encoders do
+        decoder.push_pair(timestamp, with_parity(b1, b2));
+        decoder.push_pair(timestamp, with_parity(b1, b2));
+    }
+
+    #[test]
+    fn pop_on() {
+        let mut decoder = Cea608Decoder::new();
+        push_control(&mut decoder, 0, 0x14, 0x20); // RCL
+        push_control(&mut decoder, 0, 0x14, 0x70); // PAC row 15
+        push_text(&mut decoder, 0, "HELLO THERE");
+        push_control(&mut decoder, 1_000_000, 0x14, 0x2F); // EOC
+        push_control(&mut decoder, 3_000_000, 0x14, 0x2C); // EDM
+        let cues = decoder.finish().finish(None);
+        assert_eq!(cues.len(), 1);
+        assert_eq!(cues[0].timestamp, 1_000_000);
+        assert_eq!(cues[0].duration, Some(2_000_000));
+        assert_eq!(cues[0].data, "HELLO THERE");
+    }
+
+    #[test]
+    fn roll_up() {
+        let mut decoder = Cea608Decoder::new();
+        push_control(&mut decoder, 0, 0x14, 0x25); // RU2
+        push_text(&mut decoder, 1_000, "FIRST LINE");
+        push_control(&mut decoder, 2_000, 0x14, 0x2D); // CR
+        push_text(&mut decoder, 3_000, "SECOND LINE");
+        push_control(&mut decoder, 4_000, 0x14, 0x2D); // CR
+        let cues = decoder.finish().finish(None);
+        let lines: Vec<_> = cues.iter().map(|cue| cue.data.as_str()).collect();
+        assert_eq!(lines, vec!["FIRST LINE", "SECOND LINE"]);
+        assert_eq!(cues[1].timestamp, 3_000);
+    }
+
+    #[test]
+    fn roll_up_srt() {
+        let mut decoder = Cea608Decoder::new();
+        push_control(&mut decoder, 0, 0x14, 0x25); // RU2
+        push_text(&mut decoder, 1_000_000, "FIRST LINE");
+        push_control(&mut decoder, 2_000_000, 0x14, 0x2D); // CR
+        push_text(&mut decoder, 3_000_000, "LAST LINE");
+        push_control(&mut decoder, 4_000_000, 0x14, 0x2D); // CR
+        let cues = decoder.finish().finish(Some(4_000_000));
+        // The last roll-up line lasts until the end of the file
+        assert_eq!(
+            format_subtitles_srt(cues, 10_000_000),
+            "1\n00:00:01,000 --> 00:00:03,000\nFIRST LINE\n\n\
+             2\n00:00:03,000 --> 00:00:10,000\nLAST LINE\n"
+        );
+    }
+}
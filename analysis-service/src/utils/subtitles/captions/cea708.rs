@@ -0,0 +1,307 @@
+//! DTVCC (CEA-708) caption decoder. Only service 1 (the primary caption
+//! service) is decoded, and only text content is tracked: pen styles, window
+//! placement and colors are skipped over.
+//!
+//! Written from CEA-708-E, sections 5-8.
+
+use super::CueBuilder;
+
+const NUM_WINDOWS: usize = 8;
+
+#[derive(Debug, Clone, Default)]
+struct Window {
+    defined: bool,
+    visible: bool,
+    row_count: usize,
+    rows: Vec<String>,
+}
+impl Window {
+    fn clear(&mut self) {
+        self.rows.clear();
+    }
+    fn write_char(&mut self, character: char) {
+        match self.rows.last_mut() {
+            Some(row) => row.push(character),
+            None => self.rows.push(String::from(character)),
+        }
+    }
+    fn carriage_return(&mut self) {
+        self.rows.push(String::new());
+        while self.rows.len() > self.row_count.max(1) {
+            self.rows.remove(0);
+        }
+    }
+    fn text(&self) -> String {
+        return self
+            .rows
+            .iter()
+            .map(|row| row.trim())
+            .filter(|row| !row.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+}
+
+pub struct Cea708Decoder {
+    windows: [Window; NUM_WINDOWS],
+    current_window: usize,
+    packet: Vec<u8>,
+    packet_size: usize,
+    cues: CueBuilder,
+}
+impl Cea708Decoder {
+    pub fn new() -> Self {
+        return Self {
+            windows: Default::default(),
+            current_window: 0,
+            packet: Vec::new(),
+            packet_size: 0,
+            cues: CueBuilder::default(),
+        };
+    }
+
+    /// Feeds a `DtvccStart` byte pair to the decoder. Timestamps are in microseconds.
+    pub fn push_packet_start(&mut self, timestamp: u64, bytes: [u8; 2]) {
+        if !self.packet.is_empty() {
+            // The previous packet was cut short. Decode what we have.
+            self.process_packet(timestamp);
+        }
+        let packet_size_code = (bytes[0] & 0x3F) as usize;
+        self.packet_size = match packet_size_code {
+            0 => 127,
+            code => code * 2 - 1,
+        };
+        self.packet.clear();
+        self.packet.push(bytes[1]);
+        self.try_finish_packet(timestamp);
+    }
+
+    /// Feeds a `DtvccData` byte pair to the decoder
+    pub fn push_packet_data(&mut self, timestamp: u64, bytes: [u8; 2]) {
+        if self.packet_size == 0 {
+            // We haven't seen the start of a packet yet
+            return;
+        }
+        self.packet.extend_from_slice(&bytes);
+        self.try_finish_packet(timestamp);
+    }
+
+    pub fn finish(mut self, timestamp: u64) -> CueBuilder {
+        if !self.packet.is_empty() {
+            self.process_packet(timestamp);
+        }
+        return self.cues;
+    }
+
+    fn try_finish_packet(&mut self, timestamp: u64) {
+        if self.packet.len() >= self.packet_size {
+            self.packet.truncate(self.packet_size);
+            self.process_packet(timestamp);
+        }
+    }
+
+    fn process_packet(&mut self, timestamp: u64) {
+        let packet = std::mem::take(&mut self.packet);
+        self.packet_size = 0;
+        let mut cursor = 0;
+        while cursor < packet.len() {
+            let header = packet[cursor];
+            cursor += 1;
+            let mut service_number = header >> 5;
+            let block_size = (header & 0x1F) as usize;
+            if service_number == 0 {
+                // Null service block marks the end of the packet's content
+                break;
+            }
+            if service_number == 7 {
+                let Some(extended) = packet.get(cursor) else {
+                    break;
+                };
+                service_number = extended & 0x3F;
+                cursor += 1;
+            }
+            let block_end = (cursor + block_size).min(packet.len());
+            if service_number == 1 {
+                self.process_service_block(&packet[cursor..block_end]);
+                self.update_display(timestamp);
+            }
+            cursor = block_end;
+        }
+    }
+
+    fn process_service_block(&mut self, block: &[u8]) {
+        let mut cursor = 0;
+        while cursor < block.len() {
+            let code = block[cursor];
+            cursor += 1;
+            let params = block.get(cursor..).unwrap_or(&[]);
+            cursor += match code {
+                // C0 control codes
+                0x08 => {
+                    if let Some(row) = self.window_mut().rows.last_mut() {
+                        row.pop();
+                    }
+                    0
+                }
+                0x0C => {
+                    self.window_mut().clear();
+                    0
+                }
+                0x0D => {
+                    self.window_mut().carriage_return();
+                    0
+                }
+                0x0E => {
+                    if let Some(row) = self.window_mut().rows.last_mut() {
+                        row.clear();
+                    }
+                    0
+                }
+                0x10 => self.process_extended(params) + 1,
+                0x00..=0x0F => 0,
+                0x11..=0x17 => 1,
+                0x18..=0x1F => 2,
+                // G0 character set
+                0x7F => {
+                    self.window_mut().write_char('♪');
+                    0
+                }
+                0x20..=0x7F => {
+                    self.window_mut().write_char(code as char);
+                    0
+                }
+                // C1 control codes
+                0x80..=0x87 => {
+                    self.current_window = (code - 0x80) as usize;
+                    0
+                }
+                0x88..=0x8C => {
+                    let Some(&bitmap) = params.first() else {
+                        return;
+                    };
+                    for (i, window) in self.windows.iter_mut().enumerate() {
+                        if bitmap & (1 << i) == 0 {
+                            continue;
+                        }
+                        match code {
+                            0x88 => window.clear(),
+                            0x89 => window.visible = true,
+                            0x8A => window.visible = false,
+                            0x8B => window.visible = !window.visible,
+                            _ => *window = Window::default(),
+                        }
+                    }
+                    1
+                }
+                0x8D => 1,
+                0x8E => 0,
+                0x8F => {
+                    self.windows = Default::default();
+                    0
+                }
+                0x90 | 0x92 => 2,
+                0x91 => 3,
+                0x93..=0x96 => 0,
+                0x97 => 4,
+                0x98..=0x9F => {
+                    if params.len() < 6 {
+                        return;
+                    }
+                    let window = &mut self.windows[(code - 0x98) as usize];
+                    if !window.defined {
+                        *window = Window::default();
+                        window.defined = true;
+                    }
+                    window.visible = params[0] & 0x20 != 0;
+                    window.row_count = (params[3] & 0x0F) as usize + 1;
+                    self.current_window = (code - 0x98) as usize;
+                    6
+                }
+                // G1 character set (ISO 8859-1)
+                0xA0..=0xFF => {
+                    self.window_mut().write_char(code as char);
+                    0
+                }
+            };
+        }
+    }
+
+    /// Handles a code following EXT1, returning how many parameter bytes it used
+    fn process_extended(&mut self, params: &[u8]) -> usize {
+        let Some(&code) = params.first() else {
+            return 0;
+        };
+        let params = &params[1..];
+        return match code {
+            // C2 control codes
+            0x00..=0x07 => 0,
+            0x08..=0x0F => 1,
+            0x10..=0x17 => 2,
+            0x18..=0x1F => 3,
+            // G2 character set
+            0x20..=0x7F => {
+                if let Some(character) = g2_char(code) {
+                    self.window_mut().write_char(character);
+                }
+                0
+            }
+            // C3 control codes
+            0x80..=0x87 => 4,
+            0x88..=0x8F => 5,
+            // Variable-length C3 commands carry their length in the header
+            0x90..=0x9F => params
+                .first()
+                .map(|header| (header & 0x1F) as usize + 1)
+                .unwrap_or(0),
+            // G3 character set. The only assigned character is the [CC] icon.
+            0xA0..=0xFF => 0,
+        };
+    }
+
+    fn window_mut(&mut self) -> &mut Window {
+        return &mut self.windows[self.current_window];
+    }
+
+    fn update_display(&mut self, timestamp: u64) {
+        let text = self
+            .windows
+            .iter()
+            .filter(|window| window.visible)
+            .map(Window::text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.cues.display(timestamp, text);
+    }
+}
+
+fn g2_char(code: u8) -> Option<char> {
+    return Some(match code {
+        0x20 | 0x21 => ' ',
+        0x25 => '…',
+        0x2A => 'Š',
+        0x2C => 'Œ',
+        0x30 => '█',
+        0x31 => '‘',
+        0x32 => '’',
+        0x33 => '“',
+        0x34 => '”',
+        0x35 => '•',
+        0x39 => '™',
+        0x3A => 'š',
+        0x3C => 'œ',
+        0x3D => '℠',
+        0x3F => 'Ÿ',
+        0x76 => '⅛',
+        0x77 => '⅜',
+        0x78 => '⅝',
+        0x79 => '⅞',
+        0x7A => '│',
+        0x7B => '┐',
+        0x7C => '└',
+        0x7D => '─',
+        0x7E => '┘',
+        0x7F => '┌',
+        _ => return None,
+    });
+}
@@ -0,0 +1,181 @@
+//! Closed caption extraction from video tracks.
+//!
+//! DVDs frequently carry line-21 captions in MPEG-2 user data, and broadcast
+//! style H.264 streams carry CEA-608/708 in SEI messages. Neither requires
+//! decoding pictures, so this is cheap enough to run alongside hashing when
+//! a file has no subtitle tracks we can use.
+
+use matroska_demuxer::{Frame, TrackEntry, TrackType};
+
+use super::Subtitle;
+use cea608::Cea608Decoder;
+use cea708::Cea708Decoder;
+use user_data::{CcData, CcType, extract_avc, extract_mpeg2};
+
+mod cea608;
+mod cea708;
+mod user_data;
+
+/// Frame duration to assume when the track doesn't declare one (NTSC, in µs)
+const DEFAULT_FRAME_DURATION: u64 = 33_367;
+
+/// Turns screen updates from a caption decoder into `Subtitle` entries
+#[derive(Default)]
+pub struct CueBuilder {
+    current: Option<(u64, String)>,
+    cues: Vec<Subtitle>,
+}
+impl CueBuilder {
+    /// Reports the text currently visible on screen. Text that only grows
+    /// (ie. paint-on captions) extends the running cue instead of starting
+    /// a new one.
+    pub fn display(&mut self, timestamp: u64, text: String) {
+        match self.current {
+            Some((_start, ref mut current)) if text.starts_with(current.as_str()) => {
+                *current = text;
+                return;
+            }
+            _ => {}
+        }
+        if let Some((start, current)) = self.current.take() {
+            self.cues.push(Subtitle {
+                timestamp: start,
+                duration: Some(timestamp.saturating_sub(start)),
+                data: current,
+                confidence: None,
+                region: None,
+                forced: false,
+            });
+        }
+        if !text.is_empty() {
+            self.current = Some((timestamp, text));
+        }
+    }
+
+    /// Emits a single roll-up line. These don't have a defined end, so they
+    /// last until the next cue.
+    pub fn line(&mut self, timestamp: u64, text: String) {
+        self.cues.push(Subtitle {
+            timestamp,
+            duration: None,
+            data: text,
+            confidence: None,
+            region: None,
+            forced: false,
+        });
+    }
+
+    /// Closes any cue still on screen and returns all cues in display order
+    pub fn finish(mut self, timestamp: Option<u64>) -> Vec<Subtitle> {
+        if let Some((start, current)) = self.current.take() {
+            self.cues.push(Subtitle {
+                timestamp: start,
+                duration: timestamp.map(|timestamp| timestamp.saturating_sub(start)),
+                data: current,
+                confidence: None,
+                region: None,
+                forced: false,
+            });
+        }
+        self.cues.sort_by_key(|cue| cue.timestamp);
+        return self.cues;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CaptionCarrier {
+    Mpeg2,
+    Avc { nal_length_size: usize },
+}
+
+/// Collects caption data from a video track's frames and decodes it once
+/// the whole track has been read.
+///
+/// Caption data is attached to frames in decode order, so it has to be
+/// reordered by presentation time before decoding. The data is tiny, so
+/// it's simply buffered for the whole track.
+pub struct CaptionExtractor {
+    carrier: CaptionCarrier,
+    /// Frame duration in µs, used to spread GOP-level caption data over
+    /// the frames it belongs to
+    frame_duration: u64,
+    /// (timestamp, caption data) for each frame that carried captions
+    packets: Vec<(u64, Vec<CcData>)>,
+}
+impl CaptionExtractor {
+    #[inline]
+    pub fn supported_tracks(track: &&TrackEntry) -> bool {
+        return track.track_type() == TrackType::Video
+            && matches!(track.codec_id(), "V_MPEG2" | "V_MPEG4/ISO/AVC");
+    }
+
+    pub fn new(track: &TrackEntry) -> Self {
+        let carrier = match track.codec_id() {
+            "V_MPEG4/ISO/AVC" => CaptionCarrier::Avc {
+                // lengthSizeMinusOne from the AVCDecoderConfigurationRecord
+                nal_length_size: track
+                    .codec_private()
+                    .and_then(|private| private.get(4))
+                    .map(|byte| (byte & 0x03) as usize + 1)
+                    .unwrap_or(4),
+            },
+            _ => CaptionCarrier::Mpeg2,
+        };
+        return Self {
+            carrier,
+            frame_duration: track
+                .default_duration()
+                .map(|duration| duration.get() / 1000)
+                .unwrap_or(DEFAULT_FRAME_DURATION),
+            packets: Vec::new(),
+        };
+    }
+
+    /// NOTE: This assumes frame times have already been scaled
+    pub fn push_frame(&mut self, frame: &Frame) {
+        let mut cc_data = Vec::new();
+        match self.carrier {
+            CaptionCarrier::Mpeg2 => extract_mpeg2(&frame.data, &mut cc_data),
+            CaptionCarrier::Avc { nal_length_size } => {
+                extract_avc(&frame.data, nal_length_size, &mut cc_data)
+            }
+        }
+        if !cc_data.is_empty() {
+            self.packets.push((frame.timestamp / 1000, cc_data));
+        }
+    }
+
+    /// Decodes the collected caption data. CEA-608 is preferred when both
+    /// are present since it tends to be authored more carefully; CEA-708
+    /// is used when the stream carries nothing else.
+    pub fn collect(mut self) -> Vec<Subtitle> {
+        self.packets.sort_by_key(|(timestamp, _cc_data)| *timestamp);
+        let mut decoder_608 = Cea608Decoder::new();
+        let mut decoder_708 = Cea708Decoder::new();
+        let mut last_timestamp = 0;
+        for (timestamp, cc_data) in self.packets {
+            let mut field_1_pairs = 0;
+            for cc in cc_data {
+                match cc.cc_type {
+                    CcType::Ntsc608Field1 => {
+                        // GOP user data carries a pair for every frame in the GOP
+                        let pair_timestamp = timestamp + field_1_pairs * self.frame_duration;
+                        decoder_608.push_pair(pair_timestamp, cc.bytes);
+                        last_timestamp = last_timestamp.max(pair_timestamp);
+                        field_1_pairs += 1;
+                    }
+                    CcType::Ntsc608Field2 => {}
+                    CcType::DtvccStart => decoder_708.push_packet_start(timestamp, cc.bytes),
+                    CcType::DtvccData => decoder_708.push_packet_data(timestamp, cc.bytes),
+                }
+            }
+            last_timestamp = last_timestamp.max(timestamp);
+        }
+        let end = last_timestamp + self.frame_duration;
+        let cues_608 = decoder_608.finish().finish(Some(end));
+        if !cues_608.is_empty() {
+            return cues_608;
+        }
+        return decoder_708.finish(end).finish(Some(end));
+    }
+}
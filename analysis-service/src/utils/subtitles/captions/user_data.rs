@@ -0,0 +1,250 @@
+//! Pulls caption byte pairs out of video elementary streams without decoding
+//! any pictures. Two carriers are supported:
+//!
+//! * MPEG-2 picture/GOP user data, in either the ATSC A/53 (`GA94`) layout or
+//!   the DVD line-21 (`CC`) layout.
+//! * H.264 SEI messages (`user_data_registered_itu_t_t35`) carrying A/53 data.
+//!
+//! Layouts were written from ATSC A/53 Part 4 and the notes in FFmpeg's
+//! `mpeg12dec.c`.
+
+use crate::utils::video::bit_reader::{find_start_code, unescape_rbsp};
+
+const MPEG2_USER_DATA_START: [u8; 4] = [0x00, 0x00, 0x01, 0xB2];
+const A53_IDENTIFIER: &[u8] = b"GA94";
+const A53_CC_DATA: u8 = 0x03;
+const DVD_IDENTIFIER: &[u8] = &[b'C', b'C', 0x01, 0xF8];
+const AVC_NAL_SEI: u8 = 6;
+const SEI_USER_DATA_REGISTERED: u32 = 4;
+const T35_COUNTRY_USA: u8 = 0xB5;
+const T35_PROVIDER_ATSC: u16 = 0x0031;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CcType {
+    /// CEA-608 byte pair for field 1 (CC1/CC2)
+    Ntsc608Field1,
+    /// CEA-608 byte pair for field 2 (CC3/CC4)
+    Ntsc608Field2,
+    /// Continuation of a CEA-708 DTVCC packet
+    DtvccData,
+    /// Start of a CEA-708 DTVCC packet
+    DtvccStart,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CcData {
+    pub cc_type: CcType,
+    pub bytes: [u8; 2],
+}
+
+/// Scans an MPEG-2 video frame for user data packets and extracts any
+/// caption data found within them.
+pub fn extract_mpeg2(data: &[u8], output: &mut Vec<CcData>) {
+    let mut cursor = 0;
+    while let Some(offset) = find_start_code(&data[cursor..], &MPEG2_USER_DATA_START) {
+        let start = cursor + offset + MPEG2_USER_DATA_START.len();
+        let end = find_start_code(&data[start..], &[0x00, 0x00, 0x01])
+            .map(|offset| start + offset)
+            .unwrap_or(data.len());
+        let user_data = &data[start..end];
+        if let Some(cc_data) = user_data.strip_prefix(A53_IDENTIFIER) {
+            if cc_data.first() == Some(&A53_CC_DATA) {
+                parse_a53_cc_data(&cc_data[1..], output);
+            }
+        } else if let Some(cc_data) = user_data.strip_prefix(DVD_IDENTIFIER) {
+            parse_dvd_cc_data(cc_data, output);
+        }
+        cursor = end;
+    }
+}
+
+/// Scans a length-prefixed (AVCC) H.264 access unit for SEI messages and
+/// extracts any caption data found within them.
+pub fn extract_avc(data: &[u8], nal_length_size: usize, output: &mut Vec<CcData>) {
+    let mut cursor = 0;
+    while cursor + nal_length_size <= data.len() {
+        let nal_length = data[cursor..cursor + nal_length_size]
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        cursor += nal_length_size;
+        if nal_length == 0 || cursor + nal_length > data.len() {
+            return;
+        }
+        let nal = &data[cursor..cursor + nal_length];
+        cursor += nal_length;
+        if nal[0] & 0x1F == AVC_NAL_SEI {
+            parse_sei(&unescape_rbsp(&nal[1..]), output);
+        }
+    }
+}
+
+fn parse_sei(rbsp: &[u8], output: &mut Vec<CcData>) {
+    let mut cursor = 0;
+    // Anything shorter than 2 bytes is just the rbsp trailing bits
+    while cursor + 2 <= rbsp.len() {
+        let mut payload_type = 0u32;
+        while cursor < rbsp.len() && rbsp[cursor] == 0xFF {
+            payload_type += 255;
+            cursor += 1;
+        }
+        let Some(&last_type_byte) = rbsp.get(cursor) else {
+            return;
+        };
+        payload_type += last_type_byte as u32;
+        cursor += 1;
+
+        let mut payload_size = 0usize;
+        while cursor < rbsp.len() && rbsp[cursor] == 0xFF {
+            payload_size += 255;
+            cursor += 1;
+        }
+        let Some(&last_size_byte) = rbsp.get(cursor) else {
+            return;
+        };
+        payload_size += last_size_byte as usize;
+        cursor += 1;
+
+        if cursor + payload_size > rbsp.len() {
+            return;
+        }
+        let payload = &rbsp[cursor..cursor + payload_size];
+        cursor += payload_size;
+
+        if payload_type == SEI_USER_DATA_REGISTERED {
+            parse_itu_t_t35(payload, output);
+        }
+    }
+}
+
+fn parse_itu_t_t35(payload: &[u8], output: &mut Vec<CcData>) {
+    if payload.len() < 8 || payload[0] != T35_COUNTRY_USA {
+        return;
+    }
+    if u16::from_be_bytes([payload[1], payload[2]]) != T35_PROVIDER_ATSC {
+        return;
+    }
+    if &payload[3..7] != A53_IDENTIFIER || payload[7] != A53_CC_DATA {
+        return;
+    }
+    parse_a53_cc_data(&payload[8..], output);
+}
+
+/// Parses A/53 `cc_data()`, starting at the byte holding `process_cc_data_flag`
+fn parse_a53_cc_data(data: &[u8], output: &mut Vec<CcData>) {
+    if data.len() < 2 || data[0] & 0x40 == 0 {
+        return;
+    }
+    let cc_count = (data[0] & 0x1F) as usize;
+    // data[1] is em_data, which is reserved
+    for triplet in data[2..].chunks_exact(3).take(cc_count) {
+        let cc_valid = triplet[0] & 0x04 != 0;
+        if !cc_valid {
+            continue;
+        }
+        let cc_type = match triplet[0] & 0x03 {
+            0 => CcType::Ntsc608Field1,
+            1 => CcType::Ntsc608Field2,
+            2 => CcType::DtvccData,
+            _ => CcType::DtvccStart,
+        };
+        output.push(CcData {
+            cc_type,
+            bytes: [triplet[1], triplet[2]],
+        });
+    }
+}
+
+/// Parses DVD GOP caption data, starting at the caption block flags byte.
+///
+/// The block count in the header is frequently wrong, so blocks are counted
+/// by their filler pattern instead.
+fn parse_dvd_cc_data(data: &[u8], output: &mut Vec<CcData>) {
+    let Some(&flags) = data.first() else {
+        return;
+    };
+    let odd_field_first = flags & 0x80 != 0;
+    for block in data[1..].chunks_exact(6) {
+        if block[0] & 0xFE != 0xFE || block[3] & 0xFE != 0xFE {
+            break;
+        }
+        // Each block carries one frame: a word for each field, in field order
+        let (field1, field2) = if odd_field_first {
+            ([block[1], block[2]], [block[4], block[5]])
+        } else {
+            ([block[4], block[5]], [block[1], block[2]])
+        };
+        output.push(CcData {
+            cc_type: CcType::Ntsc608Field1,
+            bytes: field1,
+        });
+        output.push(CcData {
+            cc_type: CcType::Ntsc608Field2,
+            bytes: field2,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extract_mpeg2_a53() {
+        let data = [
+            0x00, 0x00, 0x01, 0x00, 0xAA, 0xBB, // picture header
+            0x00, 0x00, 0x01, 0xB2, b'G', b'A', b'9', b'4', 0x03, // user data header
+            0x42, 0xFF, // cc_data header
+            0xFC, 0x94, 0x20, // field 1
+            0xF9, 0x80, 0x80, // field 2, invalid
+            0x00, 0x00, 0x01, 0x01, // slice
+        ];
+        let mut output = Vec::new();
+        extract_mpeg2(&data, &mut output);
+        assert_eq!(
+            output,
+            vec![CcData {
+                cc_type: CcType::Ntsc608Field1,
+                bytes: [0x94, 0x20],
+            }]
+        );
+    }
+
+    #[test]
+    fn extract_mpeg2_dvd() {
+        let data = [
+            0x00, 0x00, 0x01, 0xB2, b'C', b'C', 0x01, 0xF8, 0x8A, // header, odd field first
+            0xFF, 0x94, 0x20, 0xFE, 0x80, 0x80, // block 1
+            0xFF, 0xC8, 0xE5, 0xFE, 0x80, 0x80, // block 2
+        ];
+        let mut output = Vec::new();
+        extract_mpeg2(&data, &mut output);
+        let field1: Vec<_> = output
+            .iter()
+            .filter(|cc| cc.cc_type == CcType::Ntsc608Field1)
+            .map(|cc| cc.bytes)
+            .collect();
+        assert_eq!(field1, vec![[0x94, 0x20], [0xC8, 0xE5]]);
+    }
+
+    #[test]
+    fn extract_avc_sei() {
+        let sei_payload = [
+            0xB5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0x41, 0xFF, 0xFC, 0x94, 0x2C,
+        ];
+        let mut nal = vec![0x06, 0x04, sei_payload.len() as u8];
+        nal.extend_from_slice(&sei_payload);
+        nal.push(0x80);
+        let mut data = (nal.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&nal);
+
+        let mut output = Vec::new();
+        extract_avc(&data, 4, &mut output);
+        assert_eq!(
+            output,
+            vec![CcData {
+                cc_type: CcType::Ntsc608Field1,
+                bytes: [0x94, 0x2C],
+            }]
+        );
+    }
+}
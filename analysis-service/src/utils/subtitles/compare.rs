@@ -0,0 +1,204 @@
+//! Similarity scoring between ripped subtitle tracks and reference
+//! subtitles (ie. from OpenSubtitles).
+//!
+//! Each pair gets a text score and a timing score. Text similarity is the
+//! Jaccard index of the word shingles in each track, which is tolerant of
+//! OCR mistakes, missing lines, and different line breaks. Both tracks are
+//! run through the [`normalize`](super::normalize) pipeline first, so SDH
+//! annotations don't count against a track. Timing
+//! similarity is the confidence of the fitted [`Alignment`], so tracks with
+//! different lead-ins or framerates can still match.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use rayon::prelude::*;
+
+use super::{
+    Subtitle,
+    alignment::{Alignment, estimate_alignment},
+    normalize::{NormalizeOptions, fold_ocr_confusions, normalize_subtitle},
+};
+
+/// Number of words in each shingle
+const SHINGLE_SIZE: usize = 3;
+/// How much of the final score comes from text similarity. The rest comes
+/// from timing.
+const TEXT_WEIGHT: f64 = 0.8;
+
+/// A subtitle track that has been normalized and indexed for comparison
+pub struct PreparedSubtitles {
+    shingles: HashSet<u64>,
+    /// Start timestamps of each cue, keyed by normalized text
+    pub(super) cues: HashMap<String, Vec<u64>>,
+    pub(super) cue_count: usize,
+}
+impl PreparedSubtitles {
+    pub fn new(subtitles: &[Subtitle]) -> Self {
+        let mut cues: HashMap<String, Vec<u64>> = HashMap::new();
+        let mut words = Vec::new();
+        let options = NormalizeOptions::default();
+        for subtitle in subtitles {
+            let text = normalize_text(&normalize_subtitle(&subtitle.data, &options));
+            let text = fold_ocr_confusions(&text);
+            if text.is_empty() {
+                continue;
+            }
+            words.extend(text.split(' ').map(String::from));
+            cues.entry(text).or_default().push(subtitle.timestamp);
+        }
+
+        // Very short tracks fall back to single words
+        let shingle_size = SHINGLE_SIZE.min(words.len()).max(1);
+        let shingles = words
+            .windows(shingle_size)
+            .map(|shingle| {
+                let mut hasher = DefaultHasher::new();
+                shingle.hash(&mut hasher);
+                return hasher.finish();
+            })
+            .collect();
+
+        return Self {
+            shingles,
+            cue_count: cues.values().map(Vec::len).sum(),
+            cues,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimilarityScore {
+    /// Weighted combination of the other two scores
+    pub score: f64,
+    pub text_similarity: f64,
+    pub timing_similarity: f64,
+    /// How the ripped track's timing maps onto the reference
+    pub alignment: Option<Alignment>,
+}
+
+/// Scores every ripped track against every reference. The result has one
+/// row per ripped track, with one column per reference.
+pub fn score_matrix(
+    ripped: &[PreparedSubtitles],
+    references: &[PreparedSubtitles],
+) -> Vec<Vec<SimilarityScore>> {
+    return ripped
+        .par_iter()
+        .map(|ripped| {
+            return references
+                .iter()
+                .map(|reference| compare_subtitles(ripped, reference))
+                .collect();
+        })
+        .collect();
+}
+
+pub fn compare_subtitles(
+    ripped: &PreparedSubtitles,
+    reference: &PreparedSubtitles,
+) -> SimilarityScore {
+    let text_similarity = text_similarity(ripped, reference);
+    let alignment = estimate_alignment(ripped, reference);
+    let timing_similarity = alignment.map_or(0.0, |alignment| alignment.confidence);
+    return SimilarityScore {
+        score: text_similarity * TEXT_WEIGHT + timing_similarity * (1.0 - TEXT_WEIGHT),
+        text_similarity,
+        timing_similarity,
+        alignment,
+    };
+}
+
+fn text_similarity(left: &PreparedSubtitles, right: &PreparedSubtitles) -> f64 {
+    let intersection = left.shingles.intersection(&right.shingles).count();
+    let union = left.shingles.len() + right.shingles.len() - intersection;
+    if union == 0 {
+        return 0.0;
+    }
+    return intersection as f64 / union as f64;
+}
+
+/// Strips formatting and punctuation so that only the spoken words remain
+pub fn normalize_text(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut in_tag = None;
+    for char in text.chars() {
+        match (in_tag, char) {
+            // HTML-style (<i>) and ASS-style ({\an8}) formatting tags
+            (None, '<') => in_tag = Some('>'),
+            (None, '{') => in_tag = Some('}'),
+            (Some(end), _) => {
+                if char == end {
+                    in_tag = None;
+                }
+            }
+            (None, char) if char.is_alphanumeric() => normalized.extend(char.to_lowercase()),
+            // Contractions shouldn't be split into separate words
+            (None, '\'' | '’') => {}
+            (None, _) => {
+                if !normalized.is_empty() && !normalized.ends_with(' ') {
+                    normalized.push(' ');
+                }
+            }
+        }
+    }
+    if normalized.ends_with(' ') {
+        normalized.pop();
+    }
+    return normalized;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(cues: &[(u64, &str)]) -> Vec<Subtitle> {
+        return cues
+            .iter()
+            .map(|(timestamp, data)| Subtitle {
+                timestamp: *timestamp,
+                duration: None,
+                data: String::from(*data),
+                confidence: None,
+                region: None,
+                forced: false,
+            })
+            .collect();
+    }
+
+    #[test]
+    fn normalize_text_1() {
+        assert_eq!(
+            normalize_text("<i>Don't move!</i>\n{\\an8}- Who's THERE?"),
+            "dont move whos there"
+        );
+    }
+
+    #[test]
+    fn compare_offset_tracks() {
+        let reference = PreparedSubtitles::new(&track(&[
+            (1_000_000, "Where were you last night?"),
+            (3_000_000, "I was at the library, studying."),
+            (6_000_000, "The library closes at nine."),
+        ]));
+        // Same dialogue with an OCR error, 2 seconds later
+        let ripped = PreparedSubtitles::new(&track(&[
+            (3_000_000, "Where were you last night?"),
+            (5_000_000, "I was at the library, studyinq."),
+            (8_000_000, "The library closes at nine."),
+        ]));
+        let unrelated = PreparedSubtitles::new(&track(&[
+            (1_000_000, "Captain, the shields are down."),
+            (4_000_000, "Then raise them again!"),
+        ]));
+
+        let matrix = score_matrix(&[ripped], &[reference, unrelated]);
+        let matching = matrix[0][0];
+        let other = matrix[0][1];
+        assert!((matching.timing_similarity - 2.0 / 3.0).abs() < 1e-9);
+        assert!(matching.text_similarity > 0.5);
+        assert_eq!(other.score, 0.0);
+    }
+}
@@ -0,0 +1,66 @@
+//! Forced subtitles only cover foreign language dialogue and signs, and are
+//! shown even when subtitles are turned off. They're flagged on the whole
+//! track by Matroska's `FlagForced`, and on individual subtitles by PGS
+//! composition objects and VobSub control sequences.
+//!
+//! Flags on individual subtitles can be read without decoding any images,
+//! so every subtitle track is counted while the file is demuxed.
+
+use matroska_demuxer::TrackEntry;
+
+use super::{pgs::peek_new_subtitle, vobsub::peek_forced};
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CueCounts {
+    pub cues: u64,
+    pub forced: u64,
+    /// Total size of every block in the track
+    pub bytes: u64,
+}
+
+enum CueFormat {
+    Pgs,
+    Vobsub,
+    /// Formats that don't flag individual subtitles
+    Text,
+}
+
+pub struct CueCounter {
+    format: CueFormat,
+    track_forced: bool,
+    counts: CueCounts,
+}
+impl CueCounter {
+    pub fn new(track: &TrackEntry) -> Self {
+        return Self {
+            format: match track.codec_id() {
+                "S_HDMV/PGS" => CueFormat::Pgs,
+                "S_VOBSUB" => CueFormat::Vobsub,
+                _ => CueFormat::Text,
+            },
+            track_forced: track.flag_forced(),
+            counts: CueCounts::default(),
+        };
+    }
+
+    pub fn push_frame(&mut self, data: &[u8]) {
+        self.counts.bytes += data.len() as u64;
+        let forced = match self.format {
+            CueFormat::Pgs => match peek_new_subtitle(data) {
+                Some(forced) => forced,
+                // Clears and repeats of what's already shown
+                None => return,
+            },
+            CueFormat::Vobsub => peek_forced(data).unwrap_or(false),
+            CueFormat::Text => false,
+        };
+        self.counts.cues += 1;
+        if forced || self.track_forced {
+            self.counts.forced += 1;
+        }
+    }
+
+    pub fn counts(&self) -> CueCounts {
+        return self.counts;
+    }
+}
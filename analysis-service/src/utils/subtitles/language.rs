@@ -0,0 +1,229 @@
+//! Identifies the language subtitles are written in, since disc authoring
+//! often mislabels subtitle tracks or leaves them as `und`.
+//!
+//! Each language is profiled by its most common short words, which make up a
+//! large share of any dialogue and rarely survive translation. Bitmap tracks
+//! are OCR'd with the English model, which still reads most Latin script
+//! languages well enough for their common words to come through.
+
+use matroska_demuxer::{Frame, TrackEntry};
+
+use super::{StContext, Subtitle, SubtitleOptions, ocr::PartessCache};
+use crate::utils::ExtractDetailsError;
+
+/// How many blocks of a track are decoded to detect its language
+const SAMPLE_FRAMES: usize = 100;
+
+/// Texts with fewer words than this aren't identified
+const MIN_WORDS: usize = 20;
+
+/// Texts with fewer common words than this from the best match aren't
+/// identified
+const MIN_HITS: usize = 5;
+
+/// Common words for each language, keyed by ISO 639-2 code
+const PROFILES: &[(&str, &[&str])] = &[
+    (
+        "eng",
+        &[
+            "the", "and", "you", "that", "is", "it", "what", "to", "of", "this", "have", "with",
+            "are", "not", "was", "for", "we", "me", "my", "your", "don", "be", "he", "she", "they",
+            "just", "know", "can", "there", "here",
+        ],
+    ),
+    (
+        "fra",
+        &[
+            "le", "la", "les", "de", "des", "et", "est", "je", "tu", "vous", "nous", "il", "elle",
+            "pas", "que", "qui", "une", "un", "ce", "dans", "pour", "sur", "avec", "mais", "oui",
+            "suis", "ai", "moi", "tout", "ça",
+        ],
+    ),
+    (
+        "deu",
+        &[
+            "der", "die", "das", "und", "ist", "ich", "du", "sie", "nicht", "ein", "eine", "zu",
+            "mit", "es", "wir", "was", "auf", "ja", "den", "dem", "mir", "mich", "hier", "auch",
+            "aber", "so", "wie", "bin", "hast", "kann",
+        ],
+    ),
+    (
+        "spa",
+        &[
+            "el", "la", "los", "las", "que", "de", "y", "es", "en", "un", "una", "no", "por",
+            "para", "con", "lo", "me", "te", "se", "qué", "está", "sí", "yo", "pero", "muy", "su",
+            "al", "como", "bien", "eso",
+        ],
+    ),
+    (
+        "ita",
+        &[
+            "il", "la", "di", "che", "è", "e", "non", "un", "una", "per", "sono", "mi", "ti", "ho",
+            "lo", "gli", "questo", "cosa", "ma", "come", "si", "io", "del", "della", "con", "sei",
+            "bene", "qui", "anche", "perché",
+        ],
+    ),
+    (
+        "por",
+        &[
+            "o", "a", "os", "as", "que", "de", "e", "é", "não", "um", "uma", "para", "com", "eu",
+            "você", "se", "do", "da", "em", "no", "na", "mas", "isso", "está", "muito", "bem",
+            "sim", "tem", "meu", "ele",
+        ],
+    ),
+    (
+        "nld",
+        &[
+            "de", "het", "een", "en", "is", "ik", "je", "niet", "dat", "van", "wat", "zijn", "we",
+            "hij", "ze", "op", "met", "voor", "maar", "er", "hier", "ben", "heb", "jij", "naar",
+            "ook", "nog", "wel", "dit", "geen",
+        ],
+    ),
+];
+
+/// Other codes for each language in `PROFILES`
+const ALIASES: &[(&str, &[&str])] = &[
+    ("eng", &["en"]),
+    ("fra", &["fr", "fre"]),
+    ("deu", &["de", "ger"]),
+    ("spa", &["es"]),
+    ("ita", &["it"]),
+    ("por", &["pt"]),
+    ("nld", &["nl", "dut"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedLanguage {
+    /// ISO 639-2 code
+    pub language: &'static str,
+    /// How many of the common words found belong to this language, from 0 to 1
+    pub confidence: f64,
+}
+
+pub fn detect_language(text: &str) -> Option<DetectedLanguage> {
+    let text = text.to_lowercase();
+    let words: Vec<_> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+
+    let hits: Vec<usize> = PROFILES
+        .iter()
+        .map(|(_language, common)| words.iter().filter(|word| common.contains(word)).count())
+        .collect();
+    let total: usize = hits.iter().sum();
+    let (best, best_hits) = hits
+        .iter()
+        .enumerate()
+        .max_by_key(|(_index, hits)| **hits)?;
+    if *best_hits < MIN_HITS {
+        return None;
+    }
+    return Some(DetectedLanguage {
+        language: PROFILES[best].0,
+        confidence: *best_hits as f64 / total as f64,
+    });
+}
+
+pub fn detect_subtitle_language(subtitles: &[Subtitle]) -> Option<DetectedLanguage> {
+    let text: Vec<_> = subtitles
+        .iter()
+        .map(|subtitle| subtitle.data.as_str())
+        .collect();
+    return detect_language(&text.join("\n"));
+}
+
+/// The language a track is tagged with, if it's tagged with anything
+/// meaningful
+pub fn declared_language(track: &TrackEntry) -> Option<&str> {
+    return track
+        .language_bcp47()
+        .or(track.language())
+        .filter(|language| !matches!(*language, "und" | "mul" | "zxx" | "mis"));
+}
+
+/// Whether a declared language tag disagrees with the detected language.
+/// Untagged tracks never disagree.
+pub fn language_mismatch(declared: Option<&str>, detected: &str) -> bool {
+    let Some(declared) = declared else {
+        return false;
+    };
+    // Drop BCP 47 region and script subtags
+    let primary = declared
+        .split('-')
+        .next()
+        .unwrap_or(declared)
+        .to_lowercase();
+    let canonical = ALIASES
+        .iter()
+        .find(|(language, aliases)| *language == primary || aliases.contains(&primary.as_str()))
+        .map(|(language, _aliases)| *language);
+    return canonical != Some(detected);
+}
+
+/// Decodes the first few blocks of a track to detect its language
+pub struct LanguageSampler {
+    st_ctx: StContext,
+    frames: usize,
+}
+impl LanguageSampler {
+    /// Only tracks that pass `StContext::supported_codecs` can be sampled
+    pub fn new(
+        track: &TrackEntry,
+        partess_cache: &PartessCache,
+    ) -> Result<Self, ExtractDetailsError> {
+        return Ok(Self {
+            st_ctx: StContext::new(track, partess_cache, &SubtitleOptions::default())?,
+            frames: 0,
+        });
+    }
+
+    pub fn push_frame(&mut self, frame: &Frame) -> Result<(), ExtractDetailsError> {
+        if self.frames >= SAMPLE_FRAMES {
+            return Ok(());
+        }
+        self.frames += 1;
+        return self.st_ctx.process_frame(&mut frame.clone());
+    }
+
+    pub fn finish(self) -> Result<Option<DetectedLanguage>, ExtractDetailsError> {
+        return Ok(detect_subtitle_language(&self.st_ctx.collect()?));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_dialogue_language() {
+        let english = "I don't know what you want from me. \
+            We have to get out of here before they find us. \
+            This is the last time I'm going to tell you, and that's it.";
+        let french = "Je ne sais pas ce que vous voulez de moi. \
+            Nous devons partir avant qu'ils nous trouvent. \
+            C'est la dernière fois que je te le dis, et c'est tout.";
+        let german = "Ich weiß nicht, was du von mir willst. \
+            Wir müssen hier weg, bevor sie uns finden. \
+            Das ist das letzte Mal, dass ich es dir sage, und das war es.";
+
+        let detected = detect_language(english).unwrap();
+        assert_eq!(detected.language, "eng");
+        assert!(detected.confidence > 0.5);
+        assert_eq!(detect_language(french).unwrap().language, "fra");
+        assert_eq!(detect_language(german).unwrap().language, "deu");
+        assert_eq!(detect_language("Hello there."), None);
+    }
+
+    #[test]
+    fn compare_language_tags() {
+        assert!(!language_mismatch(Some("en-US"), "eng"));
+        assert!(!language_mismatch(Some("ger"), "deu"));
+        assert!(!language_mismatch(None, "eng"));
+        assert!(language_mismatch(Some("eng"), "fra"));
+        assert!(language_mismatch(Some("jpn"), "eng"));
+    }
+}
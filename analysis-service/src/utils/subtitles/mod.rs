@@ -1,42 +1,41 @@
-use image::{GrayAlphaImage, GrayImage, Pixel, RgbaImage};
+use std::sync::Arc;
+
+use captions::CaptionExtractor;
 use matroska_demuxer::{Frame, TrackEntry, TrackType};
-use ocr::PartessCache;
+use normalize::{NormalizeOptions, normalize_subtitles};
+use ocr::{OcrConfidence, PartessCache};
+use ocr_cache::OcrCacheCounter;
 use pgs::processor::PgsProcessor;
+use preprocess::PreprocessOptions;
 use serde::{Deserialize, Serialize};
 use vobsub::VobsubProcessor;
 
 use super::ExtractDetailsError;
 
+pub mod alignment;
+pub mod captions;
+pub mod compare;
+pub mod forced;
+pub mod language;
+pub mod mpeg_ps;
+pub mod normalize;
 pub mod ocr;
+pub mod ocr_cache;
 pub mod pgs;
+pub mod preprocess;
+pub mod selection;
 pub mod srt;
+pub mod standalone;
 pub mod utils;
 pub mod vobsub;
 
-pub fn get_subtitle_track(
-    tracks: &[TrackEntry],
-) -> Result<Option<&TrackEntry>, ExtractDetailsError> {
-    let candidates: Vec<_> = tracks
-        .into_iter()
-        .filter(StContext::supported_tracks)
-        .filter(|track| track.flag_enabled())
-        .collect();
-    return Ok(match candidates.len() {
-        0 => None,
-        1 => Some(candidates.into_iter().next().unwrap()),
-        _ => {
-            // Found multiple valid candidates. See if we can narrow down any further by
-            // filtering on default tracks. If not, just take the first candidate we found.
-            match candidates
-                .iter()
-                .cloned()
-                .find(|track| track.flag_default())
-            {
-                Some(track) => Some(track),
-                None => Some(candidates.into_iter().next().unwrap()),
-            }
-        }
-    });
+/// Picks a video track to pull closed captions from when there are no
+/// subtitle tracks to work with.
+pub fn get_caption_track(tracks: &[TrackEntry]) -> Option<&TrackEntry> {
+    return tracks
+        .iter()
+        .filter(CaptionExtractor::supported_tracks)
+        .find(|track| track.flag_enabled());
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -44,27 +43,123 @@ pub struct Subtitle {
     timestamp: u64,
     duration: Option<u64>,
     data: String,
+    /// Only present for subtitles that were OCR'd
+    confidence: Option<OcrConfidence>,
+    /// Only present for image-based subtitles
+    region: Option<ScreenRegion>,
+    /// Forced subtitles are shown even when subtitles are turned off, ie.
+    /// for foreign language dialogue
+    forced: bool,
+}
+impl Subtitle {
+    pub fn confidence(&self) -> Option<&OcrConfidence> {
+        return self.confidence.as_ref();
+    }
+
+    pub fn region(&self) -> Option<&ScreenRegion> {
+        return self.region.as_ref();
+    }
+
+    pub fn forced(&self) -> bool {
+        return self.forced;
+    }
+}
+
+/// Marks every subtitle as forced, for tracks that are flagged as forced
+/// as a whole
+pub fn mark_forced(subtitles: &mut [Subtitle]) {
+    for subtitle in subtitles {
+        subtitle.forced = true;
+    }
+}
+
+/// The bounding box of a subtitle on screen, in pixels
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ScreenRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub screen_width: u32,
+    pub screen_height: u32,
+}
+impl ScreenRegion {
+    /// Numpad-style alignment (1-9), as used by `{\anN}` tags. Dialogue
+    /// normally sits at the bottom center, which is 2.
+    ///
+    /// Subtitles in the bottom third of the screen are always treated as
+    /// bottom center, since dialogue is often shifted sideways to follow
+    /// the speaker.
+    pub fn alignment(&self) -> u8 {
+        let third = |position: u32, size: u32| (position * 3 / size.max(1)).min(2) as u8;
+        let row = third(self.y + self.height / 2, self.screen_height);
+        let column = third(self.x + self.width / 2, self.screen_width);
+        return match row {
+            0 => 7 + column,
+            1 => 4 + column,
+            _ => 2,
+        };
+    }
+}
+
+/// Settings for how subtitles are decoded and cleaned up
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SubtitleOptions {
+    /// Cleans up the text of each subtitle after decoding
+    pub normalization: Option<NormalizeOptions>,
+    /// OCR'd subtitles with a mean confidence (0-100) below this keep a PNG
+    /// of their source image for manual review
+    pub review_threshold: Option<u8>,
+    /// Cleans up bitmaps before they're OCR'd
+    pub preprocessing: Option<PreprocessOptions>,
+    /// Drops every subtitle that isn't forced, ie. for exporting just the
+    /// foreign language parts of a film
+    pub forced_only: bool,
+}
+impl SubtitleOptions {
+    /// Applies the options that take effect after decoding
+    pub fn apply(&self, subtitles: Vec<Subtitle>) -> Vec<Subtitle> {
+        let subtitles = match self.forced_only {
+            true => subtitles
+                .into_iter()
+                .filter(|subtitle| subtitle.forced)
+                .collect(),
+            false => subtitles,
+        };
+        return match &self.normalization {
+            Some(normalization) => normalize_subtitles(subtitles, normalization),
+            None => subtitles,
+        };
+    }
 }
 
 pub enum StContext {
     Subrip(Vec<Subtitle>),
     Vobsub(VobsubProcessor),
     Pgs(PgsProcessor),
+    Captions(CaptionExtractor),
 }
 impl StContext {
     #[inline]
     pub fn supported_tracks(track: &&TrackEntry) -> bool {
-        return track.track_type() == TrackType::Subtitle
-            && matches!(track.codec_id(), "S_VOBSUB" | "S_SUBRIP" | "S_HDMV/PGS")
+        return Self::supported_codecs(track)
             && matches!(
                 track.language_bcp47().or(track.language()),
                 Some("eng") | Some("en") | Some("en-US") | Some("en-GB")
             );
     }
 
+    /// Subtitle tracks we can decode, regardless of their language
+    #[inline]
+    pub fn supported_codecs(track: &&TrackEntry) -> bool {
+        return track.track_type() == TrackType::Subtitle
+            && matches!(track.codec_id(), "S_VOBSUB" | "S_SUBRIP" | "S_HDMV/PGS");
+    }
+
     pub fn new(
         st_track: &TrackEntry,
         partess_cache: &PartessCache,
+        options: &SubtitleOptions,
     ) -> Result<Self, ExtractDetailsError> {
         return Ok(match st_track.codec_id() {
             "S_SUBRIP" => StContext::Subrip(Vec::new()),
@@ -72,8 +167,16 @@ impl StContext {
                 partess_cache,
                 "eng",
                 st_track.codec_private().unwrap_or(&[]),
+                options.review_threshold,
+                options.preprocessing,
             )?),
-            "S_HDMV/PGS" => StContext::Pgs(PgsProcessor::new(partess_cache, "eng")?),
+            "S_HDMV/PGS" => StContext::Pgs(PgsProcessor::new(
+                partess_cache,
+                "eng",
+                options.review_threshold,
+                options.preprocessing,
+            )?),
+            "V_MPEG2" | "V_MPEG4/ISO/AVC" => StContext::Captions(CaptionExtractor::new(st_track)),
             // Other codecs should be filtered out above
             _ => unreachable!(),
         });
@@ -86,6 +189,9 @@ impl StContext {
                 duration: frame.duration.map(|duration| duration / 1000),
                 data: String::from_utf8(std::mem::take(&mut frame.data))
                     .map_err(|_| ExtractDetailsError::SubripInvalidUtf8)?,
+                confidence: None,
+                region: None,
+                forced: false,
             }),
             Self::Vobsub(vobs) => vobs.push_frame(
                 frame.timestamp / 1000,
@@ -93,40 +199,26 @@ impl StContext {
                 std::mem::take(&mut frame.data),
             ),
             Self::Pgs(processor) => processor.push_frame(&frame)?,
+            Self::Captions(extractor) => extractor.push_frame(frame),
         }
         return Ok(());
     }
 
+    /// Tallies OCR cache lookups, for tracks that are OCR'd
+    pub fn ocr_cache_counter(&self) -> Option<Arc<OcrCacheCounter>> {
+        return match self {
+            Self::Vobsub(vobs) => Some(vobs.cache_counter()),
+            Self::Pgs(processor) => Some(processor.cache_counter()),
+            Self::Subrip(_) | Self::Captions(_) => None,
+        };
+    }
+
     pub fn collect(self) -> Result<Vec<Subtitle>, ExtractDetailsError> {
         match self {
             Self::Subrip(subs) => Ok(subs),
             Self::Vobsub(vobs) => vobs.collect(),
             Self::Pgs(processor) => processor.collect(),
+            Self::Captions(extractor) => Ok(extractor.collect()),
         }
     }
 }
-
-pub fn process_graya_image(image: GrayAlphaImage) -> GrayImage {
-    let mut gray_image: GrayImage = GrayImage::new(image.width(), image.height());
-    for (src_pixel, dest_pixel) in image.pixels().zip(gray_image.pixels_mut()) {
-        if src_pixel.0[1] == 0 {
-            dest_pixel.0 = [255];
-            continue;
-        }
-        dest_pixel.0 = [255 - src_pixel.0[0]];
-    }
-    return gray_image;
-}
-
-pub fn process_rgba_image(image: RgbaImage) -> GrayImage {
-    let mut gray_image: GrayImage = GrayImage::new(image.width(), image.height());
-    for (src_pixel, dest_pixel) in image.pixels().zip(gray_image.pixels_mut()) {
-        if src_pixel.0[3] == 0 {
-            dest_pixel.0 = [255];
-            continue;
-        }
-        let luminance = src_pixel.to_luma().0[0];
-        dest_pixel.0 = [255 - luminance];
-    }
-    return gray_image;
-}
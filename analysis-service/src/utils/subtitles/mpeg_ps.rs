@@ -0,0 +1,195 @@
+//! Minimal MPEG program stream demuxer for VobSub .sub files.
+//!
+//! A .sub file is a program stream containing only private stream 1 PES
+//! packets. Each packet's first payload byte is the substream ID, and
+//! subpicture units (SPUs) are split across as many packets as needed.
+//! This reassembles the SPUs for a single substream so they can be handed
+//! to the VobSub decoder exactly like Matroska S_VOBSUB blocks.
+//!
+//! Written from ISO/IEC 13818-1 section 2.5 and the notes here:
+//! http://dvd.sourceforge.net/dvdinfo/pes-hdr.html
+
+use super::vobsub::VobsubError;
+
+const PACK_HEADER: u8 = 0xBA;
+const PROGRAM_END: u8 = 0xB9;
+const PRIVATE_STREAM_1: u8 = 0xBD;
+/// Substream IDs 0x20-0x3F are subpicture streams
+pub const SUBPICTURE_STREAM_BASE: u8 = 0x20;
+
+/// A fully reassembled subpicture unit
+pub struct SpuPacket {
+    /// Presentation timestamp in microseconds
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+/// Extracts all SPUs belonging to the given substream ID
+pub fn demux_spu_packets(data: &[u8], substream_id: u8) -> Result<Vec<SpuPacket>, VobsubError> {
+    let mut packets = Vec::new();
+    let mut current: Option<(u64, usize, Vec<u8>)> = None;
+    let mut cursor = 0;
+    while cursor + 4 <= data.len() {
+        if data[cursor..cursor + 3] != [0x00, 0x00, 0x01] {
+            // Resynchronize on the next start code
+            cursor += 1;
+            continue;
+        }
+        let stream_id = data[cursor + 3];
+        cursor += 4;
+        match stream_id {
+            PACK_HEADER => {
+                let Some(&marker) = data.get(cursor) else {
+                    break;
+                };
+                if marker & 0xC0 == 0x40 {
+                    // MPEG-2 pack header, with stuffing length in the last byte
+                    let Some(&stuffing) = data.get(cursor + 9) else {
+                        break;
+                    };
+                    cursor += 10 + (stuffing & 0x07) as usize;
+                } else {
+                    // MPEG-1 pack header
+                    cursor += 8;
+                }
+            }
+            PROGRAM_END => {}
+            _ => {
+                if cursor + 2 > data.len() {
+                    break;
+                }
+                let length = u16::from_be_bytes([data[cursor], data[cursor + 1]]) as usize;
+                cursor += 2;
+                let packet_end = (cursor + length).min(data.len());
+                if stream_id == PRIVATE_STREAM_1 {
+                    let pes = &data[cursor..packet_end];
+                    if let Some((pts, payload)) = parse_pes_payload(pes)?
+                        && payload.first() == Some(&substream_id)
+                    {
+                        push_payload(&mut current, &mut packets, pts, &payload[1..]);
+                    }
+                }
+                cursor = packet_end;
+            }
+        }
+    }
+    return Ok(packets);
+}
+
+/// A PES packet's PTS (in µs) if present, along with its payload
+type PesPayload<'a> = (Option<u64>, &'a [u8]);
+
+/// Parses an MPEG-2 PES header
+fn parse_pes_payload(pes: &[u8]) -> Result<Option<PesPayload<'_>>, VobsubError> {
+    if pes.len() < 3 {
+        return Ok(None);
+    }
+    if pes[0] & 0xC0 != 0x80 {
+        return Err(VobsubError::InvalidPes);
+    }
+    let pts_dts_flags = pes[1] >> 6;
+    let header_length = pes[2] as usize;
+    if pes.len() < 3 + header_length {
+        return Err(VobsubError::InvalidPes);
+    }
+    let pts = if pts_dts_flags & 0b10 != 0 && header_length >= 5 {
+        let pts = &pes[3..8];
+        let ticks = ((pts[0] as u64 >> 1) & 0x07) << 30
+            | (pts[1] as u64) << 22
+            | (pts[2] as u64 >> 1) << 15
+            | (pts[3] as u64) << 7
+            | (pts[4] as u64 >> 1);
+        // 90kHz clock
+        Some(ticks * 100 / 9)
+    } else {
+        None
+    };
+    return Ok(Some((pts, &pes[3 + header_length..])));
+}
+
+fn push_payload(
+    current: &mut Option<(u64, usize, Vec<u8>)>,
+    packets: &mut Vec<SpuPacket>,
+    pts: Option<u64>,
+    payload: &[u8],
+) {
+    match pts {
+        // A timestamp marks the start of a new SPU, whose size is in its first 2 bytes
+        Some(pts) if payload.len() >= 2 => {
+            let size = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+            *current = Some((pts, size, Vec::with_capacity(size)));
+        }
+        Some(_) => return,
+        None => {}
+    }
+    let Some((_pts, size, data)) = current else {
+        return;
+    };
+    data.extend_from_slice(payload);
+    if data.len() >= *size {
+        let (timestamp, size, mut data) = current.take().unwrap();
+        data.truncate(size);
+        packets.push(SpuPacket { timestamp, data });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pes_packet(pts: Option<u32>, payload: &[u8]) -> Vec<u8> {
+        let mut header = match pts {
+            Some(pts) => {
+                let pts = pts as u64;
+                vec![
+                    0x81,
+                    0x80,
+                    0x05,
+                    0x21 | ((pts >> 29) & 0x0E) as u8,
+                    (pts >> 22) as u8,
+                    0x01 | ((pts >> 14) & 0xFE) as u8,
+                    (pts >> 7) as u8,
+                    0x01 | ((pts << 1) & 0xFE) as u8,
+                ]
+            }
+            None => vec![0x81, 0x00, 0x00],
+        };
+        header.extend_from_slice(payload);
+        let mut packet = vec![0x00, 0x00, 0x01, PRIVATE_STREAM_1];
+        packet.extend_from_slice(&(header.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&header);
+        return packet;
+    }
+
+    #[test]
+    fn demux_split_spu() {
+        let pack = [
+            0x00,
+            0x00,
+            0x01,
+            PACK_HEADER,
+            0x44,
+            0x00,
+            0x04,
+            0x00,
+            0x04,
+            0x01,
+            0x01,
+            0x89,
+            0xC3,
+            0xF8,
+        ];
+        let mut data = Vec::new();
+        data.extend_from_slice(&pack);
+        data.extend(pes_packet(Some(90_000), &[0x20, 0x00, 0x06, 0xAA]));
+        // A packet for another substream in between
+        data.extend(pes_packet(Some(90_000), &[0x21, 0x00, 0x04, 0xEE, 0xEE]));
+        data.extend_from_slice(&pack);
+        data.extend(pes_packet(None, &[0x20, 0xBB, 0xCC, 0xDD]));
+
+        let packets = demux_spu_packets(&data, 0x20).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].timestamp, 1_000_000);
+        assert_eq!(packets[0].data, vec![0x00, 0x06, 0xAA, 0xBB, 0xCC, 0xDD]);
+    }
+}
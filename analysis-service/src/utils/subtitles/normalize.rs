@@ -0,0 +1,314 @@
+//! Cleanup for SDH annotations and OCR noise in subtitle text.
+//!
+//! SDH tracks describe sounds and name speakers, which reference subtitles
+//! usually don't, and OCR regularly confuses glyphs that look alike. Both
+//! hurt matching, and neither is very nice to read.
+//!
+//! Corrections here are conservative, since they end up in exported SRTs.
+//! An upper case I is only read as an L where that spells a common word.
+//! Other confusions that can't be fixed without a dictionary (ie. `rn` vs
+//! `m`) are instead folded away by [`fold_ocr_confusions`] on both sides of
+//! a comparison.
+
+use super::Subtitle;
+
+/// Common words with an L that subtitles use, lower case and without
+/// apostrophes
+#[rustfmt::skip]
+const L_WORDS: &[&str] = &[
+    "able", "actually", "airplane", "alive", "all", "allow", "allowed", "almost", "alone", "along",
+    "already", "alright", "also", "although", "always", "angel", "animal", "apple", "awful",
+    "ball", "balls", "battle", "beautiful", "believe", "believed", "bell", "belly", "below",
+    "belt", "bill", "black", "blame", "blind", "block", "blood", "bloody", "blow", "blue",
+    "bottle", "build", "building", "built", "bullet", "bullshit", "call", "called", "calling",
+    "calls", "calm", "careful", "carefully", "castle", "cell", "child", "children", "clean",
+    "clear", "clearly", "clever", "climb", "clock", "close", "closed", "closer", "clothes", "club",
+    "clue", "cold", "college", "color", "cool", "could", "couldnt", "couple", "cruel", "daily",
+    "deal", "delicious", "devil", "dollar", "dollars", "double", "early", "easily", "else",
+    "email", "english", "equal", "evil", "exactly", "example", "explain", "fail", "failed", "fall",
+    "fallen", "falling", "false", "familiar", "family", "fault", "feel", "feeling", "feelings",
+    "feels", "fell", "fellow", "felt", "field", "file", "fill", "film", "final", "finally", "flag",
+    "flight", "floor", "flowers", "fly", "flying", "folks", "follow", "following", "fool",
+    "football", "full", "funeral", "girl", "girlfriend", "girls", "glad", "glass", "glasses",
+    "gold", "golden", "guilt", "guilty", "hall", "handle", "hell", "hello", "help", "helped",
+    "helpful", "helping", "helps", "herself", "hill", "himself", "hold", "holding", "hole",
+    "holiday", "holy", "honestly", "hospital", "hotel", "ill", "illegal", "impossible",
+    "incredible", "island", "itll", "itself", "jail", "jealous", "jelly", "kill", "killed",
+    "killer", "killing", "kills", "knowledge", "lady", "laid", "lake", "land", "language", "large",
+    "last", "late", "lately", "later", "laugh", "laughing", "law", "lawyer", "lay", "lazy", "lead",
+    "leader", "learn", "learned", "least", "leave", "leaves", "leaving", "led", "left", "leg",
+    "legal", "legs", "less", "lesson", "let", "lets", "letter", "letting", "level", "liar", "lie",
+    "lied", "lies", "life", "lift", "light", "lights", "like", "liked", "likely", "likes", "limit",
+    "line", "lines", "lion", "lips", "list", "listen", "listening", "little", "live", "lived",
+    "lives", "living", "load", "local", "lock", "locked", "lonely", "long", "longer", "look",
+    "looked", "looking", "looks", "loose", "lord", "lose", "loser", "losing", "loss", "lost",
+    "lot", "lots", "loud", "love", "loved", "lovely", "lover", "loves", "loving", "low", "lower",
+    "loyal", "luck", "lucky", "lunch", "lying", "mail", "male", "mall", "meal", "medical",
+    "mental", "metal", "middle", "mile", "miles", "milk", "mill", "million", "model", "myself",
+    "nearly", "normal", "normally", "obviously", "official", "oil", "old", "older", "only",
+    "ourselves", "palace", "pale", "people", "pill", "pillow", "place", "places", "plan", "plane",
+    "planet", "plans", "plant", "play", "played", "player", "playing", "plays", "please",
+    "pleased", "pleasure", "plenty", "plus", "police", "polite", "possible", "possibly",
+    "probably", "problem", "problems", "pull", "pulled", "pulling", "quality", "quickly",
+    "quietly", "real", "realize", "really", "relationship", "relax", "release", "relief", "role",
+    "roll", "rule", "rules", "sail", "sale", "school", "self", "sell", "selling", "shall", "shell",
+    "should", "shouldnt", "silence", "silent", "silly", "silver", "simple", "simply", "single",
+    "skill", "sleep", "sleeping", "slept", "slightly", "slow", "slowly", "small", "smell", "smile",
+    "smiling", "sold", "soldier", "solid", "solve", "soul", "special", "spell", "steal", "still",
+    "stole", "stolen", "style", "table", "tail", "tale", "talent", "talk", "talked", "talking",
+    "talks", "tall", "telephone", "television", "tell", "telling", "tells", "terrible", "terribly",
+    "thatll", "themselves", "therell", "theyll", "toilet", "told", "tool", "total", "totally",
+    "trial", "trouble", "truly", "ugly", "uncle", "unless", "unlike", "until", "useful", "usually",
+    "valley", "value", "village", "violence", "violent", "walk", "walked", "walking", "wall",
+    "walls", "welcome", "well", "whole", "wholl", "wild", "will", "willing", "wolf", "wonderful",
+    "world", "would", "wouldnt", "yell", "yelling", "yellow", "youll", "yourself", "yourselves",
+];
+
+/// Selects which normalization steps to run
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NormalizeOptions {
+    /// Removes bracketed descriptions, like `[MUSIC PLAYING]` or `(sighs)`
+    pub sound_descriptions: bool,
+    /// Removes speaker labels, like `JOHN:`
+    pub speaker_labels: bool,
+    /// Removes music notes around lyrics
+    pub music_notes: bool,
+    /// Removes the dashes that mark a change of speaker
+    pub dialogue_dashes: bool,
+    /// Fixes unambiguous OCR confusions, like `l'm` or `wiII`
+    pub ocr_confusions: bool,
+}
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        return Self {
+            sound_descriptions: true,
+            speaker_labels: true,
+            music_notes: true,
+            dialogue_dashes: true,
+            ocr_confusions: true,
+        };
+    }
+}
+
+/// Normalizes each subtitle, dropping any that end up empty
+pub fn normalize_subtitles(subtitles: Vec<Subtitle>, options: &NormalizeOptions) -> Vec<Subtitle> {
+    return subtitles
+        .into_iter()
+        .filter_map(|subtitle| {
+            let data = normalize_subtitle(&subtitle.data, options);
+            if data.is_empty() {
+                return None;
+            }
+            return Some(Subtitle { data, ..subtitle });
+        })
+        .collect();
+}
+
+/// Normalizes the text of a single subtitle. Line breaks are preserved, but
+/// lines that end up empty are removed.
+pub fn normalize_subtitle(text: &str, options: &NormalizeOptions) -> String {
+    let text = match options.sound_descriptions {
+        true => strip_sound_descriptions(text),
+        false => String::from(text),
+    };
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut line = line.trim();
+        if options.music_notes {
+            line = line.trim_matches(|char: char| is_music_note(char) || char.is_whitespace());
+        }
+        if options.dialogue_dashes {
+            line = strip_dialogue_dash(line);
+        }
+        if options.speaker_labels {
+            line = strip_speaker_label(line);
+        }
+        let mut line = String::from(line);
+        if options.music_notes {
+            line = line.replace(is_music_note, " ");
+        }
+        if options.ocr_confusions {
+            line = fix_ocr_confusions(&line);
+        }
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    return lines.join("\n");
+}
+
+/// Folds characters that OCR commonly confuses into a single form. This
+/// loses information, so it should only be applied to normalized comparison
+/// keys (lowercase, alphanumeric), and to both sides of the comparison.
+pub fn fold_ocr_confusions(key: &str) -> String {
+    return key
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .replace(['l', '1', '|'], "i")
+        .replace('0', "o");
+}
+
+fn is_music_note(char: char) -> bool {
+    return matches!(char, '♪' | '♫' | '♬' | '♩');
+}
+
+/// Removes `[...]` and `(...)` spans, which may cross line breaks
+fn strip_sound_descriptions(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut depth = 0usize;
+    for char in text.chars() {
+        match char {
+            '[' | '(' => depth += 1,
+            ']' | ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(char),
+            // Keep line breaks so lines don't get merged together
+            '\n' => stripped.push(char),
+            _ => {}
+        }
+    }
+    return stripped;
+}
+
+fn strip_dialogue_dash(line: &str) -> &str {
+    return match line.strip_prefix(['-', '‐', '–', '—']) {
+        Some(rest) => rest.trim_start(),
+        None => line,
+    };
+}
+
+/// Removes an upper case label followed by a colon from the start of the
+/// line, ie. `JOHN:`, `MAN 2:`, or `DR. SMITH:`
+fn strip_speaker_label(line: &str) -> &str {
+    let Some((label, rest)) = line.split_once(':') else {
+        return line;
+    };
+    // Avoid times, like 10:30
+    if rest.starts_with(|char: char| !char.is_whitespace()) {
+        return line;
+    }
+    let is_label = label.chars().filter(|char| char.is_alphabetic()).count() >= 2
+        && label.chars().all(|char| {
+            char.is_uppercase() || char.is_ascii_digit() || matches!(char, ' ' | '.' | '\'' | '-')
+        });
+    return match is_label {
+        true => rest.trim_start(),
+        false => line,
+    };
+}
+
+fn fix_ocr_confusions(line: &str) -> String {
+    let mut sentence_start = true;
+    let mut words = Vec::new();
+    for word in line.split(' ') {
+        words.push(fix_ocr_word(word, sentence_start));
+        if !word.is_empty() {
+            sentence_start = word
+                .trim_end_matches(['"', '\'', ')'])
+                .ends_with(['.', '!', '?']);
+        }
+    }
+    return words.join(" ");
+}
+
+/// `sentence_start` keeps the case of an I read as the word's first L
+fn fix_ocr_word(word: &str, sentence_start: bool) -> String {
+    let mut chars: Vec<char> = word.replace('|', "I").chars().collect();
+
+    // A lone lower case L is the pronoun I, as are its contractions
+    let core: String = chars
+        .iter()
+        .filter(|char| char.is_alphanumeric() || **char == '\'')
+        .collect();
+    if matches!(core.as_str(), "l" | "l'm" | "l'll" | "l've" | "l'd") {
+        let position = chars.iter().position(|char| *char == 'l').unwrap();
+        chars[position] = 'I';
+        return chars.into_iter().collect();
+    }
+
+    // Digits are only swapped for letters in words that start with a letter,
+    // so numbers like "1st" and "10s" are left alone.
+    let starts_with_letter = chars
+        .iter()
+        .find(|char| char.is_alphanumeric())
+        .is_some_and(|char| char.is_alphabetic());
+    let has_other_digits = chars
+        .iter()
+        .any(|char| char.is_ascii_digit() && !matches!(char, '0' | '1'));
+
+    // Upper case I for a lower case L, which is only fixed if that makes a
+    // word, since names like Iowa and McIntyre look the same
+    let first_letter = chars.iter().position(|char| char.is_alphabetic());
+    let mut fixed = chars.clone();
+    for i in 0..fixed.len() {
+        let prev = i.checked_sub(1).map(|i| fixed[i]);
+        let next = fixed.get(i + 1).copied();
+        // Few words start with these, and "I" doesn't follow a lower case letter
+        if fixed[i] == 'I'
+            && Some(i) == first_letter
+            && matches!(next, Some('i' | 'e' | 'o' | 'u' | 'y'))
+        {
+            fixed[i] = if sentence_start { 'L' } else { 'l' };
+        } else if fixed[i] == 'I' && prev.is_some_and(char::is_lowercase) {
+            fixed[i] = 'l';
+        }
+    }
+    if fixed != chars {
+        let word: String = fixed
+            .iter()
+            .filter(|char| char.is_alphabetic())
+            .flat_map(|char| char.to_lowercase())
+            .collect();
+        if L_WORDS.contains(&word.as_str()) {
+            chars = fixed;
+        }
+    }
+
+    for i in 0..chars.len() {
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1).copied();
+        let lower_context =
+            prev.is_some_and(char::is_lowercase) || next.is_some_and(char::is_lowercase);
+        let upper_context =
+            prev.is_some_and(char::is_uppercase) && next.is_none_or(|char| !char.is_lowercase());
+        chars[i] = match chars[i] {
+            // All caps words don't contain a lower case L
+            'l' if upper_context => 'I',
+            '0' if starts_with_letter && !has_other_digits && lower_context => 'o',
+            '0' if starts_with_letter && !has_other_digits && upper_context => 'O',
+            '1' if starts_with_letter && !has_other_digits && lower_context => 'l',
+            '1' if starts_with_letter && !has_other_digits && upper_context => 'I',
+            char => char,
+        };
+    }
+    return chars.into_iter().collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Lines taken from OCR'd and SDH subtitle tracks, and what they should
+    /// normalize to. Cues are separated by blank lines, and inputs from
+    /// expected outputs by `=>`.
+    const CORPUS: &str = include_str!("../../../test_data/normalize_corpus.txt");
+
+    #[test]
+    fn normalize_corpus() {
+        let options = NormalizeOptions::default();
+        for case in CORPUS.split("\n\n").filter(|case| !case.trim().is_empty()) {
+            let (input, expected) = case.split_once("\n=>\n").expect("Malformed corpus entry");
+            assert_eq!(
+                normalize_subtitle(input, &options),
+                expected.trim_end(),
+                "Input: {input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn fold_ocr_confusions_1() {
+        assert_eq!(fold_ocr_confusions("modern"), fold_ocr_confusions("modem"));
+        assert_eq!(fold_ocr_confusions("iittie"), fold_ocr_confusions("little"));
+    }
+}
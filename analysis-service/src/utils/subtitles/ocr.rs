@@ -7,8 +7,11 @@ use std::{
 
 use image::GrayImage;
 use leptess::{LepTess, Variable, leptonica};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use super::ocr_cache::{OcrCache, OcrCacheCounter, OcrCacheKey};
+
 #[derive(Error, Debug)]
 pub enum PartessError {
     #[error("Failed to initialize leptess:\n{0}")]
@@ -23,17 +26,288 @@ pub enum PartessError {
     LeptonicaPixError(#[from] leptonica::PixError),
 }
 
+/// Tesseract's confidence in the text it recognized, from 0 to 100
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct OcrConfidence {
+    pub mean: u8,
+    pub words: Vec<WordConfidence>,
+    /// PNG of the image that was OCR'd. This is only kept for images with
+    /// a low mean confidence, so they can be corrected by hand.
+    pub review_image: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct WordConfidence {
+    pub text: String,
+    pub confidence: u8,
+    pub italic: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OcrOutput {
+    pub text: String,
+    pub confidence: OcrConfidence,
+}
+
 pub struct PartessInstance(Arc<PartessInner>, Option<LepTess>);
 impl PartessInstance {
-    pub fn ocr_image(&mut self, image: GrayImage) -> Result<String, PartessError> {
+    /// OCRs an image. If the mean confidence falls below `review_threshold`,
+    /// the image is kept alongside the text.
+    pub fn ocr_image(
+        &mut self,
+        image: GrayImage,
+        review_threshold: Option<u8>,
+    ) -> Result<OcrOutput, PartessError> {
         let leptess = self.1.as_mut().unwrap();
         let mut img_bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
         image.write_to(&mut img_bytes, image::ImageFormat::Pnm)?;
         leptess.set_image_from_mem(img_bytes.get_ref())?;
-        return Ok(leptess
+        let text = leptess
             .get_utf8_text()
-            .map_err(|_| PartessError::InvalidOcrOutput)?);
+            .map_err(|_| PartessError::InvalidOcrOutput)?;
+        // Both of these reuse the recognition results from above
+        let mut words = parse_hocr_words(
+            &leptess
+                .get_hocr_text(0)
+                .map_err(|_| PartessError::InvalidOcrOutput)?,
+        );
+        mark_slanted_words(&image, &mut words);
+        let text = match words.iter().any(|word| word.word.italic) {
+            true => italic_text(&words),
+            false => text,
+        };
+        let words = words.into_iter().map(|word| word.word).collect();
+        let mean = leptess.mean_text_conf().clamp(0, 100) as u8;
+
+        let review_image = review_image(&image, mean, review_threshold)?;
+
+        return Ok(OcrOutput {
+            text,
+            confidence: OcrConfidence {
+                mean,
+                words,
+                review_image,
+            },
+        });
+    }
+}
+
+/// Encodes the image as a PNG if its confidence is below `review_threshold`
+fn review_image(
+    image: &GrayImage,
+    mean: u8,
+    review_threshold: Option<u8>,
+) -> Result<Option<Vec<u8>>, PartessError> {
+    return Ok(match review_threshold {
+        Some(threshold) if mean < threshold => {
+            let mut png_bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+            image.write_to(&mut png_bytes, image::ImageFormat::Png)?;
+            Some(png_bytes.into_inner())
+        }
+        _ => None,
+    });
+}
+
+/// A word from tesseract's hOCR output
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct HocrWord {
+    /// Index of the line the word is on
+    line: usize,
+    /// `[x0, y0, x1, y1]` in image pixels
+    bbox: Option<[u32; 4]>,
+    word: WordConfidence,
+}
+
+/// Pulls word-level confidence and italics out of tesseract's hOCR output.
+/// Italics are only marked here if `hocr_font_info` is enabled, and the
+/// LSTM engine never reports them; see [`mark_slanted_words`].
+fn parse_hocr_words(hocr: &str) -> Vec<HocrWord> {
+    let mut words = Vec::new();
+    let mut line = 0;
+    for span in hocr.split("<span ").skip(1) {
+        let Some((attributes, content)) = span.split_once('>') else {
+            continue;
+        };
+        match hocr_attribute(attributes, "class") {
+            Some("ocr_line" | "ocr_caption" | "ocr_header" | "ocr_textfloat") => line += 1,
+            Some("ocrx_word") => {
+                // Words don't contain other spans
+                let content = content.split("</span>").next().unwrap_or_default();
+                let text = unescape_html(&strip_html_tags(content));
+                let text = text.trim();
+                if text.is_empty() {
+                    continue;
+                }
+                let title = hocr_attribute(attributes, "title").unwrap_or_default();
+                let property = |name: &str| {
+                    return title.split(';').find_map(|property| {
+                        return property.trim().strip_prefix(name);
+                    });
+                };
+                let confidence = property("x_wconf ")
+                    .and_then(|confidence| confidence.trim().parse::<u8>().ok())
+                    .unwrap_or(0);
+                let bbox = property("bbox ").and_then(|bbox| {
+                    let coords = bbox
+                        .split_whitespace()
+                        .map(|coord| coord.parse::<u32>().ok())
+                        .collect::<Option<Vec<_>>>()?;
+                    return coords.try_into().ok();
+                });
+                words.push(HocrWord {
+                    line,
+                    bbox,
+                    word: WordConfidence {
+                        text: String::from(text),
+                        confidence: confidence.min(100),
+                        italic: content.contains("<em>"),
+                    },
+                });
+            }
+            _ => {}
+        }
+    }
+    return words;
+}
+
+/// Reads a quoted attribute from the inside of an HTML tag
+fn hocr_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let (_, value) = attributes.split_once(&format!("{name}="))?;
+    let quote = value
+        .chars()
+        .next()
+        .filter(|char| matches!(char, '\'' | '"'))?;
+    let value = &value[1..];
+    return Some(&value[..value.find(quote)?]);
+}
+
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for char in html.chars() {
+        match char {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(char),
+            _ => {}
+        }
+    }
+    return text;
+}
+
+fn unescape_html(text: &str) -> String {
+    return text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+}
+
+/// Shears tried when measuring slant, in pixels of lean per pixel of height
+const SLANTS: [f32; 10] = [-0.2, -0.1, 0.0, 0.1, 0.15, 0.2, 0.25, 0.3, 0.35, 0.4];
+/// Italic type leans by at least this much
+const MIN_ITALIC_SLANT: f32 = 0.1;
+/// How much sharper the strokes must get when sheared upright
+const MIN_SLANT_GAIN: f64 = 1.1;
+/// Words with fewer dark pixels are too small to measure
+const MIN_SLANT_INK: usize = 40;
+
+/// Marks words whose glyphs lean like italic type. Words too small to
+/// measure follow the majority of the measured words on their line.
+fn mark_slanted_words(image: &GrayImage, words: &mut [HocrWord]) {
+    let slanted: Vec<Option<bool>> = words
+        .iter()
+        .map(|word| is_slanted(image, word.bbox?))
+        .collect();
+    // (slanted, upright) per line
+    let mut lines: HashMap<usize, (usize, usize)> = HashMap::new();
+    for (word, slanted) in words.iter().zip(&slanted) {
+        let counts = lines.entry(word.line).or_default();
+        match slanted {
+            Some(true) => counts.0 += 1,
+            Some(false) => counts.1 += 1,
+            None => {}
+        }
     }
+    for (word, slanted) in words.iter_mut().zip(slanted) {
+        let slanted = slanted.unwrap_or_else(|| {
+            let (slanted, upright) = lines[&word.line];
+            return slanted > upright;
+        });
+        word.word.italic |= slanted;
+    }
+}
+
+/// Whether the dark glyphs inside `bbox` lean right. Vertical strokes pile
+/// up in the fewest columns once the word is sheared back upright, so this
+/// looks for the shear that makes the column histogram the sharpest.
+/// Returns `None` if the word has too little ink to tell.
+fn is_slanted(image: &GrayImage, bbox: [u32; 4]) -> Option<bool> {
+    let [x0, y0, x1, y1] = bbox;
+    let (x1, y1) = (x1.min(image.width()), y1.min(image.height()));
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    let (width, height) = (x1 - x0, y1 - y0);
+    let middle = (y0 + y1) as f32 / 2.0;
+    let ink: Vec<(f32, f32)> = (y0..y1)
+        .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+        .filter(|(x, y)| image.get_pixel(*x, *y).0[0] < 128)
+        .map(|(x, y)| ((x - x0) as f32, y as f32 - middle))
+        .collect();
+    if ink.len() < MIN_SLANT_INK {
+        return None;
+    }
+    // Sum of squared column heights, which grows as strokes line up
+    let sharpness = |slant: f32| {
+        let last = (width + height) as usize;
+        let mut columns = vec![0u32; last + 1];
+        for (x, dy) in &ink {
+            // Up is negative, so the tops of leaning strokes move back left
+            let column = (x + dy * slant + height as f32 / 2.0).round();
+            columns[(column.max(0.0) as usize).min(last)] += 1;
+        }
+        return columns
+            .into_iter()
+            .map(|count| count as f64 * count as f64)
+            .sum::<f64>();
+    };
+    let upright = sharpness(0.0);
+    let (slant, best) = SLANTS
+        .into_iter()
+        .map(|slant| (slant, sharpness(slant)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    return Some(slant >= MIN_ITALIC_SLANT && best >= upright * MIN_SLANT_GAIN);
+}
+
+/// Rebuilds the text from OCR'd words, wrapping italic runs in `<i>` tags.
+/// Tags are closed at the end of each line.
+fn italic_text(words: &[HocrWord]) -> String {
+    let mut text = String::new();
+    let mut italic = false;
+    let mut prev_line = None;
+    for HocrWord { line, word, .. } in words {
+        let new_line = prev_line.is_some_and(|prev_line| prev_line != *line);
+        if italic && (new_line || !word.italic) {
+            text.push_str("</i>");
+            italic = false;
+        }
+        if prev_line.is_some() {
+            text.push(if new_line { '\n' } else { ' ' });
+        }
+        if word.italic && !italic {
+            text.push_str("<i>");
+            italic = true;
+        }
+        text.push_str(&word.text);
+        prev_line = Some(*line);
+    }
+    if italic {
+        text.push_str("</i>");
+    }
+    return text;
 }
 impl Deref for PartessInstance {
     type Target = LepTess;
@@ -61,13 +335,18 @@ struct PartessInner {
     instances: Mutex<Vec<LepTess>>,
     language: String,
     variables: Vec<(Variable, String)>,
+    ocr_cache: OcrCache,
+    settings_digest: OcrCacheKey,
 }
 impl Partess {
-    pub fn new(language: String, variables: Vec<(Variable, String)>) -> Self {
+    pub fn new(language: String, variables: Vec<(Variable, String)>, ocr_cache: OcrCache) -> Self {
+        let settings_digest = OcrCache::settings_digest(&language, &variables);
         return Partess(Arc::new(PartessInner {
             instances: Mutex::new(Vec::new()),
             language,
             variables,
+            ocr_cache,
+            settings_digest,
         }));
     }
     fn new_instance(&self) -> Result<LepTess, PartessError> {
@@ -87,6 +366,77 @@ impl Partess {
             }
         });
     }
+    /// OCRs an image, reusing the result for an identical image if there
+    /// is one in the cache. Lookups are tallied in `counter`.
+    pub fn ocr_image(
+        &self,
+        image: GrayImage,
+        review_threshold: Option<u8>,
+        counter: &OcrCacheCounter,
+    ) -> Result<OcrOutput, PartessError> {
+        let key = OcrCache::key(&self.0.settings_digest, &image);
+        if let Some((mut output, tier)) = self.0.ocr_cache.get(&key) {
+            counter.record(Some(tier));
+            output.confidence.review_image =
+                review_image(&image, output.confidence.mean, review_threshold)?;
+            return Ok(output);
+        }
+        counter.record(None);
+        let output = self.get()?.ocr_image(image, review_threshold)?;
+        self.0.ocr_cache.insert(key, &output);
+        return Ok(output);
+    }
+
+    /// OCRs each region of a subtitle on its own, and joins the results from
+    /// top to bottom. The review image is that of the least confident region.
+    pub fn ocr_regions(
+        &self,
+        images: Vec<GrayImage>,
+        review_threshold: Option<u8>,
+        counter: &OcrCacheCounter,
+    ) -> Result<OcrOutput, PartessError> {
+        let mut outputs = images
+            .into_iter()
+            .map(|image| self.ocr_image(image, review_threshold, counter))
+            .collect::<Result<Vec<_>, _>>()?;
+        if outputs.len() == 1 {
+            return Ok(outputs.pop().unwrap());
+        }
+
+        let text = outputs
+            .iter()
+            .map(|output| output.text.trim_end())
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        // Weighted by word count, so stray specks don't drag the mean down
+        let weights: Vec<_> = outputs
+            .iter()
+            .map(|output| output.confidence.words.len().max(1) as u64)
+            .collect();
+        let mean = outputs
+            .iter()
+            .zip(&weights)
+            .map(|(output, weight)| output.confidence.mean as u64 * weight)
+            .sum::<u64>()
+            / weights.iter().sum::<u64>().max(1);
+        let review_image = outputs
+            .iter_mut()
+            .filter(|output| output.confidence.review_image.is_some())
+            .min_by_key(|output| output.confidence.mean)
+            .and_then(|output| output.confidence.review_image.take());
+        return Ok(OcrOutput {
+            text,
+            confidence: OcrConfidence {
+                mean: mean as u8,
+                words: outputs
+                    .into_iter()
+                    .flat_map(|output| output.confidence.words)
+                    .collect(),
+                review_image,
+            },
+        });
+    }
 }
 impl Clone for Partess {
     fn clone(&self) -> Self {
@@ -96,11 +446,14 @@ impl Clone for Partess {
 
 pub struct PartessCache {
     pub cache: Arc<Mutex<HashMap<String, Partess>>>,
+    /// Shared by every language, since settings are part of the key
+    pub ocr_cache: OcrCache,
 }
 impl PartessCache {
-    pub fn new() -> Self {
+    pub fn new(ocr_cache: OcrCache) -> Self {
         return Self {
             cache: Arc::new(Mutex::new(HashMap::new())),
+            ocr_cache,
         };
     }
 }
@@ -108,6 +461,94 @@ impl Clone for PartessCache {
     fn clone(&self) -> Self {
         return Self {
             cache: Arc::clone(&self.cache),
+            ocr_cache: self.ocr_cache.clone(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_hocr_words_1() {
+        let hocr = "<div class='ocr_page' id='page_1' title='bbox 0 0 640 120'>\n\
+            <p class='ocr_par' id='par_1_1' lang='eng'>\n\
+            <span class='ocr_line' id='line_1_1' title='bbox 12 10 300 50'>\
+            <span class='ocrx_word' id='word_1_1' title='bbox 12 10 102 50; x_wconf 96'>Hello,</span> \
+            <span class='ocrx_word' id='word_1_2' title='bbox 110 10 230 50; x_wconf 41'><em>w0rld</em></span>\
+            </span>\n\
+            <span class='ocr_line' id='line_1_2' title='bbox 12 60 300 100'>\
+            <span class='ocrx_word' id='word_1_3' title='bbox 12 60 90 100; x_wconf 88'><em>Tom&amp;Jerry</em></span> \
+            <span class='ocrx_word' id='word_1_4' title='bbox 100 60 200 100; x_wconf 90'><em>&quot;Hi&quot;</em></span>\
+            </span>\n</p>\n</div>\n";
+        let words = parse_hocr_words(hocr);
+        let word = |line, bbox, text: &str, confidence, italic| {
+            return HocrWord {
+                line,
+                bbox: Some(bbox),
+                word: WordConfidence {
+                    text: String::from(text),
+                    confidence,
+                    italic,
+                },
+            };
+        };
+        assert_eq!(
+            words,
+            vec![
+                word(1, [12, 10, 102, 50], "Hello,", 96, false),
+                word(1, [110, 10, 230, 50], "w0rld", 41, true),
+                word(2, [12, 60, 90, 100], "Tom&Jerry", 88, true),
+                word(2, [100, 60, 200, 100], "\"Hi\"", 90, true),
+            ]
+        );
+        assert_eq!(
+            italic_text(&words),
+            "Hello, <i>w0rld</i>\n<i>Tom&Jerry \"Hi\"</i>"
+        );
+    }
+
+    #[test]
+    fn slanted_strokes() {
+        // Five strokes 3px wide and 30px tall, leaning by `slant`
+        let strokes = |slant: f32| {
+            let mut image = GrayImage::from_pixel(80, 40, image::Luma([255]));
+            for y in 5..35 {
+                let lean = ((35 - y) as f32 * slant).round() as u32;
+                for stroke in 0..5 {
+                    for x in 0..3 {
+                        image.put_pixel(8 + stroke * 12 + lean + x, y, image::Luma([0]));
+                    }
+                }
+            }
+            return image;
         };
+        assert_eq!(is_slanted(&strokes(0.0), [0, 0, 80, 40]), Some(false));
+        assert_eq!(is_slanted(&strokes(0.25), [0, 0, 80, 40]), Some(true));
+        assert_eq!(is_slanted(&strokes(0.25), [0, 0, 2, 2]), None);
+
+        let mut words = vec![
+            HocrWord {
+                line: 1,
+                bbox: Some([0, 0, 80, 40]),
+                word: WordConfidence {
+                    text: String::from("lll"),
+                    confidence: 90,
+                    italic: false,
+                },
+            },
+            HocrWord {
+                line: 1,
+                bbox: Some([0, 0, 2, 2]),
+                word: WordConfidence {
+                    text: String::from("."),
+                    confidence: 90,
+                    italic: false,
+                },
+            },
+        ];
+        mark_slanted_words(&strokes(0.25), &mut words);
+        assert!(words.iter().all(|word| word.word.italic));
     }
 }
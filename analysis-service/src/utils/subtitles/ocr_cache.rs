@@ -0,0 +1,244 @@
+//! Content-addressed cache for OCR results.
+//!
+//! PGS streams repeat the same bitmap at every acquisition point, and the
+//! same menus and warnings show up on every disc of a series. Entries are
+//! keyed on the exact image handed to tesseract, along with the language and
+//! settings it was recognized with, so identical images are only OCR'd once.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use image::GrayImage;
+use leptess::Variable;
+
+use super::ocr::OcrOutput;
+
+/// Bump this whenever OCR output changes for the same input, so stale
+/// entries in the disk tier are ignored.
+const CACHE_VERSION: u32 = 1;
+
+/// How many results are kept in memory before the oldest are evicted
+const MEMORY_CAPACITY: usize = 4096;
+
+pub type OcrCacheKey = [u8; 16];
+
+pub struct OcrCache(Arc<OcrCacheInner>);
+struct OcrCacheInner {
+    memory: Mutex<MemoryTier>,
+    disk_dir: Option<PathBuf>,
+}
+#[derive(Default)]
+struct MemoryTier {
+    entries: HashMap<OcrCacheKey, OcrOutput>,
+    /// Insertion order, for eviction
+    order: VecDeque<OcrCacheKey>,
+}
+impl OcrCache {
+    /// Creates a cache that only lives in memory
+    pub fn new() -> Self {
+        return Self(Arc::new(OcrCacheInner {
+            memory: Mutex::new(MemoryTier::default()),
+            disk_dir: None,
+        }));
+    }
+
+    /// Creates a cache that also persists results in `disk_dir`, so they
+    /// survive restarts
+    pub fn with_disk_tier(disk_dir: PathBuf) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(&disk_dir)?;
+        return Ok(Self(Arc::new(OcrCacheInner {
+            memory: Mutex::new(MemoryTier::default()),
+            disk_dir: Some(disk_dir),
+        })));
+    }
+
+    /// Hashes the OCR language and settings, to be mixed into each key
+    pub fn settings_digest(language: &str, variables: &[(Variable, String)]) -> OcrCacheKey {
+        let mut context = md5::Context::new();
+        context.consume(CACHE_VERSION.to_le_bytes());
+        context.consume(language.as_bytes());
+        for (key, value) in variables {
+            context.consume([0]);
+            context.consume(key.as_cstr().to_bytes());
+            context.consume([0]);
+            context.consume(value.as_bytes());
+        }
+        return context.compute().0;
+    }
+
+    pub fn key(settings_digest: &OcrCacheKey, image: &GrayImage) -> OcrCacheKey {
+        let mut context = md5::Context::new();
+        context.consume(settings_digest);
+        context.consume(image.width().to_le_bytes());
+        context.consume(image.height().to_le_bytes());
+        context.consume(image.as_raw());
+        return context.compute().0;
+    }
+
+    pub fn get(&self, key: &OcrCacheKey) -> Option<(OcrOutput, CacheTier)> {
+        if let Some(output) = self.0.memory.lock().unwrap().entries.get(key) {
+            return Some((output.clone(), CacheTier::Memory));
+        }
+        let path = self.0.disk_dir.as_ref()?.join(hex::encode(key));
+        let data = std::fs::read(path).ok()?;
+        let output: OcrOutput = match serde_json::from_slice(&data) {
+            Ok(output) => output,
+            Err(err) => {
+                eprintln!(
+                    "Corrupt OCR cache entry {}: {err}. Ignoring...",
+                    hex::encode(key)
+                );
+                return None;
+            }
+        };
+        self.insert_memory(*key, output.clone());
+        return Some((output, CacheTier::Disk));
+    }
+
+    /// Stores an OCR result. Review images aren't cached, since whether one
+    /// is needed depends on the request.
+    pub fn insert(&self, key: OcrCacheKey, output: &OcrOutput) {
+        let mut output = output.clone();
+        output.confidence.review_image = None;
+
+        if let Some(disk_dir) = &self.0.disk_dir {
+            // Write then rename, so other readers never see a partial entry
+            let path = disk_dir.join(hex::encode(key));
+            static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+            let temp_path = path.with_extension(format!(
+                "{}-{}.tmp",
+                std::process::id(),
+                TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let result = serde_json::to_vec(&output)
+                .map_err(std::io::Error::from)
+                .and_then(|data| std::fs::write(&temp_path, data))
+                .and_then(|_| std::fs::rename(&temp_path, &path));
+            if let Err(err) = result {
+                eprintln!(
+                    "Failed to write OCR cache entry {}: {err}",
+                    hex::encode(key)
+                );
+            }
+        }
+        self.insert_memory(key, output);
+    }
+
+    fn insert_memory(&self, key: OcrCacheKey, output: OcrOutput) {
+        let mut memory = self.0.memory.lock().unwrap();
+        if memory.entries.insert(key, output).is_some() {
+            return;
+        }
+        memory.order.push_back(key);
+        while memory.order.len() > MEMORY_CAPACITY {
+            let oldest = memory.order.pop_front().unwrap();
+            memory.entries.remove(&oldest);
+        }
+    }
+}
+impl Clone for OcrCache {
+    fn clone(&self) -> Self {
+        return Self(Arc::clone(&self.0));
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CacheTier {
+    Memory,
+    Disk,
+}
+
+/// Counts cache lookups over the course of an analysis
+#[derive(Default)]
+pub struct OcrCacheCounter {
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+}
+impl OcrCacheCounter {
+    pub fn record(&self, tier: Option<CacheTier>) {
+        let counter = match tier {
+            Some(CacheTier::Memory) => &self.memory_hits,
+            Some(CacheTier::Disk) => &self.disk_hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> OcrCacheStats {
+        return OcrCacheStats {
+            memory_hits: self.memory_hits.load(Ordering::Relaxed),
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct OcrCacheStats {
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+}
+impl OcrCacheStats {
+    /// Fraction of images that didn't need to be OCR'd, from 0 to 1
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.memory_hits + self.disk_hits;
+        let total = hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        return hits as f64 / total as f64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::subtitles::ocr::{OcrConfidence, WordConfidence};
+
+    #[test]
+    fn disk_tier_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("ocr-cache-test-{}", std::process::id()));
+        let settings = OcrCache::settings_digest("eng", &[]);
+        let image = GrayImage::from_pixel(4, 2, image::Luma([255]));
+        let key = OcrCache::key(&settings, &image);
+        let output = OcrOutput {
+            text: String::from("Hello"),
+            confidence: OcrConfidence {
+                mean: 90,
+                words: vec![WordConfidence {
+                    text: String::from("Hello"),
+                    confidence: 90,
+                    italic: false,
+                }],
+                review_image: Some(vec![1, 2, 3]),
+            },
+        };
+
+        let cache = OcrCache::with_disk_tier(dir.clone()).unwrap();
+        assert!(cache.get(&key).is_none());
+        cache.insert(key, &output);
+        assert_eq!(cache.get(&key).unwrap().1, CacheTier::Memory);
+
+        // A fresh cache only has the disk tier to go off of
+        let cache = OcrCache::with_disk_tier(dir.clone()).unwrap();
+        let (cached, tier) = cache.get(&key).unwrap();
+        assert_eq!(tier, CacheTier::Disk);
+        assert_eq!(cached.text, output.text);
+        assert_eq!(cached.confidence.words, output.confidence.words);
+        assert_eq!(cached.confidence.review_image, None);
+
+        // Different settings don't share entries
+        let other_settings = OcrCache::settings_digest("fra", &[]);
+        assert!(cache.get(&OcrCache::key(&other_settings, &image)).is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
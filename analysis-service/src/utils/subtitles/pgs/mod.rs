@@ -1,7 +1,7 @@
 //! This implements a PGS parser for the S_HDMV/PGS subtitle format.
 //! It is intended to be used for parsing data from MKV files, though
-//! it could be adapted to support other containers, or raw SUP files
-//! as well.
+//! it could be adapted to support other containers. Raw SUP files are
+//! supported through the adapter in `sup`.
 //!
 //! This code was implemented from the format described here:
 //! https://blog.thescorpius.com/index.php/2017/07/15/presentation-graphic-stream-sup-files-bluray-subtitle-format/
@@ -27,6 +27,7 @@ mod binary_reader;
 mod constants;
 mod pgs_types;
 pub mod processor;
+pub mod sup;
 mod window_adapter;
 
 #[derive(Error, Debug)]
@@ -52,6 +53,8 @@ pub enum PgsError {
     FormatError,
     #[error("Display set is incomplete.")]
     DsIncomplete,
+    #[error("Invalid SUP segment header.")]
+    InvalidSupHeader,
 }
 
 fn render_into_image<'a>(
@@ -118,9 +121,28 @@ fn render_into_image<'a>(
     return Ok(());
 }
 
+/// A subtitle that was shown and then taken off screen, in the timescale of
+/// the frames that were passed in
+pub struct PgsCue {
+    pub start: u64,
+    /// Absent if the stream ended while the subtitle was still shown, and
+    /// its block didn't have a duration
+    pub end: Option<u64>,
+    pub image: image::GrayAlphaImage,
+    pub forced: bool,
+}
+
+struct DisplayedComposition {
+    start: u64,
+    duration: Option<u64>,
+    image: image::GrayAlphaImage,
+    forced: bool,
+}
+
 #[derive(Default)]
 pub struct PgsParser {
     running_pcs: Option<PresentationComposition>,
+    displayed: Option<DisplayedComposition>,
     window_table: HashMap<u8, SingleWindowDefinition>,
     /// palette_id -> color_id -> color
     palette_table: HashMap<u8, HashMap<u8, LumaA<u8>>>,
@@ -132,11 +154,11 @@ impl PgsParser {
         return PgsParser::default();
     }
 
+    /// Processes a display set. If it takes a subtitle off screen, that
+    /// subtitle is returned.
+    ///
     /// NOTE: This assumes frame times have already been scaled
-    pub fn process_mkv_frame(
-        &mut self,
-        frame: &Frame,
-    ) -> Result<Option<image::GrayAlphaImage>, PgsError> {
+    pub fn process_mkv_frame(&mut self, frame: &Frame) -> Result<Option<PgsCue>, PgsError> {
         // Parse display set
         let mut data = PacketReader::new(&frame.data);
         let display_set = match read_display_set(&mut data) {
@@ -185,6 +207,7 @@ impl PgsParser {
         }
 
         // Update running PCS
+        let palette_update = display_set.pcs.palette_update_flag;
         match display_set.pcs.composition_state {
             CompositionState::AcquisitionPoint => {
                 if let Some(ref mut running_pcs) = self.running_pcs {
@@ -199,72 +222,158 @@ impl PgsParser {
             }
         }
 
-        // Render PCS
-        if let Some(ref pcs) = self.running_pcs {
-            let mut image = image::GrayAlphaImage::new(pcs.width as _, pcs.height as _);
-            let palette =
-                self.palette_table
-                    .get(&pcs.palette_id)
-                    .ok_or(PgsError::MissingPalette {
-                        palette_id: pcs.palette_id,
+        // Render PCS. A composition without any objects clears the screen.
+        let image = match self.running_pcs {
+            Some(ref pcs) if !pcs.composition_objects.is_empty() => Some(self.render(pcs)?),
+            _ => None,
+        };
+        let forced = self.running_pcs.as_ref().is_some_and(is_forced);
+        return Ok(self.update_display(
+            frame.timestamp,
+            frame.duration,
+            image,
+            forced,
+            palette_update,
+        ));
+    }
+
+    /// Ends the subtitle that's still on screen at the end of the stream.
+    /// It ends after its block duration, if there is one.
+    pub fn finish(&mut self) -> Option<PgsCue> {
+        let displayed = self.displayed.take()?;
+        return Some(PgsCue {
+            start: displayed.start,
+            end: displayed
+                .duration
+                .map(|duration| displayed.start + duration),
+            image: displayed.image,
+            forced: displayed.forced,
+        });
+    }
+
+    /// Pairs each shown composition with the clear or replacement that
+    /// follows it, returning the subtitle that was just taken off screen.
+    fn update_display(
+        &mut self,
+        timestamp: u64,
+        duration: Option<u64>,
+        image: Option<image::GrayAlphaImage>,
+        forced: bool,
+        palette_update: bool,
+    ) -> Option<PgsCue> {
+        if let (Some(displayed), Some(image)) = (&mut self.displayed, &image) {
+            // Acquisition points repeat what's already on screen, and palette
+            // updates are used for fades. Neither starts a new subtitle.
+            if palette_update {
+                if image.pixels().any(|pixel| pixel.0[1] > 0) {
+                    displayed.image = image.clone();
+                }
+                return None;
+            }
+            if displayed.image == *image {
+                return None;
+            }
+        }
+        let ended = self.displayed.take().map(|displayed| PgsCue {
+            start: displayed.start,
+            end: Some(timestamp),
+            image: displayed.image,
+            forced: displayed.forced,
+        });
+        self.displayed = image.map(|image| DisplayedComposition {
+            start: timestamp,
+            duration,
+            image,
+            forced,
+        });
+        return ended;
+    }
+
+    fn render(&self, pcs: &PresentationComposition) -> Result<image::GrayAlphaImage, PgsError> {
+        let mut image = image::GrayAlphaImage::new(pcs.width as _, pcs.height as _);
+        let palette = self
+            .palette_table
+            .get(&pcs.palette_id)
+            .ok_or(PgsError::MissingPalette {
+                palette_id: pcs.palette_id,
+                composition_number: pcs.composition_number,
+            })?;
+        for object in pcs.composition_objects.iter() {
+            let object_def =
+                self.object_table
+                    .get(&object.object_id)
+                    .ok_or(PgsError::MissingObject {
+                        object_id: object.object_id,
                         composition_number: pcs.composition_number,
                     })?;
-            for object in pcs.composition_objects.iter() {
-                let object_def =
-                    self.object_table
-                        .get(&object.object_id)
-                        .ok_or(PgsError::MissingObject {
-                            object_id: object.object_id,
-                            composition_number: pcs.composition_number,
-                        })?;
-                let window_def =
-                    self.window_table
-                        .get(&object.window_id)
-                        .ok_or(PgsError::MissingWindow {
-                            window_id: object.window_id,
-                            composition_number: pcs.composition_number,
-                        })?;
-                let mut image_window = if object.object_cropped_flag {
-                    let horizontal_offset = object
-                        .object_horizontal_pos
-                        .saturating_sub(window_def.horizontal_pos)
-                        as u32;
-                    let vertical_offset = object
-                        .object_vertical_pos
-                        .saturating_sub(window_def.vertical_pos)
-                        as u32;
-                    ImageWindow::with_window_cropped(
-                        &mut image,
-                        window_def.horizontal_pos as u32 + horizontal_offset,
-                        window_def.vertical_pos as u32 + vertical_offset,
-                        object.object_cropping_width as u32,
-                        object.object_cropping_height as u32,
-                        object.object_cropping_horizontal_pos as u32,
-                        object.object_cropping_vertical_pos as u32,
-                    )
-                } else {
-                    let horizontal_offset = object
-                        .object_horizontal_pos
-                        .saturating_sub(window_def.horizontal_pos)
-                        as u32;
-                    let vertical_offset = object
-                        .object_vertical_pos
-                        .saturating_sub(window_def.vertical_pos)
-                        as u32;
-                    ImageWindow::with_window(
-                        &mut image,
-                        window_def.horizontal_pos as u32 + horizontal_offset,
-                        window_def.vertical_pos as u32 + horizontal_offset,
-                        window_def.width as u32 - horizontal_offset,
-                        window_def.height as u32 - vertical_offset,
-                    )
-                };
-                render_into_image(&mut image_window, palette, &object_def.rle_data)?;
-            }
-            return Ok(Some(image));
+            let window_def =
+                self.window_table
+                    .get(&object.window_id)
+                    .ok_or(PgsError::MissingWindow {
+                        window_id: object.window_id,
+                        composition_number: pcs.composition_number,
+                    })?;
+            let horizontal_offset = object
+                .object_horizontal_pos
+                .saturating_sub(window_def.horizontal_pos)
+                as u32;
+            let vertical_offset = object
+                .object_vertical_pos
+                .saturating_sub(window_def.vertical_pos) as u32;
+            let mut image_window = if object.object_cropped_flag {
+                ImageWindow::with_window_cropped(
+                    &mut image,
+                    window_def.horizontal_pos as u32 + horizontal_offset,
+                    window_def.vertical_pos as u32 + vertical_offset,
+                    object.object_cropping_width as u32,
+                    object.object_cropping_height as u32,
+                    object.object_cropping_horizontal_pos as u32,
+                    object.object_cropping_vertical_pos as u32,
+                )
+            } else {
+                ImageWindow::with_window(
+                    &mut image,
+                    window_def.horizontal_pos as u32 + horizontal_offset,
+                    window_def.vertical_pos as u32 + vertical_offset,
+                    window_def.width as u32 - horizontal_offset,
+                    window_def.height as u32 - vertical_offset,
+                )
+            };
+            render_into_image(&mut image_window, palette, &object_def.rle_data)?;
         }
+        return Ok(image);
+    }
+}
 
-        return Ok(None);
+/// Whether a composition only shows forced objects
+fn is_forced(pcs: &PresentationComposition) -> bool {
+    return !pcs.composition_objects.is_empty()
+        && pcs
+            .composition_objects
+            .iter()
+            .all(|object| object.object_forced_on_flag);
+}
+
+/// Counts a PGS block towards a track's subtitles, without decoding it.
+/// Returns `Some(forced)` if the block shows a new subtitle.
+pub fn peek_new_subtitle(data: &[u8]) -> Option<bool> {
+    let mut data = PacketReader::new(data);
+    loop {
+        let segment_type = data.read_u8()?;
+        let segment_size = data.read_u16()?;
+        let segment = data.take_bytes(segment_size as usize)?;
+        if segment_type != PGS_SEGMENT_TYPE_PCS {
+            continue;
+        }
+        let pcs = parse_pcs(segment).ok()?;
+        // Acquisition points repeat what's shown, and palette updates are fades
+        if pcs.composition_objects.is_empty()
+            || pcs.composition_state == CompositionState::AcquisitionPoint
+            || pcs.palette_update_flag
+        {
+            return None;
+        }
+        return Some(is_forced(&pcs));
     }
 }
 
@@ -404,7 +513,7 @@ fn parse_pcs(data: &[u8]) -> Result<PresentationComposition, PgsError> {
         0x00 => CompositionState::Normal,
         0x40 => CompositionState::AcquisitionPoint,
         0x80 => CompositionState::EpochStart,
-        _ => panic!("Invalid composition state"),
+        _ => return Err(PgsError::FormatError),
     };
     let palette_update_flag = data.read_u8().ok_or(PgsError::FormatError)? > 0;
     let palette_id = data.read_u8().ok_or(PgsError::FormatError)?;
@@ -414,7 +523,9 @@ fn parse_pcs(data: &[u8]) -> Result<PresentationComposition, PgsError> {
     for _ in 0..composition_object_len {
         let object_id = data.read_u16().ok_or(PgsError::FormatError)?;
         let window_id = data.read_u8().ok_or(PgsError::FormatError)?;
-        let object_cropped_flag = data.read_u8().ok_or(PgsError::FormatError)? & 0x80 > 0;
+        let object_flags = data.read_u8().ok_or(PgsError::FormatError)?;
+        let object_cropped_flag = object_flags & 0x80 > 0;
+        let object_forced_on_flag = object_flags & 0x40 > 0;
         let object_horizontal_pos = data.read_u16().ok_or(PgsError::FormatError)?;
         let object_vertical_pos = data.read_u16().ok_or(PgsError::FormatError)?;
 
@@ -442,6 +553,7 @@ fn parse_pcs(data: &[u8]) -> Result<PresentationComposition, PgsError> {
             object_id,
             window_id,
             object_cropped_flag,
+            object_forced_on_flag,
             object_horizontal_pos,
             object_vertical_pos,
             object_cropping_horizontal_pos,
@@ -477,3 +589,137 @@ fn parse_wds(data: &[u8]) -> Result<Vec<SingleWindowDefinition>, PgsError> {
     }
     return Ok(windows);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pair_compositions_with_clears() {
+        let blank = image::GrayAlphaImage::new(4, 4);
+        let hello = image::GrayAlphaImage::from_pixel(4, 4, LumaA([200, 255]));
+        let world = image::GrayAlphaImage::from_pixel(4, 4, LumaA([100, 255]));
+        let faded = image::GrayAlphaImage::from_pixel(4, 4, LumaA([100, 128]));
+
+        let mut parser = PgsParser::new();
+        assert!(
+            parser
+                .update_display(1_000, None, Some(hello.clone()), false, false)
+                .is_none()
+        );
+        // An acquisition point repeating the same image
+        assert!(
+            parser
+                .update_display(2_000, None, Some(hello.clone()), false, false)
+                .is_none()
+        );
+        // Replaced by a different image
+        let cue = parser
+            .update_display(3_000, None, Some(world.clone()), true, false)
+            .unwrap();
+        assert_eq!((cue.start, cue.end), (1_000, Some(3_000)));
+        assert_eq!(cue.image, hello);
+        // Fades don't end the subtitle, even once it's fully transparent
+        assert!(
+            parser
+                .update_display(4_000, None, Some(faded.clone()), true, true)
+                .is_none()
+        );
+        assert!(
+            parser
+                .update_display(4_500, None, Some(blank), true, true)
+                .is_none()
+        );
+        let cue = parser
+            .update_display(5_000, None, None, false, false)
+            .unwrap();
+        assert_eq!((cue.start, cue.end), (3_000, Some(5_000)));
+        assert_eq!(cue.image, faded);
+        assert!(cue.forced);
+
+        // Nothing left on screen
+        assert!(
+            parser
+                .update_display(6_000, None, None, false, false)
+                .is_none()
+        );
+        parser.update_display(7_000, Some(500), Some(hello), false, false);
+        let cue = parser.finish().unwrap();
+        assert_eq!((cue.start, cue.end), (7_000, Some(7_500)));
+    }
+
+    #[test]
+    fn peek_forced_compositions() {
+        #[rustfmt::skip]
+        let display_set = |state: u8, object_flags: u8| vec![
+            PGS_SEGMENT_TYPE_PCS, 0, 19,
+            // 1920x1080, composition 1
+            0x07, 0x80, 0x04, 0x38, 0x10, 0, 1, state, 0, 0, 1,
+            // Object 0 in window 0
+            0, 0, 0, object_flags, 0, 100, 0, 100,
+            PGS_SEGMENT_TYPE_END, 0, 0,
+        ];
+        assert_eq!(peek_new_subtitle(&display_set(0x80, 0x40)), Some(true));
+        assert_eq!(peek_new_subtitle(&display_set(0x00, 0x00)), Some(false));
+        // Acquisition points repeat what's already shown
+        assert_eq!(peek_new_subtitle(&display_set(0x40, 0x40)), None);
+    }
+
+    #[test]
+    fn render_object_inside_window() {
+        let mut parser = PgsParser::new();
+        parser.window_table.insert(
+            0,
+            SingleWindowDefinition {
+                window_id: 0,
+                horizontal_pos: 10,
+                vertical_pos: 20,
+                width: 8,
+                height: 8,
+            },
+        );
+        parser
+            .palette_table
+            .insert(0, HashMap::from([(1, LumaA([255, 255]))]));
+        parser.object_table.insert(
+            0,
+            ObjectDefinition {
+                object_id: 0,
+                object_version: 0,
+                last_in_sequence: LastInSequence::all(),
+                width: 2,
+                height: 1,
+                // Two pixels in color 1, then end of line
+                rle_data: vec![1, 1, 0, 0],
+            },
+        );
+        let pcs = PresentationComposition {
+            width: 64,
+            height: 64,
+            frame_rate: 0x10,
+            composition_number: 1,
+            composition_state: CompositionState::EpochStart,
+            palette_update_flag: false,
+            palette_id: 0,
+            composition_objects: vec![CompositionObject {
+                object_id: 0,
+                window_id: 0,
+                object_cropped_flag: false,
+                object_forced_on_flag: false,
+                object_horizontal_pos: 12,
+                object_vertical_pos: 25,
+                object_cropping_horizontal_pos: 0,
+                object_cropping_vertical_pos: 0,
+                object_cropping_width: 0,
+                object_cropping_height: 0,
+            }],
+        };
+        let image = parser.render(&pcs).unwrap();
+        let drawn: Vec<(u32, u32)> = image
+            .enumerate_pixels()
+            .filter(|(_x, _y, pixel)| pixel.0[1] > 0)
+            .map(|(x, y, _pixel)| (x, y))
+            .collect();
+        assert_eq!(drawn, vec![(12, 25), (13, 25)]);
+    }
+}
@@ -62,6 +62,8 @@ pub struct CompositionObject {
     pub object_id: u16,
     pub window_id: u8,
     pub object_cropped_flag: bool,
+    /// Forced objects are shown even when subtitles are turned off
+    pub object_forced_on_flag: bool,
     pub object_horizontal_pos: u16,
     pub object_vertical_pos: u16,
     pub object_cropping_horizontal_pos: u16,
@@ -1,36 +1,41 @@
-use image::{GrayAlphaImage, GrayImage};
+use std::sync::Arc;
+
 use leptess::Variable;
 
 use crate::{
     rayon_helpers::BackpressuredRayon,
     utils::subtitles::{
-        ExtractDetailsError, Subtitle,
+        ExtractDetailsError, ScreenRegion, Subtitle,
         ocr::{Partess, PartessCache},
-        process_graya_image,
-        utils::crop_gray_image,
+        ocr_cache::OcrCacheCounter,
+        preprocess::{PreprocessOptions, prepare_image},
+        utils::{crop_gray_image, gray_image_bounds},
     },
 };
 
-use super::{PgsError, PgsParser};
+use super::{PgsCue, PgsError, PgsParser};
 
 pub struct PgsProcessor {
     pgs_parser: PgsParser,
+    cache_counter: Arc<OcrCacheCounter>,
     rayon_pool: BackpressuredRayon<
         Box<
-            dyn Fn(
-                    (u64, Option<u64>, GrayAlphaImage),
-                )
-                    -> Result<Option<(u64, Option<u64>, String)>, ExtractDetailsError>
+            dyn Fn(PgsCue) -> Result<Option<Subtitle>, ExtractDetailsError>
                 + Send
                 + Sync
                 + 'static,
         >,
-        (u64, Option<u64>, GrayAlphaImage),
-        Result<Option<(u64, Option<u64>, String)>, ExtractDetailsError>,
+        PgsCue,
+        Result<Option<Subtitle>, ExtractDetailsError>,
     >,
 }
 impl PgsProcessor {
-    pub fn new(partess_cache: &PartessCache, language: &str) -> Result<Self, ExtractDetailsError> {
+    pub fn new(
+        partess_cache: &PartessCache,
+        language: &str,
+        review_threshold: Option<u8>,
+        preprocessing: Option<PreprocessOptions>,
+    ) -> Result<Self, ExtractDetailsError> {
         let mut cache = partess_cache.cache.lock().unwrap();
         let partess = match cache.get(language) {
             Some(partess) => partess.clone(),
@@ -43,39 +48,57 @@ impl PgsProcessor {
                         (Variable::TesseditPagesegMode, String::from("6")),
                         (Variable::TesseditDoInvert, String::from("0")),
                         (Variable::TesseditCharBlacklist, String::from("|\\/`_~{}")),
+                        // Marks italic words in the hOCR output for the legacy engine;
+                        // the LSTM engine relies on slant detection instead
+                        (Variable::HocrFontInfo, String::from("1")),
                     ],
+                    partess_cache.ocr_cache.clone(),
                 );
                 cache.insert(String::from(language), partess.clone());
                 partess
             }
         };
         drop(cache);
+        let cache_counter = Arc::new(OcrCacheCounter::default());
+        let closure_counter = Arc::clone(&cache_counter);
         return Ok(Self {
+            cache_counter,
             pgs_parser: PgsParser::new(),
             rayon_pool: BackpressuredRayon::new(
                 5,
-                Box::new(move |(timestamp, duration, image)| {
-                    let image = crop_gray_image(&image);
-                    if image.width() == 0 || image.height() == 0 {
+                Box::new(move |cue: PgsCue| {
+                    let image = cue.image;
+                    // The image covers the whole screen, so the bounds are its position
+                    let Some(bounds) = gray_image_bounds(&image) else {
                         return Ok(None);
-                    }
-                    let image: GrayImage = process_graya_image(image);
-                    let mut partess = partess.get()?;
-                    let sub = partess.ocr_image(image)?;
-                    return Ok(Some((timestamp, duration, sub)));
+                    };
+                    let (x1, y1, x2, y2) = bounds;
+                    let region = ScreenRegion {
+                        x: x1,
+                        y: y1,
+                        width: x2 + 1 - x1,
+                        height: y2 + 1 - y1,
+                        screen_width: image.width(),
+                        screen_height: image.height(),
+                    };
+                    let image = crop_gray_image(&image, bounds);
+                    let images = prepare_image(image, preprocessing.as_ref());
+                    let output = partess.ocr_regions(images, review_threshold, &closure_counter)?;
+                    return Ok(Some(Subtitle {
+                        timestamp: cue.start,
+                        duration: cue.end.map(|end| end.saturating_sub(cue.start)),
+                        data: output.text,
+                        confidence: Some(output.confidence),
+                        region: Some(region),
+                        forced: cue.forced,
+                    }));
                 }),
             ),
         });
     }
     pub fn push_frame(&mut self, frame: &matroska_demuxer::Frame) -> Result<(), PgsError> {
         match self.pgs_parser.process_mkv_frame(frame) {
-            Ok(Some(image)) => {
-                self.rayon_pool.push_data((
-                    frame.timestamp / 1000,
-                    frame.duration.map(|duration| duration / 1000),
-                    image,
-                ));
-            }
+            Ok(Some(cue)) => self.push_cue(cue),
             Ok(None) => {}
             Err(err) => {
                 eprintln!("Error in frame at {}ms: {}. Ignoring...", frame.timestamp, err);
@@ -83,21 +106,29 @@ impl PgsProcessor {
         }
         return Ok(());
     }
-    pub fn collect(self) -> Result<Vec<Subtitle>, ExtractDetailsError> {
+    fn push_cue(&self, cue: PgsCue) {
+        // Nanoseconds to microseconds
+        self.rayon_pool.push_data(PgsCue {
+            start: cue.start / 1000,
+            end: cue.end.map(|end| end / 1000),
+            ..cue
+        });
+    }
+    /// Tallies OCR cache lookups. The counts are final once `collect` returns.
+    pub fn cache_counter(&self) -> Arc<OcrCacheCounter> {
+        return Arc::clone(&self.cache_counter);
+    }
+    pub fn collect(mut self) -> Result<Vec<Subtitle>, ExtractDetailsError> {
+        if let Some(cue) = self.pgs_parser.finish() {
+            self.push_cue(cue);
+        }
         let mut subs: Vec<_> = self
             .rayon_pool
             .try_collect()?
             .into_iter()
             .filter_map(|item| item)
             .collect();
-        subs.sort_by_key(|(timestamp, _duration, _sub)| *timestamp);
-        return Ok(subs
-            .into_iter()
-            .map(|(timestamp, duration, data)| Subtitle {
-                timestamp,
-                duration,
-                data,
-            })
-            .collect());
+        subs.sort_by_key(|subtitle| subtitle.timestamp);
+        return Ok(subs);
     }
 }
@@ -0,0 +1,65 @@
+//! Reader for raw PGS (.sup) files, as produced by Blu-ray demuxers.
+//!
+//! A SUP file is the same segment stream that Matroska stores in
+//! S_HDMV/PGS blocks, except every segment is prefixed with a `PG` magic
+//! number and its own presentation/decoding timestamps. This strips those
+//! headers and regroups segments into display sets, so the rest of the
+//! PGS pipeline can't tell the difference.
+
+use std::io::{ErrorKind, Read};
+
+use matroska_demuxer::Frame;
+
+use crate::utils::ExtractDetailsError;
+
+use super::{PgsError, constants::PGS_SEGMENT_TYPE_END};
+
+const SUP_MAGIC: [u8; 2] = *b"PG";
+
+pub struct SupReader<R: Read> {
+    reader: R,
+}
+impl<R: Read> SupReader<R> {
+    pub fn new(reader: R) -> Self {
+        return Self { reader };
+    }
+
+    /// Reads the next display set into the given `Frame`, with its timestamp
+    /// scaled to nanoseconds like a demuxed Matroska frame.
+    ///
+    /// Returns `false` at the end of the file.
+    pub fn next_frame(&mut self, frame: &mut Frame) -> Result<bool, ExtractDetailsError> {
+        frame.data.clear();
+        frame.duration = None;
+        loop {
+            let mut header = [0u8; 13];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {}
+                // A clean EOF between display sets
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof && frame.data.is_empty() => {
+                    return Ok(false);
+                }
+                Err(err) => return Err(err.into()),
+            }
+            if header[0..2] != SUP_MAGIC {
+                return Err(PgsError::InvalidSupHeader.into());
+            }
+            let pts = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+            let segment_type = header[10];
+            let segment_size = u16::from_be_bytes([header[11], header[12]]);
+
+            if frame.data.is_empty() {
+                // 90kHz clock
+                frame.timestamp = pts as u64 * 100_000 / 9;
+            }
+            frame.data.extend_from_slice(&header[10..13]);
+            let data_start = frame.data.len();
+            frame.data.resize(data_start + segment_size as usize, 0);
+            self.reader.read_exact(&mut frame.data[data_start..])?;
+
+            if segment_type == PGS_SEGMENT_TYPE_END {
+                return Ok(true);
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Prepares subtitle bitmaps for OCR.
+//!
+//! Decoded bitmaps are light text with a dark outline, drawn at whatever
+//! size the disc was authored for. Tesseract does best on solid dark text on
+//! a white page, with glyphs around 30px tall, so thin DVD fonts and
+//! anti-aliased PGS text are cleaned up before they're OCR'd.
+
+use image::{
+    GrayAlphaImage, GrayImage, Luma, Pixel, RgbaImage,
+    imageops::{self, FilterType},
+};
+
+use super::utils::{crop_gray_image, gray_image_bounds};
+
+/// Outlines are only removed if the fill is at least this much brighter
+const OUTLINE_CONTRAST: u8 = 96;
+
+/// White margin added around each image, in output pixels
+const PADDING: u32 = 10;
+
+/// How much darker than its surroundings a pixel has to be to count as text
+/// when thresholding, from 0 to 1
+const THRESHOLD_SENSITIVITY: f64 = 0.15;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PreprocessOptions {
+    /// Factor to upscale images by. 1 leaves them at their native size.
+    pub scale: u32,
+    /// Binarizes each pixel against the mean of a window this many source
+    /// pixels around it. Anti-aliased edges are left gray if unset.
+    pub threshold_radius: Option<u32>,
+    /// Drops outlines and background boxes, keeping only the text fill
+    pub remove_outline: bool,
+    /// OCRs regions separated by a wide vertical gap on their own, ie. a
+    /// sign at the top of the screen and dialogue at the bottom
+    pub split_regions: bool,
+}
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        return Self {
+            scale: 2,
+            threshold_radius: Some(8),
+            remove_outline: true,
+            split_regions: true,
+        };
+    }
+}
+
+/// Turns a cropped subtitle bitmap into dark text on white for OCR. Without
+/// `options`, the image is only inverted, as it always used to be.
+///
+/// Returns one image per region, from top to bottom.
+pub fn prepare_image(image: GrayAlphaImage, options: Option<&PreprocessOptions>) -> Vec<GrayImage> {
+    let Some(options) = options else {
+        return vec![invert_image(&image)];
+    };
+    let regions = match options.split_regions {
+        true => split_regions(&image),
+        false => vec![image],
+    };
+    return regions
+        .into_iter()
+        .map(|mut region| {
+            if options.remove_outline {
+                remove_outline(&mut region);
+            }
+            let mut gray = pad_image(&composite_image(&region), PADDING / options.scale.max(1));
+            if options.scale > 1 {
+                gray = imageops::resize(
+                    &gray,
+                    gray.width() * options.scale,
+                    gray.height() * options.scale,
+                    FilterType::CatmullRom,
+                );
+            }
+            if let Some(radius) = options.threshold_radius {
+                adaptive_threshold(&mut gray, radius * options.scale.max(1));
+            }
+            return gray;
+        })
+        .collect();
+}
+
+/// Keeps the luminance and transparency of a VobSub bitmap, so it can go
+/// through `prepare_image`
+pub fn graya_from_rgba(image: &RgbaImage) -> GrayAlphaImage {
+    let mut gray_image = GrayAlphaImage::new(image.width(), image.height());
+    for (src_pixel, dest_pixel) in image.pixels().zip(gray_image.pixels_mut()) {
+        dest_pixel.0 = [src_pixel.to_luma().0[0], src_pixel.0[3]];
+    }
+    return gray_image;
+}
+
+/// Inverts visible pixels regardless of their transparency, and makes the
+/// rest white
+fn invert_image(image: &GrayAlphaImage) -> GrayImage {
+    let mut gray_image = GrayImage::new(image.width(), image.height());
+    for (src_pixel, dest_pixel) in image.pixels().zip(gray_image.pixels_mut()) {
+        if src_pixel.0[1] == 0 {
+            dest_pixel.0 = [255];
+            continue;
+        }
+        dest_pixel.0 = [255 - src_pixel.0[0]];
+    }
+    return gray_image;
+}
+
+/// Draws the image over black and inverts it, so partially transparent edges
+/// come out lighter rather than as solid text
+fn composite_image(image: &GrayAlphaImage) -> GrayImage {
+    let mut gray_image = GrayImage::new(image.width(), image.height());
+    for (src_pixel, dest_pixel) in image.pixels().zip(gray_image.pixels_mut()) {
+        let [luma, alpha] = src_pixel.0;
+        dest_pixel.0 = [255 - (luma as u16 * alpha as u16 / 255) as u8];
+    }
+    return gray_image;
+}
+
+/// Splits an image at every run of blank rows that's taller than twice the
+/// content on either side of it. Line spacing within a block of dialogue is
+/// much smaller than that.
+fn split_regions(image: &GrayAlphaImage) -> Vec<GrayAlphaImage> {
+    let blank_rows: Vec<bool> = (0..image.height())
+        .map(|y| (0..image.width()).all(|x| image.get_pixel(x, y).0[1] == 0))
+        .collect();
+    // (start, end) of each run of rows with content, exclusive
+    let mut bands: Vec<(u32, u32)> = Vec::new();
+    for (y, blank) in blank_rows.iter().enumerate() {
+        let y = y as u32;
+        match (blank, bands.last_mut()) {
+            (false, Some((_start, end))) if *end == y => *end = y + 1,
+            (false, _) => bands.push((y, y + 1)),
+            (true, _) => {}
+        }
+    }
+
+    // Merge bands that are close together back into regions
+    let mut regions: Vec<(u32, u32)> = Vec::new();
+    for band in bands {
+        match regions.last_mut() {
+            Some(region) if band.0 - region.1 <= 2 * (region.1 - region.0).max(band.1 - band.0) => {
+                region.1 = band.1;
+            }
+            _ => regions.push(band),
+        }
+    }
+    if regions.len() <= 1 {
+        return vec![image.clone()];
+    }
+    return regions
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let band = imageops::crop_imm(image, 0, start, image.width(), end - start).to_image();
+            let bounds = gray_image_bounds(&band)?;
+            return Some(crop_gray_image(&band, bounds));
+        })
+        .collect();
+}
+
+/// Makes every visible pixel darker than the midpoint between the darkest
+/// and brightest ones transparent. Subtitles are almost always light text
+/// with a dark outline, so this leaves the fill.
+fn remove_outline(image: &mut GrayAlphaImage) {
+    let opaque = image.pixels().filter(|pixel| pixel.0[1] >= 128);
+    let (min, max) = opaque.fold((u8::MAX, u8::MIN), |(min, max), pixel| {
+        return (min.min(pixel.0[0]), max.max(pixel.0[0]));
+    });
+    if max < min || max - min < OUTLINE_CONTRAST {
+        // No outline, or a single color
+        return;
+    }
+    let midpoint = ((min as u16 + max as u16) / 2) as u8;
+    for pixel in image.pixels_mut() {
+        if pixel.0[0] < midpoint {
+            pixel.0[1] = 0;
+        }
+    }
+}
+
+fn pad_image(image: &GrayImage, padding: u32) -> GrayImage {
+    let mut padded = GrayImage::from_pixel(
+        image.width() + 2 * padding,
+        image.height() + 2 * padding,
+        Luma([255]),
+    );
+    imageops::replace(&mut padded, image, padding as i64, padding as i64);
+    return padded;
+}
+
+/// Bradley's adaptive threshold: pixels sufficiently darker than the mean of
+/// the window around them become black, and everything else white
+fn adaptive_threshold(image: &mut GrayImage, radius: u32) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    // Summed-area table, with an extra row and column of zeroes
+    let mut sums = vec![0u64; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row_sum = 0;
+        for x in 0..width {
+            row_sum += image.get_pixel(x as u32, y as u32).0[0] as u64;
+            sums[(y + 1) * (width + 1) + x + 1] = sums[y * (width + 1) + x + 1] + row_sum;
+        }
+    }
+    let radius = radius as usize;
+    for y in 0..height {
+        let (y1, y2) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+        for x in 0..width {
+            let (x1, x2) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+            let area = ((x2 - x1) * (y2 - y1)) as f64;
+            let sum = sums[y2 * (width + 1) + x2] + sums[y1 * (width + 1) + x1]
+                - sums[y1 * (width + 1) + x2]
+                - sums[y2 * (width + 1) + x1];
+            let pixel = image.get_pixel_mut(x as u32, y as u32);
+            let dark = pixel.0[0] as f64 * area <= sum as f64 * (1.0 - THRESHOLD_SENSITIVITY);
+            pixel.0 = [if dark { 0 } else { 255 }];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::subtitles::{
+        ocr::Partess,
+        ocr_cache::{OcrCache, OcrCacheCounter},
+    };
+    use image::LumaA;
+    use leptess::Variable;
+
+    /// Draws opaque white rectangles, given as (x, y, width, height), with a
+    /// 1px black outline
+    fn draw_boxes(width: u32, height: u32, boxes: &[(u32, u32, u32, u32)]) -> GrayAlphaImage {
+        let mut image = GrayAlphaImage::new(width, height);
+        for (x, y, box_width, box_height) in boxes {
+            for py in *y - 1..=y + box_height {
+                for px in *x - 1..=x + box_width {
+                    let inside =
+                        (*x..x + box_width).contains(&px) && (*y..y + box_height).contains(&py);
+                    image.put_pixel(px, py, LumaA([if inside { 255 } else { 0 }, 255]));
+                }
+            }
+        }
+        return image;
+    }
+
+    #[test]
+    fn split_distant_regions() {
+        // Two lines of dialogue
+        let dialogue = draw_boxes(100, 60, &[(10, 5, 80, 20), (10, 30, 60, 20)]);
+        assert_eq!(split_regions(&dialogue).len(), 1);
+
+        // A sign at the top and dialogue at the bottom
+        let sign = draw_boxes(100, 300, &[(30, 5, 40, 20), (10, 260, 80, 20)]);
+        let regions = split_regions(&sign);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].dimensions(), (42, 22));
+        assert_eq!(regions[1].dimensions(), (82, 22));
+    }
+
+    #[test]
+    fn remove_box_outlines() {
+        let image = draw_boxes(20, 20, &[(5, 5, 10, 10)]);
+        let options = PreprocessOptions {
+            scale: 1,
+            threshold_radius: None,
+            remove_outline: true,
+            split_regions: false,
+        };
+        let prepared = prepare_image(image, Some(&options)).pop().unwrap();
+        let padding = PADDING;
+        // The fill is black, and the outline as white as the background
+        assert_eq!(prepared.get_pixel(padding + 10, padding + 10).0, [0]);
+        assert_eq!(prepared.get_pixel(padding + 4, padding + 10).0, [255]);
+        assert_eq!(prepared.get_pixel(padding, padding).0, [255]);
+    }
+
+    /// Subtitle bitmaps rendered in the style of PGS (anti-aliased, with a
+    /// soft outline) and VobSub (four colors) tracks, and the text in them.
+    /// Samples are separated by blank lines, and start with their file name.
+    const SAMPLES: &str = include_str!("../../../test_data/ocr_samples/samples.txt");
+
+    /// Characters in `expected` that OCR got right, from 0 to 1
+    fn character_accuracy(expected: &str, actual: &str) -> f64 {
+        let expected: Vec<char> = expected.chars().collect();
+        let actual: Vec<char> = actual.trim().chars().collect();
+        let mut row: Vec<usize> = (0..=actual.len()).collect();
+        for (i, expected_char) in expected.iter().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, actual_char) in actual.iter().enumerate() {
+                let substitution = diagonal + (expected_char != actual_char) as usize;
+                diagonal = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+            }
+        }
+        return 1.0 - (row[actual.len()] as f64 / expected.len() as f64).min(1.0);
+    }
+
+    #[test]
+    #[ignore = "needs tesseract with English data installed"]
+    fn preprocessing_accuracy() {
+        let partess = Partess::new(
+            String::from("eng"),
+            vec![
+                (Variable::ClassifyEnableLearning, String::from("0")),
+                (Variable::TesseditPagesegMode, String::from("6")),
+                (Variable::TesseditDoInvert, String::from("0")),
+                (Variable::TesseditCharBlacklist, String::from("|\\/`_~{}")),
+            ],
+            OcrCache::new(),
+        );
+        let counter = OcrCacheCounter::default();
+        let ocr = |image: &GrayAlphaImage, options: Option<&PreprocessOptions>| {
+            return prepare_image(image.clone(), options)
+                .into_iter()
+                .map(|region| partess.ocr_image(region, None, &counter).unwrap().text)
+                .map(|text| String::from(text.trim()))
+                .collect::<Vec<_>>()
+                .join("\n");
+        };
+
+        let options = PreprocessOptions::default();
+        let (mut raw_total, mut prepared_total, mut samples) = (0.0, 0.0, 0);
+        for sample in SAMPLES
+            .split("\n\n")
+            .filter(|sample| !sample.trim().is_empty())
+        {
+            let (file_name, expected) = sample.split_once('\n').expect("Malformed sample entry");
+            let path = format!(
+                "{}/test_data/ocr_samples/{file_name}",
+                env!("CARGO_MANIFEST_DIR")
+            );
+            let image = image::open(path).unwrap().to_luma_alpha8();
+            let bounds = gray_image_bounds(&image).unwrap();
+            let image = crop_gray_image(&image, bounds);
+
+            let raw = character_accuracy(expected.trim_end(), &ocr(&image, None));
+            let prepared = character_accuracy(expected.trim_end(), &ocr(&image, Some(&options)));
+            println!(
+                "{file_name}: {:.1}% raw, {:.1}% preprocessed",
+                raw * 100.0,
+                prepared * 100.0
+            );
+            raw_total += raw;
+            prepared_total += prepared;
+            samples += 1;
+        }
+        let raw_mean = raw_total / samples as f64;
+        let prepared_mean = prepared_total / samples as f64;
+        println!(
+            "Mean: {:.1}% raw, {:.1}% preprocessed",
+            raw_mean * 100.0,
+            prepared_mean * 100.0
+        );
+        assert!(prepared_mean >= raw_mean);
+        assert!(prepared_mean > 0.9);
+    }
+}
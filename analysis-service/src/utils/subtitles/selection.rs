@@ -0,0 +1,343 @@
+//! Picks which subtitle track to OCR when the request doesn't name one.
+//!
+//! Discs often carry several English tracks: the main one, SDH, forced-only
+//! and commentary. Track flags are unreliable, so before any OCR happens a
+//! handful of short sections spread across the file are demuxed, and each
+//! candidate is scored on how many subtitles it has there alongside its
+//! flags and name.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+};
+
+use matroska_demuxer::{Frame, MatroskaFile, TrackEntry};
+
+use super::{
+    StContext,
+    forced::{CueCounter, CueCounts},
+    language::{DetectedLanguage, LanguageSampler, declared_language},
+    ocr::PartessCache,
+};
+use crate::utils::ExtractDetailsError;
+
+/// Which tracks `extract_details` decodes subtitles from
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubtitleTracks {
+    /// The best track from `rank_subtitle_tracks`, falling back to closed
+    /// captions if there are no subtitle tracks
+    Auto,
+    /// A single subtitle track, or a video track with closed captions
+    Track(u64),
+    /// Several subtitle or video tracks, decoded in the same pass
+    Tracks(Vec<u64>),
+    /// Every enabled subtitle track we can process
+    All,
+}
+
+/// How many sections of the file are sampled
+const SAMPLE_POINTS: u64 = 12;
+/// How long each sampled section is, in nanoseconds
+const SAMPLE_WINDOW_NS: u64 = 20_000_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackRanking {
+    pub track_number: u64,
+    pub score: f64,
+    /// Human-readable explanations for each adjustment to the score
+    pub reasons: Vec<String>,
+    /// Subtitles found in the sampled sections. Zero if sampling was skipped.
+    pub sample: CueCounts,
+    /// Only detected for untagged tracks
+    pub detected_language: Option<DetectedLanguage>,
+}
+
+/// What the scoring looks at for each track
+#[derive(Debug, Clone, Default)]
+struct CandidateTrack {
+    track_number: u64,
+    name: String,
+    default: bool,
+    commentary: bool,
+    hearing_impaired: bool,
+    forced: bool,
+    /// Whether the track has no language tag
+    untagged: bool,
+    /// Only detected for untagged tracks
+    detected_language: Option<DetectedLanguage>,
+}
+impl CandidateTrack {
+    fn new(track: &TrackEntry) -> Self {
+        return Self {
+            track_number: track.track_number().get(),
+            name: track.name().unwrap_or_default().to_lowercase(),
+            default: track.flag_default(),
+            commentary: track.flag_commentary(),
+            hearing_impaired: track.flag_hearing_impaired(),
+            forced: is_forced_track(track),
+            untagged: declared_language(track).is_none(),
+            detected_language: None,
+        };
+    }
+
+    fn name_has_word(&self, word: &str) -> bool {
+        return self
+            .name
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|part| part == word);
+    }
+}
+
+/// Whether a track is marked as only containing forced subtitles, either by
+/// its flags or by its name
+fn is_forced_track(track: &TrackEntry) -> bool {
+    return track.flag_forced()
+        || track
+            .name()
+            .is_some_and(|name| name.to_lowercase().contains("forced"));
+}
+
+/// Ranks the enabled subtitle tracks we can process, best first. The file is
+/// seeked back to the start afterwards.
+///
+/// Forced-only tracks are ranked last, since they skip most of the dialogue,
+/// unless `forced_only` is set, in which case they're ranked first instead.
+/// Untagged tracks are only considered if they're detected as English.
+pub fn rank_subtitle_tracks<T: Read + Seek>(
+    mkv_file: &mut MatroskaFile<T>,
+    partess_cache: &PartessCache,
+    forced_only: bool,
+) -> Result<Vec<TrackRanking>, ExtractDetailsError> {
+    let tracks: Vec<_> = mkv_file
+        .tracks()
+        .iter()
+        .filter(StContext::supported_codecs)
+        .filter(|track| track.flag_enabled())
+        .filter(|track| StContext::supported_tracks(track) || declared_language(track).is_none())
+        .collect();
+    let mut candidates: Vec<_> = tracks
+        .iter()
+        .map(|track| CandidateTrack::new(track))
+        .collect();
+    let mut counters: HashMap<u64, CueCounter> = tracks
+        .iter()
+        .map(|track| (track.track_number().get(), CueCounter::new(track)))
+        .collect();
+    let mut samplers = HashMap::new();
+    for track in tracks
+        .iter()
+        .filter(|track| declared_language(track).is_none())
+    {
+        samplers.insert(
+            track.track_number().get(),
+            LanguageSampler::new(track, partess_cache)?,
+        );
+    }
+
+    // There's nothing to compare against with a single track, unless we
+    // need to find out what language it's in
+    let sampled = candidates.len() > 1 || !samplers.is_empty();
+    if sampled {
+        sample_tracks(mkv_file, &mut counters, &mut samplers)?;
+    }
+    for (track_number, sampler) in samplers {
+        let detected = sampler.finish()?;
+        let candidate = candidates
+            .iter_mut()
+            .find(|candidate| candidate.track_number == track_number)
+            .unwrap();
+        candidate.detected_language = detected;
+    }
+    candidates.retain(|candidate| {
+        return !candidate.untagged
+            || candidate
+                .detected_language
+                .is_some_and(|detected| detected.language == "eng");
+    });
+
+    let samples: Vec<_> = candidates
+        .iter()
+        .map(|candidate| counters[&candidate.track_number].counts())
+        .collect();
+    return Ok(score_tracks(&candidates, &samples, sampled, forced_only));
+}
+
+/// Counts subtitles in each tracked track, over sections spread evenly across
+/// the file, and feeds them to the language samplers
+fn sample_tracks<T: Read + Seek>(
+    mkv_file: &mut MatroskaFile<T>,
+    counters: &mut HashMap<u64, CueCounter>,
+    samplers: &mut HashMap<u64, LanguageSampler>,
+) -> Result<(), ExtractDetailsError> {
+    let info = mkv_file.info();
+    // Frame timestamps are in ticks of the timestamp scale
+    let duration = info.duration().unwrap_or_default() as u64;
+    let window = (SAMPLE_WINDOW_NS / info.timestamp_scale().get()).max(1);
+
+    let mut frame = Frame::default();
+    for point in 0..SAMPLE_POINTS {
+        let start = duration * (2 * point + 1) / (2 * SAMPLE_POINTS);
+        mkv_file.seek(start)?;
+        while mkv_file.next_frame(&mut frame)? {
+            if frame.timestamp >= start + window {
+                break;
+            }
+            if let Some(counter) = counters.get_mut(&frame.track) {
+                counter.push_frame(&frame.data);
+            }
+            if let Some(sampler) = samplers.get_mut(&frame.track) {
+                sampler.push_frame(&frame)?;
+            }
+        }
+    }
+    mkv_file.seek(0)?;
+    return Ok(());
+}
+
+fn score_tracks(
+    candidates: &[CandidateTrack],
+    samples: &[CueCounts],
+    sampled: bool,
+    forced_only: bool,
+) -> Vec<TrackRanking> {
+    let max_cues = samples.iter().map(|sample| sample.cues).max().unwrap_or(0);
+    let mut rankings: Vec<_> = candidates
+        .iter()
+        .zip(samples)
+        .map(|(candidate, sample)| {
+            let mut score = 0.0;
+            let mut reasons = Vec::new();
+
+            if !sampled {
+                reasons.push(String::from("Only candidate track"));
+            } else if sample.cues == 0 {
+                score -= 30.0;
+                reasons.push(String::from("No subtitles in the sampled sections"));
+            } else {
+                let share = sample.cues as f64 / max_cues as f64;
+                score += 50.0 * share;
+                reasons.push(format!(
+                    "{} subtitles in the sampled sections ({:.0}% of the busiest track)",
+                    sample.cues,
+                    share * 100.0
+                ));
+            }
+
+            if candidate.commentary || candidate.name.contains("commentary") {
+                score -= 100.0;
+                reasons.push(String::from("Commentary track"));
+            }
+            if candidate.hearing_impaired
+                || candidate.name.contains("hearing impaired")
+                || candidate.name_has_word("sdh")
+                || candidate.name_has_word("cc")
+            {
+                score -= 20.0;
+                reasons.push(String::from(
+                    "SDH track, which describes sounds as well as dialogue",
+                ));
+            }
+            // Tracks that only had forced subtitles where we looked are most
+            // likely unflagged forced-only tracks
+            let forced = candidate.forced || (sample.cues > 0 && sample.forced == sample.cues);
+            if forced && forced_only {
+                score += 80.0;
+                reasons.push(String::from("Forced track, as requested"));
+            } else if forced {
+                score -= 80.0;
+                reasons.push(String::from(
+                    "Forced track, which only covers foreign language dialogue",
+                ));
+            }
+            if candidate.default {
+                score += 10.0;
+                reasons.push(String::from("Default track"));
+            }
+            if let Some(detected) = candidate.detected_language {
+                reasons.push(format!(
+                    "Untagged, but detected as English ({:.0}% confidence)",
+                    detected.confidence * 100.0
+                ));
+            }
+
+            return TrackRanking {
+                track_number: candidate.track_number,
+                score,
+                reasons,
+                sample: *sample,
+                detected_language: candidate.detected_language,
+            };
+        })
+        .collect();
+    // Stable, so ties go to the earlier track
+    rankings.sort_by(|a, b| b.score.total_cmp(&a.score));
+    return rankings;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rank_main_track_first() {
+        let candidates = vec![
+            CandidateTrack {
+                track_number: 3,
+                name: String::from("forced"),
+                default: true,
+                ..Default::default()
+            },
+            CandidateTrack {
+                track_number: 4,
+                name: String::from("english (sdh)"),
+                ..Default::default()
+            },
+            CandidateTrack {
+                track_number: 5,
+                ..Default::default()
+            },
+            CandidateTrack {
+                track_number: 6,
+                name: String::from("director's commentary"),
+                ..Default::default()
+            },
+        ];
+        let samples = vec![
+            CueCounts {
+                cues: 4,
+                forced: 4,
+                bytes: 0,
+            },
+            CueCounts {
+                cues: 120,
+                forced: 0,
+                bytes: 0,
+            },
+            CueCounts {
+                cues: 100,
+                forced: 0,
+                bytes: 0,
+            },
+            CueCounts {
+                cues: 110,
+                forced: 0,
+                bytes: 0,
+            },
+        ];
+        let order = |rankings: Vec<TrackRanking>| {
+            return rankings
+                .into_iter()
+                .map(|ranking| ranking.track_number)
+                .collect::<Vec<_>>();
+        };
+
+        assert_eq!(
+            order(score_tracks(&candidates, &samples, true, false)),
+            vec![5, 4, 6, 3]
+        );
+        assert_eq!(
+            order(score_tracks(&candidates, &samples, true, true)),
+            vec![3, 5, 4, 6]
+        );
+    }
+}
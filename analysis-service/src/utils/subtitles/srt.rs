@@ -1,5 +1,3 @@
-#![allow(dead_code)]
-
 use super::Subtitle;
 
 use thiserror::Error;
@@ -14,7 +12,9 @@ pub enum SrtParseError {
     UnexpectedEof,
 }
 
-/// Formats an iterator of subtitles as SRT text
+/// Formats an iterator of subtitles as SRT text. Subtitles positioned away
+/// from the bottom of the screen get an `{\anN}` position hint. `duration`
+/// is in µs, and ends a last subtitle that has no duration of its own.
 pub fn format_subtitles_srt(
     subtitles: impl IntoIterator<Item = Subtitle>,
     duration: u64,
@@ -39,11 +39,28 @@ pub fn format_subtitles_srt(
             format_srt_timestamp(start_time),
             format_srt_timestamp(end_time)
         ));
+        // Only signs and such need a position hint. Everything else goes at the bottom.
+        if let Some(region) = subtitle.region
+            && region.alignment() != 2
+        {
+            formatted.push_str(&format!("{{\\an{}}}", region.alignment()));
+        }
         formatted.push_str(subtitle.data.trim_end_matches("\n"));
     }
     return formatted + "\n";
 }
 
+/// Finds where the last subtitle ends, for tracks that don't carry a
+/// duration of their own. A final subtitle without a duration ends where
+/// it starts.
+pub fn last_end_timestamp(subtitles: &[Subtitle]) -> u64 {
+    return subtitles
+        .iter()
+        .map(|subtitle| subtitle.timestamp + subtitle.duration.unwrap_or(0))
+        .max()
+        .unwrap_or(0);
+}
+
 fn format_srt_timestamp(timestamp: u64) -> String {
     let timestamp_ms = timestamp / 1000;
     let ms = timestamp_ms % 1000;
@@ -99,6 +116,7 @@ fn parse_srt_timerange(timerange: &str) -> Result<(u64, u64), SrtParseError> {
     ));
 }
 
+/// Parses SRT lines into subtitles, with timestamps in microseconds
 pub fn parse_srt_file<T: Iterator<Item = S>, S: AsRef<str>>(
     mut srt_lines: T,
 ) -> Result<Vec<Subtitle>, SrtParseError> {
@@ -113,6 +131,7 @@ pub fn parse_srt_file<T: Iterator<Item = S>, S: AsRef<str>>(
 
         let sequence_number: usize = line
             .trim()
+            .trim_start_matches('\u{feff}')
             .parse()
             .map_err(|_| SrtParseError::InvalidSequence)?;
         last_seq += 1;
@@ -132,9 +151,12 @@ pub fn parse_srt_file<T: Iterator<Item = S>, S: AsRef<str>>(
                 }
             });
         subtitles.push(Subtitle {
-            timestamp: tr_start,
-            duration: Some(tr_end - tr_start),
+            timestamp: tr_start * 1000,
+            duration: Some(tr_end.saturating_sub(tr_start) * 1000),
             data: subtitle_str,
+            confidence: None,
+            region: None,
+            forced: false,
         });
     }
 
@@ -144,6 +166,7 @@ pub fn parse_srt_file<T: Iterator<Item = S>, S: AsRef<str>>(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::utils::subtitles::ScreenRegion;
 
     #[test]
     fn parse_srt_timestamp_1() {
@@ -197,26 +220,75 @@ Text 4"#;
             subtitles,
             vec![
                 Subtitle {
-                    timestamp: 6_373,
-                    duration: Some(5_439),
+                    timestamp: 6_373_000,
+                    duration: Some(5_439_000),
                     data: String::from("Text 1"),
+                    confidence: None,
+                    region: None,
+                    forced: false,
                 },
                 Subtitle {
-                    timestamp: 12_079,
-                    duration: Some(2_025),
+                    timestamp: 12_079_000,
+                    duration: Some(2_025_000),
                     data: String::from("Text 2"),
+                    confidence: None,
+                    region: None,
+                    forced: false,
                 },
                 Subtitle {
-                    timestamp: 14_181,
-                    duration: Some(2_526),
+                    timestamp: 14_181_000,
+                    duration: Some(2_526_000),
                     data: String::from("Text 3"),
+                    confidence: None,
+                    region: None,
+                    forced: false,
                 },
                 Subtitle {
-                    timestamp: 16_783,
-                    duration: Some(1_593),
+                    timestamp: 16_783_000,
+                    duration: Some(1_593_000),
                     data: String::from("Text 4"),
+                    confidence: None,
+                    region: None,
+                    forced: false,
                 },
             ]
         );
     }
+
+    #[test]
+    fn format_srt_positions() {
+        let region = |y| {
+            Some(ScreenRegion {
+                x: 560,
+                y,
+                width: 800,
+                height: 100,
+                screen_width: 1920,
+                screen_height: 1080,
+            })
+        };
+        let subtitles = vec![
+            Subtitle {
+                timestamp: 1_000_000,
+                duration: Some(1_000_000),
+                data: String::from("<i>Dialogue</i>"),
+                confidence: None,
+                region: region(900),
+                forced: false,
+            },
+            Subtitle {
+                timestamp: 3_000_000,
+                duration: Some(1_000_000),
+                data: String::from("A sign"),
+                confidence: None,
+                region: region(60),
+                forced: false,
+            },
+        ];
+        assert_eq!(
+            format_subtitles_srt(subtitles, 5_000_000),
+            "1\n00:00:01,000 --> 00:00:02,000\n<i>Dialogue</i>\n\n\
+            2\n00:00:03,000 --> 00:00:04,000\n{\\an8}A sign\n"
+        );
+    }
 }
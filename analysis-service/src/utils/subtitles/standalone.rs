@@ -0,0 +1,74 @@
+//! OCR for subtitle files that were extracted by other tools, rather than
+//! muxed into an MKV.
+
+use std::io::Read;
+
+use matroska_demuxer::Frame;
+
+use super::{
+    ExtractDetailsError, Subtitle, SubtitleOptions,
+    mpeg_ps::{SUBPICTURE_STREAM_BASE, demux_spu_packets},
+    ocr::PartessCache,
+    ocr_cache::OcrCacheStats,
+    pgs::{processor::PgsProcessor, sup::SupReader},
+    vobsub::{VobsubProcessor, parse_idx_streams},
+};
+
+/// OCRs a raw PGS (.sup) file
+pub fn ocr_sup_file<T: Read>(
+    sup_file: T,
+    partess_cache: &PartessCache,
+    options: &SubtitleOptions,
+) -> Result<(Vec<Subtitle>, OcrCacheStats), ExtractDetailsError> {
+    let mut reader = SupReader::new(sup_file);
+    let mut processor = PgsProcessor::new(
+        partess_cache,
+        "eng",
+        options.review_threshold,
+        options.preprocessing,
+    )?;
+    let mut frame = Frame::default();
+    while reader.next_frame(&mut frame)? {
+        processor.push_frame(&frame)?;
+    }
+    let cache_counter = processor.cache_counter();
+    let subtitles = options.apply(processor.collect()?);
+    return Ok((subtitles, cache_counter.stats()));
+}
+
+/// OCRs a VobSub .idx/.sub pair.
+///
+/// The first English stream declared in the idx file is used. If there
+/// isn't one, the first declared stream is used instead.
+pub fn ocr_vobsub_files<T: Read>(
+    idx_data: &[u8],
+    mut sub_file: T,
+    partess_cache: &PartessCache,
+    options: &SubtitleOptions,
+) -> Result<(Vec<Subtitle>, OcrCacheStats), ExtractDetailsError> {
+    let streams = parse_idx_streams(idx_data);
+    let stream_index = streams
+        .iter()
+        .find(|stream| matches!(stream.language.as_str(), "en" | "eng"))
+        .or(streams.first())
+        .map(|stream| stream.index)
+        .unwrap_or(0);
+
+    // .sub files are small enough to demux in memory
+    let mut sub_data = Vec::new();
+    sub_file.read_to_end(&mut sub_data)?;
+
+    let processor = VobsubProcessor::new(
+        partess_cache,
+        "eng",
+        idx_data,
+        options.review_threshold,
+        options.preprocessing,
+    )?;
+    for packet in demux_spu_packets(&sub_data, SUBPICTURE_STREAM_BASE + stream_index)? {
+        processor.push_frame(packet.timestamp, None, packet.data);
+    }
+    let cache_counter = processor.cache_counter();
+    let subtitles = options.apply(processor.collect()?);
+    return Ok((subtitles, cache_counter.stats()));
+}
@@ -1,6 +1,8 @@
 use image::GrayAlphaImage;
 
-pub fn crop_gray_image(image: &GrayAlphaImage) -> GrayAlphaImage {
+/// Finds the bounding box (x1, y1, x2, y2) of the visible pixels in an
+/// image, inclusive.
+pub fn gray_image_bounds(image: &GrayAlphaImage) -> Option<(u32, u32, u32, u32)> {
     let mut bounds: Option<(u32, u32, u32, u32)> = None;
     for y in 0..image.height() {
         for x in 0..image.width() {
@@ -26,18 +28,19 @@ pub fn crop_gray_image(image: &GrayAlphaImage) -> GrayAlphaImage {
             }
         }
     }
-    match bounds {
-        None => {
-            return GrayAlphaImage::new(0, 0);
-        }
-        Some((x1, y1, x2, y2)) => {
-            let mut new_image = GrayAlphaImage::new(x2 + 1 - x1, y2 + 1 - y1);
-            for (new_y, y) in (y1..=y2).enumerate() {
-                for (new_x, x) in (x1..=x2).enumerate() {
-                    new_image.put_pixel(new_x as _, new_y as _, image.get_pixel(x, y).clone());
-                }
-            }
-            return new_image;
+    return bounds;
+}
+
+/// Crops an image to the bounds found by `gray_image_bounds`
+pub fn crop_gray_image(
+    image: &GrayAlphaImage,
+    (x1, y1, x2, y2): (u32, u32, u32, u32),
+) -> GrayAlphaImage {
+    let mut new_image = GrayAlphaImage::new(x2 + 1 - x1, y2 + 1 - y1);
+    for (new_y, y) in (y1..=y2).enumerate() {
+        for (new_x, x) in (x1..=x2).enumerate() {
+            new_image.put_pixel(new_x as _, new_y as _, image.get_pixel(x, y).clone());
         }
     }
+    return new_image;
 }
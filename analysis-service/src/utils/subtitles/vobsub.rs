@@ -2,7 +2,9 @@
 //!
 //! https://sam.zoy.org/writings/dvd/subtitles/
 
-use image::{GrayImage, Rgb, Rgba, RgbaImage};
+use std::sync::Arc;
+
+use image::{Rgb, Rgba, RgbaImage};
 
 use leptess::Variable;
 use thiserror::Error;
@@ -10,9 +12,10 @@ use thiserror::Error;
 use crate::rayon_helpers::BackpressuredRayon;
 
 use super::{
-    ExtractDetailsError, Subtitle,
-    ocr::{Partess, PartessCache},
-    process_rgba_image,
+    ExtractDetailsError, ScreenRegion, Subtitle,
+    ocr::{OcrOutput, Partess, PartessCache},
+    ocr_cache::OcrCacheCounter,
+    preprocess::{PreprocessOptions, graya_from_rgba, prepare_image},
 };
 
 #[derive(Error, Debug, Clone)]
@@ -25,20 +28,34 @@ pub enum VobsubError {
     InvalidControl,
     #[error("Invalid VobSub frame data.")]
     InvalidFrame,
+    #[error("Invalid PES packet in VobSub stream.")]
+    InvalidPes,
+}
+
+/// SPU control sequence delays are in units of 1024 ticks of a 90kHz clock
+fn spu_delay_to_us(delay: u16) -> u64 {
+    return delay as u64 * 1024 * 100 / 9;
+}
+
+struct OcrdSubpicture {
+    /// (timestamp, duration) of each time the subpicture is shown
+    intervals: Vec<(u64, Option<u64>)>,
+    forced: bool,
+    output: OcrOutput,
+    region: ScreenRegion,
 }
 
 pub struct VobsubProcessor {
+    cache_counter: Arc<OcrCacheCounter>,
     rayon_pool: BackpressuredRayon<
         Box<
-            dyn Fn(
-                    (u64, Option<u64>, Vec<u8>),
-                ) -> Result<(u64, Option<u64>, String), ExtractDetailsError>
+            dyn Fn((u64, Option<u64>, Vec<u8>)) -> Result<OcrdSubpicture, ExtractDetailsError>
                 + Send
                 + Sync
                 + 'static,
         >,
         (u64, Option<u64>, Vec<u8>),
-        Result<(u64, Option<u64>, String), ExtractDetailsError>,
+        Result<OcrdSubpicture, ExtractDetailsError>,
     >,
 }
 impl VobsubProcessor {
@@ -46,6 +63,8 @@ impl VobsubProcessor {
         partess_cache: &PartessCache,
         language: &str,
         codec_data: &[u8],
+        review_threshold: Option<u8>,
+        preprocessing: Option<PreprocessOptions>,
     ) -> Result<Self, ExtractDetailsError> {
         let mut cache = partess_cache.cache.lock().unwrap();
         let partess = match cache.get(language) {
@@ -59,7 +78,11 @@ impl VobsubProcessor {
                         (Variable::TesseditPagesegMode, String::from("6")),
                         (Variable::TesseditDoInvert, String::from("0")),
                         (Variable::TesseditCharBlacklist, String::from("|\\/`_~{}")),
+                        // Marks italic words in the hOCR output for the legacy engine;
+                        // the LSTM engine relies on slant detection instead
+                        (Variable::HocrFontInfo, String::from("1")),
                     ],
+                    partess_cache.ocr_cache.clone(),
                 );
                 cache.insert(String::from(language), partess.clone());
                 partess
@@ -67,15 +90,62 @@ impl VobsubProcessor {
         };
         drop(cache);
         let idx_data = parse_idx(codec_data)?;
+        let cache_counter = Arc::new(OcrCacheCounter::default());
+        let closure_counter = Arc::clone(&cache_counter);
         return Ok(Self {
+            cache_counter,
             rayon_pool: BackpressuredRayon::new(
                 5,
                 Box::new(move |(timestamp, duration, data)| {
-                    let frame = parse_frame(&idx_data, &data)?;
-                    let image: GrayImage = process_rgba_image(frame);
-                    let mut partess = partess.get()?;
-                    let sub = partess.ocr_image(image)?;
-                    return Ok((timestamp, duration, sub));
+                    let Subpicture {
+                        image: frame,
+                        coordinates,
+                        force,
+                        mut intervals,
+                    } = parse_frame(&idx_data, &data)?;
+                    if intervals.is_empty() {
+                        // Without a start date, the subpicture is shown right away
+                        intervals.push(DisplayInterval {
+                            start: 0,
+                            stop: None,
+                        });
+                    }
+                    let intervals = intervals
+                        .into_iter()
+                        .map(|interval| {
+                            let start = timestamp + spu_delay_to_us(interval.start);
+                            let end = match interval.stop {
+                                Some(stop) => Some(timestamp + spu_delay_to_us(stop)),
+                                // Fall back to the container's timing
+                                None => duration.map(|duration| timestamp + duration),
+                            };
+                            let duration = end.filter(|end| *end > start).map(|end| end - start);
+                            return (start, duration);
+                        })
+                        .collect();
+                    let (screen_width, screen_height) = idx_data.size.unwrap_or(
+                        // Guess between NTSC and PAL
+                        match coordinates.y2 < 480 {
+                            true => (720, 480),
+                            false => (720, 576),
+                        },
+                    );
+                    let region = ScreenRegion {
+                        x: coordinates.x1 as u32,
+                        y: coordinates.y1 as u32,
+                        width: frame.width(),
+                        height: frame.height(),
+                        screen_width,
+                        screen_height,
+                    };
+                    let images = prepare_image(graya_from_rgba(&frame), preprocessing.as_ref());
+                    let output = partess.ocr_regions(images, review_threshold, &closure_counter)?;
+                    return Ok(OcrdSubpicture {
+                        intervals,
+                        forced: force,
+                        output,
+                        region,
+                    });
                 }),
             ),
         });
@@ -83,38 +153,93 @@ impl VobsubProcessor {
     pub fn push_frame(&self, timestamp: u64, duration: Option<u64>, data: Vec<u8>) {
         self.rayon_pool.push_data((timestamp, duration, data))
     }
+    /// Tallies OCR cache lookups. The counts are final once `collect` returns.
+    pub fn cache_counter(&self) -> Arc<OcrCacheCounter> {
+        return Arc::clone(&self.cache_counter);
+    }
     pub fn collect(self) -> Result<Vec<Subtitle>, ExtractDetailsError> {
-        let mut subs = self.rayon_pool.try_collect()?;
-        subs.sort_by_key(|(timestamp, _duration, _sub)| *timestamp);
-        return Ok(subs
+        let mut subs: Vec<_> = self
+            .rayon_pool
+            .try_collect()?
             .into_iter()
-            .map(|(timestamp, duration, data)| Subtitle {
-                timestamp,
-                duration,
-                data,
+            .flat_map(|subpicture| {
+                return subpicture
+                    .intervals
+                    .iter()
+                    .map(|(timestamp, duration)| Subtitle {
+                        timestamp: *timestamp,
+                        duration: *duration,
+                        data: subpicture.output.text.clone(),
+                        confidence: Some(subpicture.output.confidence.clone()),
+                        region: Some(subpicture.region),
+                        forced: subpicture.forced,
+                    })
+                    .collect::<Vec<_>>();
             })
-            .collect());
+            .collect();
+        subs.sort_by_key(|subtitle| subtitle.timestamp);
+        return Ok(subs);
     }
 }
 
 pub struct IdxData {
     pub palette: [Rgb<u8>; 16],
+    /// Screen size (width, height) that coordinates are relative to
+    pub size: Option<(u32, u32)>,
 }
 pub fn parse_idx(data: &[u8]) -> Result<IdxData, VobsubError> {
-    for line in String::from_utf8_lossy(data).split("\n") {
-        if line.trim_start().starts_with("#") {
+    let mut size = None;
+    // `lines` also drops the `\r` of CRLF endings
+    for line in String::from_utf8_lossy(data).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
             continue;
         }
-        let (key, value) = line.split_once(": ").ok_or(VobsubError::InvalidIdx)?;
+        // Standalone files have settings we don't read, not all in this form
+        let Some((key, value)) = line.split_once(": ") else {
+            continue;
+        };
+        if key == "size" {
+            size = value.trim().split_once('x').and_then(|(width, height)| {
+                return Some((width.parse().ok()?, height.parse().ok()?));
+            });
+        }
         if key == "palette" {
             return Ok(IdxData {
                 palette: parse_palette(value).ok_or(VobsubError::InvalidIdx)?,
+                size,
             });
         }
     }
     return Err(VobsubError::InvalidIdx);
 }
 
+/// A subtitle stream declared in a standalone .idx file
+pub struct IdxStream {
+    pub language: String,
+    pub index: u8,
+}
+
+/// Lists the streams declared by `id: <lang>, index: <n>` lines in an .idx file
+pub fn parse_idx_streams(data: &[u8]) -> Vec<IdxStream> {
+    let mut streams = Vec::new();
+    for line in String::from_utf8_lossy(data).lines() {
+        let Some(declaration) = line.trim().strip_prefix("id: ") else {
+            continue;
+        };
+        let Some((language, index)) = declaration.split_once(", index: ") else {
+            continue;
+        };
+        if let Ok(index) = index.trim().parse() {
+            streams.push(IdxStream {
+                language: String::from(language.trim()),
+                index,
+            });
+        }
+    }
+    return streams;
+}
+
 pub fn parse_palette(palette: &str) -> Option<[Rgb<u8>; 16]> {
     let segments = palette.split(",");
     let mut palette = [Rgb::<u8>([0, 0, 0]); 16];
@@ -124,7 +249,24 @@ pub fn parse_palette(palette: &str) -> Option<[Rgb<u8>; 16]> {
     return Some(palette);
 }
 
-pub fn parse_frame(idx: &IdxData, file_data: &[u8]) -> Result<RgbaImage, VobsubError> {
+/// Reads a subpicture's force flag without decoding it
+pub fn peek_forced(file_data: &[u8]) -> Option<bool> {
+    if file_data.len() < 4 {
+        return None;
+    }
+    let control_offset = u16::from_be_bytes([file_data[2], file_data[3]]);
+    return Some(parse_control(file_data, control_offset as usize)?.force);
+}
+
+/// A decoded subpicture, with its position and timing
+pub struct Subpicture {
+    pub image: RgbaImage,
+    pub coordinates: Coordinates,
+    pub force: bool,
+    pub intervals: Vec<DisplayInterval>,
+}
+
+pub fn parse_frame(idx: &IdxData, file_data: &[u8]) -> Result<Subpicture, VobsubError> {
     if file_data.len() < 4 {
         return Err(VobsubError::InvalidFrameHeader);
     }
@@ -133,7 +275,19 @@ pub fn parse_frame(idx: &IdxData, file_data: &[u8]) -> Result<RgbaImage, VobsubE
 
     let control =
         parse_control(&file_data, control_offset as usize).ok_or(VobsubError::InvalidControl)?;
-    return parse_data(&idx.palette, control, &file_data).ok_or(VobsubError::InvalidFrame);
+    let coordinates = control
+        .coordinates
+        .clone()
+        .ok_or(VobsubError::InvalidFrame)?;
+    let force = control.force;
+    let intervals = control.intervals.clone();
+    let image = parse_data(&idx.palette, control, &file_data).ok_or(VobsubError::InvalidFrame)?;
+    return Ok(Subpicture {
+        image,
+        coordinates,
+        force,
+        intervals,
+    });
 }
 
 #[derive(Debug, Clone)]
@@ -144,11 +298,19 @@ pub struct Coordinates {
     pub y2: u16,
 }
 
+/// When a subpicture is shown, as delays from its presentation timestamp
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DisplayInterval {
+    pub start: u16,
+    /// Absent if the subpicture is never hidden by its own control sequences
+    pub stop: Option<u16>,
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct ControlData {
     pub force: bool,
-    pub start_time: Option<u16>,
-    pub stop_time: Option<u16>,
+    /// Each display/hide pair, in order
+    pub intervals: Vec<DisplayInterval>,
     pub color_palette: Option<[u8; 4]>,
     pub alpha_palette: Option<[u8; 4]>,
     pub coordinates: Option<Coordinates>,
@@ -171,25 +333,37 @@ fn parse_control(data: &[u8], mut cursor: usize) -> Option<ControlData> {
             }
             let command = data[cursor];
             match command {
-                0x00 => {
-                    // Force displaying
-                    control.force = true;
-                    cursor += 1;
-                }
-                0x01 => {
-                    // Start date
-                    control.start_time = Some(offset_time);
+                0x00 | 0x01 => {
+                    // Start date, forcing display for 0x00
+                    if command == 0x00 {
+                        control.force = true;
+                    }
+                    // Starting while already shown changes nothing
+                    if control
+                        .intervals
+                        .last()
+                        .is_none_or(|interval| interval.stop.is_some())
+                    {
+                        control.intervals.push(DisplayInterval {
+                            start: offset_time,
+                            stop: None,
+                        });
+                    }
                     cursor += 1;
                 }
                 0x02 => {
                     // Stop date
-                    control.stop_time = Some(offset_time);
+                    if let Some(interval) = control.intervals.last_mut()
+                        && interval.stop.is_none()
+                    {
+                        interval.stop = Some(offset_time);
+                    }
                     cursor += 1;
                 }
                 0x03 => {
                     // Palette
                     let mut colors = [0u8; 4];
-                    let mut nibbles = NibbleStream::new(&data[cursor + 1..cursor + 3]);
+                    let mut nibbles = NibbleStream::new(data.get(cursor + 1..cursor + 3)?);
                     for i in 0..4 {
                         colors[i] = nibbles.take_nibble()?;
                     }
@@ -199,7 +373,7 @@ fn parse_control(data: &[u8], mut cursor: usize) -> Option<ControlData> {
                 0x04 => {
                     // Alpha channel
                     let mut alphas = [0u8; 4];
-                    let mut nibbles = NibbleStream::new(&data[cursor + 1..cursor + 3]);
+                    let mut nibbles = NibbleStream::new(data.get(cursor + 1..cursor + 3)?);
                     for i in 0..4 {
                         alphas[i] = nibbles.take_nibble()?;
                     }
@@ -230,17 +404,30 @@ fn parse_control(data: &[u8], mut cursor: usize) -> Option<ControlData> {
                     control.rle_offsets = Some((evens, odds));
                     cursor += 5;
                 }
+                0x07 => {
+                    // Color and contrast changes within the picture, skipped.
+                    // The size counts its own two bytes.
+                    let size = data.get(cursor + 1..cursor + 3)?;
+                    let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+                    if size < 2 {
+                        return None;
+                    }
+                    cursor += 1 + size;
+                }
                 0xFF => {
                     // End of command sequence
                     break;
                 }
-                _ => {}
+                // Unknown commands, whose lengths we can't know
+                _ => return None,
             }
         }
-        if next_control as usize == this_sequence {
-            break;
-        } else {
-            cursor = next_control as usize;
+        // The last sequence points at itself. Anything pointing backwards
+        // is corrupt, and would otherwise loop forever.
+        match (next_control as usize).cmp(&this_sequence) {
+            std::cmp::Ordering::Equal => break,
+            std::cmp::Ordering::Less => return None,
+            std::cmp::Ordering::Greater => cursor = next_control as usize,
         }
     }
     return Some(control);
@@ -289,8 +476,8 @@ fn parse_data(
     let color_palette = control.color_palette?;
     let alpha_palette = control.alpha_palette?;
     let coordinates = control.coordinates?;
-    let width = (coordinates.x2 - coordinates.x1 + 1) as u32;
-    let height = (coordinates.y2 - coordinates.y1 + 1) as u32;
+    let width = coordinates.x2.checked_sub(coordinates.x1)? as u32 + 1;
+    let height = coordinates.y2.checked_sub(coordinates.y1)? as u32 + 1;
     let mut image = image::ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width as _, height as _);
 
     let mut y = 0;
@@ -374,3 +561,128 @@ impl<'a> NibbleStream<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_control_intervals() {
+        #[rustfmt::skip]
+        let data = [
+            // Subpicture header
+            0, 28, 0, 4,
+            // Forced start at 0, stop at 100
+            0, 0, 0, 10, 0x00, 0xFF,
+            0, 100, 0, 16, 0x02, 0xFF,
+            // Shown again from 200 to 300
+            0, 200, 0, 22, 0x01, 0xFF,
+            1, 44, 0, 22, 0x02, 0xFF,
+        ];
+        let control = parse_control(&data, 4).unwrap();
+        assert!(control.force);
+        assert_eq!(
+            control.intervals,
+            vec![
+                DisplayInterval {
+                    start: 0,
+                    stop: Some(100),
+                },
+                DisplayInterval {
+                    start: 200,
+                    stop: Some(300),
+                },
+            ]
+        );
+        assert_eq!(spu_delay_to_us(100), 1_137_777);
+    }
+
+    #[test]
+    fn parse_corrupt_control() {
+        #[rustfmt::skip]
+        let data = [
+            0, 20, 0, 4,
+            0, 0, 0, 10, 0x00, 0xFF,
+            // Palette and alpha, then a hop back to the first sequence
+            0, 100, 0, 4, 0x03, 0x01, 0x23, 0x04, 0xFF, 0xFF, 0xFF,
+        ];
+        assert_eq!(peek_forced(&data), None);
+        // Every truncation ends in a command cut short
+        for len in 0..data.len() {
+            assert_eq!(peek_forced(&data[..len]), None);
+        }
+        let mut data = data.to_vec();
+        data[13] = 10;
+        assert_eq!(peek_forced(&data), Some(true));
+    }
+
+    #[test]
+    fn parse_color_contrast_change() {
+        #[rustfmt::skip]
+        let data = [
+            0, 22, 0, 4,
+            // Forced start, then a CHG_COLCON with 6 bytes of parameters
+            0, 0, 0, 4, 0x00, 0x07, 0, 8, 0, 0, 0, 0, 0, 0, 0x06, 0, 1, 0, 2, 0xFF,
+        ];
+        let control = parse_control(&data, 4).unwrap();
+        assert!(control.force);
+        assert_eq!(control.rle_offsets, Some((1, 2)));
+        // Sizes running past the end, or too small to cover themselves
+        assert!(parse_control(&data[..data.len() - 8], 4).is_none());
+        let mut data = data.to_vec();
+        data[11] = 1;
+        assert!(parse_control(&data, 4).is_none());
+    }
+
+    #[test]
+    fn parse_standalone_idx() {
+        // The header VobSub writes, trimmed, with the CRLF endings it uses
+        let idx = [
+            "# VobSub index file, v7 (do not modify this line!)",
+            "#",
+            "# To repair desyncronization, you can insert gaps this way:",
+            "#\tdelay: [sign]hh:mm:ss:ms",
+            "",
+            "",
+            "# Settings",
+            "",
+            "# Original frame size",
+            "size: 720x480",
+            "",
+            "# Origin, relative to the upper-left corner, can be overloaded by aligment",
+            "org: 0, 0",
+            "",
+            "# Smoothing for very blocky images (use OLD for no filtering)",
+            "smooth: OFF",
+            "",
+            "# In millisecs",
+            "fadein/out: 50, 50",
+            "",
+            "# The original palette of the DVD",
+            "palette: 000000, ffffff, 808080, 000000, 000000, 000000, 000000, 000000, \
+             000000, 000000, 000000, 000000, 000000, 000000, eaeaea, 101010",
+            "",
+            "# Custom colors (transp idxs and the four colors)",
+            "custom colors: OFF, tridx: 0000, colors: 000000, 000000, 000000, 000000",
+            "",
+            "# Language index in use",
+            "langidx: 0",
+            "",
+            "# English",
+            "id: en, index: 0",
+            "# Vob/Cell ID: 1, 1 (PTS: 0)",
+            "timestamp: 00:00:01:101, filepos: 000000000",
+            "   ",
+            "# French",
+            "id: fr, index: 1",
+        ]
+        .join("\r\n");
+        let data = parse_idx(idx.as_bytes()).unwrap();
+        assert_eq!(data.size, Some((720, 480)));
+        assert_eq!(data.palette[1], Rgb([0xff, 0xff, 0xff]));
+        assert_eq!(data.palette[14], Rgb([0xea, 0xea, 0xea]));
+        let streams = parse_idx_streams(idx.as_bytes());
+        assert_eq!(streams.len(), 2);
+        assert_eq!((streams[1].language.as_str(), streams[1].index), ("fr", 1));
+    }
+}
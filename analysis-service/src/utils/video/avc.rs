@@ -0,0 +1,280 @@
+//! Reads the AVCDecoderConfigurationRecord (ISO/IEC 14496-15) and the
+//! sequence parameter set inside it (ITU-T H.264 7.3.2.1).
+
+use super::{
+    CodecDetails, ColourDescription,
+    bit_reader::{BitReader, unescape_rbsp},
+};
+
+/// Profiles that carry chroma format and bit depth in their SPS
+const HIGH_PROFILES: [u8; 12] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134];
+
+pub fn parse_avc_config(data: &[u8]) -> Option<CodecDetails> {
+    if data.len() < 6 || data[0] != 1 {
+        return None;
+    }
+    let (profile_idc, constraints, level_idc) = (data[1], data[2], data[3]);
+    let mut details = CodecDetails {
+        profile: Some(profile_name(profile_idc, constraints)),
+        level: Some(level_name(profile_idc, constraints, level_idc)),
+        // Only High profiles can signal anything else
+        chroma_format: Some("4:2:0"),
+        bit_depth: Some(8),
+        ..Default::default()
+    };
+
+    let sps_count = data[5] & 0x1F;
+    if sps_count > 0 {
+        let length = u16::from_be_bytes([*data.get(6)?, *data.get(7)?]) as usize;
+        let sps = data.get(8..8 + length)?;
+        // A truncated SPS still leaves the fields read before it
+        let _ = parse_sps(&unescape_rbsp(sps.get(1..)?), &mut details);
+    }
+    return Some(details);
+}
+
+fn profile_name(profile_idc: u8, constraints: u8) -> String {
+    let constraint_set1 = constraints & 0x40 != 0;
+    return String::from(match profile_idc {
+        66 if constraint_set1 => "Constrained Baseline",
+        66 => "Baseline",
+        77 => "Main",
+        88 => "Extended",
+        100 => "High",
+        110 => "High 10",
+        122 => "High 4:2:2",
+        244 => "High 4:4:4 Predictive",
+        44 => "CAVLC 4:4:4 Intra",
+        118 => "Multiview High",
+        128 => "Stereo High",
+        _ => return format!("Profile {profile_idc}"),
+    });
+}
+
+fn level_name(profile_idc: u8, constraints: u8, level_idc: u8) -> String {
+    let constraint_set3 = constraints & 0x10 != 0;
+    if level_idc == 9 || (level_idc == 11 && constraint_set3 && matches!(profile_idc, 66 | 77)) {
+        return String::from("1b");
+    }
+    return match level_idc % 10 {
+        0 => format!("{}", level_idc / 10),
+        minor => format!("{}.{minor}", level_idc / 10),
+    };
+}
+
+/// Fills in what the SPS says about the picture. Returns `None` if it ends
+/// early.
+fn parse_sps(rbsp: &[u8], details: &mut CodecDetails) -> Option<()> {
+    let mut reader = BitReader::new(rbsp);
+    let profile_idc = reader.read_bits(8)? as u8;
+    reader.skip_bits(16)?; // constraint flags, level_idc
+    reader.read_ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    if HIGH_PROFILES.contains(&profile_idc) {
+        chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            reader.skip_bits(1)?; // separate_colour_plane_flag
+        }
+        details.chroma_format = chroma_format_name(chroma_format_idc);
+        let bit_depth_luma = reader.read_ue()? + 8;
+        reader.read_ue()?; // bit_depth_chroma_minus8
+        details.bit_depth = Some(bit_depth_luma as u8);
+        reader.skip_bits(1)?; // qpprime_y_zero_transform_bypass_flag
+        if reader.read_bit()? {
+            // seq_scaling_matrix_present_flag
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.read_bit()? {
+                    skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.read_ue()?; // log2_max_frame_num_minus4
+    match reader.read_ue()? {
+        0 => {
+            reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            reader.skip_bits(1)?; // delta_pic_order_always_zero_flag
+            reader.read_se()?; // offset_for_non_ref_pic
+            reader.read_se()?; // offset_for_top_to_bottom_field
+            for _ in 0..reader.read_ue()? {
+                reader.read_se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    reader.read_ue()?; // max_num_ref_frames
+    reader.skip_bits(1)?; // gaps_in_frame_num_value_allowed_flag
+    let width_in_mbs = reader.read_ue()? + 1;
+    let height_in_map_units = reader.read_ue()? + 1;
+    let frame_mbs_only = reader.read_bit()?;
+    if !frame_mbs_only {
+        reader.skip_bits(1)?; // mb_adaptive_frame_field_flag
+    }
+    reader.skip_bits(1)?; // direct_8x8_inference_flag
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        0 => (1, field_factor),
+        1 => (2, 2 * field_factor),
+        2 => (2, field_factor),
+        _ => (1, field_factor),
+    };
+    let mut width = width_in_mbs * 16;
+    let mut height = height_in_map_units * 16 * field_factor;
+    if reader.read_bit()? {
+        // frame_cropping_flag
+        let (left, right) = (reader.read_ue()?, reader.read_ue()?);
+        let (top, bottom) = (reader.read_ue()?, reader.read_ue()?);
+        width = width.saturating_sub((left + right) * crop_unit_x);
+        height = height.saturating_sub((top + bottom) * crop_unit_y);
+    }
+    details.width = Some(width);
+    details.height = Some(height);
+
+    if reader.read_bit()? {
+        parse_vui(&mut reader, details)?;
+    }
+    return Some(());
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let (mut last_scale, mut next_scale) = (8, 8);
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    return Some(());
+}
+
+/// Video usability information (H.264 E.1.1), for colour and timing
+fn parse_vui(reader: &mut BitReader, details: &mut CodecDetails) -> Option<()> {
+    if reader.read_bit()? {
+        // aspect_ratio_info_present_flag
+        if reader.read_bits(8)? == 255 {
+            reader.skip_bits(32)?; // sar_width, sar_height
+        }
+    }
+    if reader.read_bit()? {
+        reader.skip_bits(1)?; // overscan_appropriate_flag
+    }
+    if reader.read_bit()? {
+        // video_signal_type_present_flag
+        reader.skip_bits(3)?; // video_format
+        let full_range = reader.read_bit()?;
+        if reader.read_bit()? {
+            details.colour = Some(ColourDescription {
+                primaries: reader.read_bits(8)? as u8,
+                transfer_characteristics: reader.read_bits(8)? as u8,
+                matrix_coefficients: reader.read_bits(8)? as u8,
+                full_range,
+            });
+        }
+    }
+    if reader.read_bit()? {
+        // chroma_loc_info_present_flag
+        reader.read_ue()?;
+        reader.read_ue()?;
+    }
+    if reader.read_bit()? {
+        // timing_info_present_flag
+        let num_units_in_tick = reader.read_bits(32)?;
+        let time_scale = reader.read_bits(32)?;
+        if num_units_in_tick > 0 {
+            // Each frame is two ticks, one per field
+            details.frame_rate = Some(time_scale as f64 / (2.0 * num_units_in_tick as f64));
+        }
+    }
+    return Some(());
+}
+
+pub(super) fn chroma_format_name(chroma_format_idc: u32) -> Option<&'static str> {
+    return match chroma_format_idc {
+        0 => Some("4:0:0"),
+        1 => Some("4:2:0"),
+        2 => Some("4:2:2"),
+        3 => Some("4:4:4"),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writes fields MSB first, the way an encoder lays out an SPS
+    #[derive(Default)]
+    struct BitWriter {
+        bits: Vec<bool>,
+    }
+    impl BitWriter {
+        fn bits(&mut self, value: u32, count: u32) -> &mut Self {
+            for i in (0..count).rev() {
+                self.bits.push((value >> i) & 1 == 1);
+            }
+            return self;
+        }
+        fn ue(&mut self, value: u32) -> &mut Self {
+            let code = value + 1;
+            let length = 32 - code.leading_zeros();
+            return self.bits(0, length - 1).bits(code, length);
+        }
+        fn bytes(&self) -> Vec<u8> {
+            return self
+                .bits
+                .chunks(8)
+                .map(|chunk| {
+                    return chunk
+                        .iter()
+                        .enumerate()
+                        .fold(0, |byte, (i, bit)| byte | ((*bit as u8) << (7 - i)));
+                })
+                .collect();
+        }
+    }
+
+    #[test]
+    fn parse_high_10_1080p() {
+        let mut sps = BitWriter::default();
+        sps.bits(110, 8).bits(0, 8).bits(41, 8).ue(0);
+        sps.ue(1).ue(2).ue(2).bits(0, 1).bits(0, 1); // 4:2:0, 10-bit, no scaling
+        sps.ue(0).ue(2).ue(4).bits(0, 1); // frame_num, POC, refs
+        sps.ue(119).ue(67).bits(1, 1).bits(1, 1); // 120x68 macroblocks
+        sps.bits(1, 1).ue(0).ue(0).ue(0).ue(4); // crop 1088 to 1080
+        sps.bits(1, 1); // VUI
+        sps.bits(0, 1).bits(0, 1); // aspect ratio, overscan
+        sps.bits(1, 1)
+            .bits(5, 3)
+            .bits(0, 1)
+            .bits(1, 1)
+            .bits(1, 8)
+            .bits(1, 8)
+            .bits(1, 8);
+        sps.bits(0, 1); // chroma location
+        sps.bits(1, 1).bits(1001, 32).bits(48000, 32).bits(1, 1);
+        sps.bits(1, 1); // stop bit
+
+        let mut config = vec![1, 110, 0, 41, 0xFF, 0xE1];
+        let nal: Vec<u8> = [0x67].into_iter().chain(sps.bytes()).collect();
+        config.extend((nal.len() as u16).to_be_bytes());
+        config.extend(nal);
+
+        let details = parse_avc_config(&config).unwrap();
+        assert_eq!(details.profile.as_deref(), Some("High 10"));
+        assert_eq!(details.level.as_deref(), Some("4.1"));
+        assert_eq!(details.chroma_format, Some("4:2:0"));
+        assert_eq!(details.bit_depth, Some(10));
+        assert_eq!((details.width, details.height), (Some(1920), Some(1080)));
+        assert_eq!(details.colour.unwrap().transfer_characteristics, 1);
+        assert!((details.frame_rate.unwrap() - 23.976).abs() < 0.001);
+    }
+}
@@ -0,0 +1,158 @@
+/// Reads big-endian bit fields and Exp-Golomb codes, as used in H.264 and
+/// H.265 parameter sets, and the unary codes of FLAC. Reads past the end
+/// return `None`.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    position: usize,
+}
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        return Self { data, position: 0 };
+    }
+
+    pub fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        return Some(bit == 1);
+    }
+
+    /// Reads up to 32 bits
+    pub fn read_bits(&mut self, count: u32) -> Option<u32> {
+        if self.position + count as usize > self.data.len() * 8 {
+            return None;
+        }
+        // A byte at a time, since audio residuals go through here
+        let mut value: u64 = 0;
+        let mut remaining = count;
+        while remaining > 0 {
+            let byte = self.data[self.position / 8] as u64;
+            let offset = (self.position % 8) as u32;
+            let take = (8 - offset).min(remaining);
+            let bits = (byte >> (8 - offset - take)) & ((1 << take) - 1);
+            value = (value << take) | bits;
+            self.position += take as usize;
+            remaining -= take;
+        }
+        return Some(value as u32);
+    }
+
+    /// Reads up to 32 bits as two's complement
+    pub fn read_signed_bits(&mut self, count: u32) -> Option<i32> {
+        if count == 0 {
+            return Some(0);
+        }
+        let value = self.read_bits(count)?;
+        return Some(((value << (32 - count)) as i32) >> (32 - count));
+    }
+
+    /// Counts zero bits up to the next one bit, which is skipped
+    pub fn read_unary(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        loop {
+            let byte = *self.data.get(self.position / 8)?;
+            let offset = (self.position % 8) as u32;
+            let rest = byte << offset;
+            if rest != 0 {
+                let leading = rest.leading_zeros();
+                self.position += leading as usize + 1;
+                return Some(zeros + leading);
+            }
+            zeros += 8 - offset;
+            self.position += (8 - offset) as usize;
+        }
+    }
+
+    pub fn skip_bits(&mut self, count: usize) -> Option<()> {
+        if self.position + count > self.data.len() * 8 {
+            return None;
+        }
+        self.position += count;
+        return Some(());
+    }
+
+    /// Unsigned Exp-Golomb code, `ue(v)`
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        let suffix = self.read_bits(leading_zeros)? as u64;
+        return u32::try_from((1u64 << leading_zeros) - 1 + suffix).ok();
+    }
+
+    /// Signed Exp-Golomb code, `se(v)`
+    pub fn read_se(&mut self) -> Option<i32> {
+        let code = self.read_ue()? as i64;
+        let value = match code % 2 {
+            1 => (code + 1) / 2,
+            _ => -(code / 2),
+        };
+        return Some(value as i32);
+    }
+}
+
+/// Removes emulation prevention bytes (`00 00 03` -> `00 00`) from a NAL unit
+pub fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    return rbsp;
+}
+
+/// Finds the offset of the first occurrence of `start_code`
+pub fn find_start_code(data: &[u8], start_code: &[u8]) -> Option<usize> {
+    return data
+        .windows(start_code.len())
+        .position(|window| window == start_code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_exp_golomb() {
+        // 1 | 010 | 011 | 00100 | 00101, then 4 bits of padding
+        let data = [0b1010_0110, 0b0100_0010, 0b1000_0000];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_ue(), Some(0));
+        assert_eq!(reader.read_ue(), Some(1));
+        assert_eq!(reader.read_se(), Some(-1));
+        assert_eq!(reader.read_ue(), Some(3));
+        assert_eq!(reader.read_se(), Some(-2));
+        assert_eq!(reader.read_bits(3), Some(0));
+        assert_eq!(reader.read_bits(5), None);
+    }
+
+    #[test]
+    fn read_unary_and_signed() {
+        // 0000 0000 0001 | 1101 | 0000 0001, then 3 bits of padding
+        let data = [0b0000_0000, 0b0001_1101, 0b0000_0001, 0b0000_0000];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_unary(), Some(11));
+        assert_eq!(reader.read_signed_bits(4), Some(-3));
+        assert_eq!(reader.read_unary(), Some(7));
+        assert_eq!(reader.read_bits(5), Some(0));
+        assert_eq!(reader.read_unary(), None);
+    }
+
+    #[test]
+    fn unescape_rbsp_1() {
+        assert_eq!(
+            unescape_rbsp(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00]),
+            vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Reads the HEVCDecoderConfigurationRecord (ISO/IEC 14496-15 8.3.3) and the
+//! picture size from the sequence parameter set inside it (ITU-T H.265
+//! 7.3.2.2).
+
+use super::{
+    CodecDetails,
+    avc::chroma_format_name,
+    bit_reader::{BitReader, unescape_rbsp},
+};
+
+const NAL_SPS: u8 = 33;
+/// Dolby Vision reference processing unit
+pub const NAL_DOLBY_VISION_RPU: u8 = 62;
+/// Dolby Vision enhancement layer
+pub const NAL_DOLBY_VISION_EL: u8 = 63;
+
+pub fn parse_hevc_config(data: &[u8]) -> Option<CodecDetails> {
+    if data.len() < 23 || data[0] != 1 {
+        return None;
+    }
+    let high_tier = data[1] & 0x20 != 0;
+    let profile_idc = data[1] & 0x1F;
+    let level_idc = data[12];
+    let average_frame_rate = u16::from_be_bytes([data[19], data[20]]);
+    let mut details = CodecDetails {
+        profile: Some(profile_name(profile_idc)),
+        level: Some(match (level_idc % 30 / 3, high_tier) {
+            (0, false) => format!("{}", level_idc / 30),
+            (0, true) => format!("{} High tier", level_idc / 30),
+            (minor, false) => format!("{}.{minor}", level_idc / 30),
+            (minor, true) => format!("{}.{minor} High tier", level_idc / 30),
+        }),
+        chroma_format: chroma_format_name((data[16] & 0x03) as u32),
+        bit_depth: Some((data[17] & 0x07) + 8),
+        // In frames per 256 seconds
+        frame_rate: (average_frame_rate > 0).then(|| average_frame_rate as f64 / 256.0),
+        ..Default::default()
+    };
+
+    let array_count = data[22];
+    let mut cursor = 23;
+    for _ in 0..array_count {
+        let nal_type = data.get(cursor)? & 0x3F;
+        let nal_count = u16::from_be_bytes([*data.get(cursor + 1)?, *data.get(cursor + 2)?]);
+        cursor += 3;
+        for _ in 0..nal_count {
+            let length = u16::from_be_bytes([*data.get(cursor)?, *data.get(cursor + 1)?]) as usize;
+            let nal = data.get(cursor + 2..cursor + 2 + length)?;
+            cursor += 2 + length;
+            if nal_type == NAL_SPS && details.width.is_none() {
+                // Skip the two byte NAL header
+                let _ = parse_sps(&unescape_rbsp(nal.get(2..)?), &mut details);
+            }
+        }
+    }
+    return Some(details);
+}
+
+fn profile_name(profile_idc: u8) -> String {
+    return String::from(match profile_idc {
+        1 => "Main",
+        2 => "Main 10",
+        3 => "Main Still Picture",
+        4 => "Format Range Extensions",
+        5 => "High Throughput",
+        9 => "Screen Content Coding",
+        _ => return format!("Profile {profile_idc}"),
+    });
+}
+
+/// Reads the picture size, which is all the SPS adds over the configuration
+/// record. Colour is left to Matroska's `Colour` element.
+fn parse_sps(rbsp: &[u8], details: &mut CodecDetails) -> Option<()> {
+    let mut reader = BitReader::new(rbsp);
+    reader.skip_bits(4)?; // sps_video_parameter_set_id
+    let max_sub_layers_minus1 = reader.read_bits(3)?;
+    reader.skip_bits(1)?; // sps_temporal_id_nesting_flag
+
+    // profile_tier_level
+    reader.skip_bits(96)?; // general profile, tier, constraints and level
+    let mut sub_layers = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = reader.read_bit()?;
+        let level_present = reader.read_bit()?;
+        sub_layers.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        reader.skip_bits(2 * (8 - max_sub_layers_minus1 as usize))?;
+    }
+    for (profile_present, level_present) in sub_layers {
+        if profile_present {
+            reader.skip_bits(88)?;
+        }
+        if level_present {
+            reader.skip_bits(8)?;
+        }
+    }
+
+    reader.read_ue()?; // sps_seq_parameter_set_id
+    let chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+        reader.skip_bits(1)?; // separate_colour_plane_flag
+    }
+    let mut width = reader.read_ue()?;
+    let mut height = reader.read_ue()?;
+    if reader.read_bit()? {
+        // conformance_window_flag
+        let (sub_width, sub_height) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let (left, right) = (reader.read_ue()?, reader.read_ue()?);
+        let (top, bottom) = (reader.read_ue()?, reader.read_ue()?);
+        width = width.saturating_sub((left + right) * sub_width);
+        height = height.saturating_sub((top + bottom) * sub_height);
+    }
+    details.width = Some(width);
+    details.height = Some(height);
+    return Some(());
+}
+
+/// The NAL unit type of each NAL unit in a length-prefixed access unit
+pub fn nal_unit_types(data: &[u8], nal_length_size: usize) -> impl Iterator<Item = u8> + '_ {
+    let mut cursor = 0;
+    return std::iter::from_fn(move || {
+        let length = data
+            .get(cursor..cursor + nal_length_size)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        cursor += nal_length_size;
+        let header = *data.get(cursor)?;
+        cursor += length;
+        return Some((header >> 1) & 0x3F);
+    });
+}
@@ -0,0 +1,277 @@
+//! Describes video tracks beyond what the Matroska headers say outright:
+//! codec profile and level from `CodecPrivate`, colour and HDR signaling from
+//! the `Colour` element, and Dolby Vision from the bitstream itself.
+
+use matroska_demuxer::{MatrixCoefficients, Primaries, Range, TrackEntry, TransferCharacteristics};
+
+use hevc::{NAL_DOLBY_VISION_EL, NAL_DOLBY_VISION_RPU, nal_unit_types};
+
+pub mod avc;
+pub mod bit_reader;
+pub mod hevc;
+pub mod mpeg2;
+
+/// How many frames of an HEVC track are checked for Dolby Vision NAL units
+const DOLBY_VISION_PROBE_FRAMES: usize = 64;
+
+/// ITU-T H.273 transfer characteristics
+const TRANSFER_PQ: u8 = 16;
+const TRANSFER_HLG: u8 = 18;
+
+/// What the codec's configuration says about the stream
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CodecDetails {
+    pub profile: Option<String>,
+    pub level: Option<String>,
+    /// ie. `4:2:0`
+    pub chroma_format: Option<&'static str>,
+    pub bit_depth: Option<u8>,
+    /// Picture size after cropping
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    /// Only H.264 streams are checked for this
+    pub colour: Option<ColourDescription>,
+}
+
+pub fn parse_codec_private(codec_id: &str, data: &[u8]) -> Option<CodecDetails> {
+    return match codec_id {
+        "V_MPEG4/ISO/AVC" => avc::parse_avc_config(data),
+        "V_MPEGH/ISO/HEVC" => hevc::parse_hevc_config(data),
+        "V_MPEG1" | "V_MPEG2" => mpeg2::parse_mpeg2_config(data),
+        _ => None,
+    };
+}
+
+/// ITU-T H.273 code points
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ColourDescription {
+    pub primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub full_range: bool,
+}
+
+/// ST 2086 mastering display colour volume, as chromaticity coordinates and
+/// luminance in cd/m²
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MasteringDisplay {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white_point: (f64, f64),
+    pub max_luminance: f64,
+    pub min_luminance: f64,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DynamicRange {
+    Sdr,
+    /// PQ with static metadata
+    Hdr10,
+    /// PQ without static metadata
+    Pq,
+    Hlg,
+}
+
+/// Everything we can tell about a video track without decoding it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VideoDetails {
+    pub codec: CodecDetails,
+    /// Matroska's `Colour` element, falling back to the codec's
+    pub primaries: Option<u8>,
+    pub transfer_characteristics: Option<u8>,
+    pub matrix_coefficients: Option<u8>,
+    pub full_range: Option<bool>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub max_cll: Option<u64>,
+    pub max_fall: Option<u64>,
+}
+impl VideoDetails {
+    pub fn new(track: &TrackEntry) -> Self {
+        let mut details = Self {
+            codec: track
+                .codec_private()
+                .and_then(|data| parse_codec_private(track.codec_id(), data))
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        // Matroska's default duration is exact, unlike codec timing fields
+        if let Some(duration) = track.default_duration() {
+            details.codec.frame_rate = Some(1_000_000_000.0 / duration.get() as f64);
+        }
+        if let Some(video) = track.video() {
+            details
+                .codec
+                .width
+                .get_or_insert(video.pixel_width().get() as u32);
+            details
+                .codec
+                .height
+                .get_or_insert(video.pixel_height().get() as u32);
+        }
+
+        if let Some(colour) = details.codec.colour {
+            details.primaries = Some(colour.primaries);
+            details.transfer_characteristics = Some(colour.transfer_characteristics);
+            details.matrix_coefficients = Some(colour.matrix_coefficients);
+            details.full_range = Some(colour.full_range);
+        }
+        let Some(colour) = track.video().and_then(|video| video.colour()) else {
+            return details;
+        };
+        if let Some(primaries) = colour.primaries().and_then(primaries_code) {
+            details.primaries = Some(primaries);
+        }
+        if let Some(transfer) = colour.transfer_characteristics().and_then(transfer_code) {
+            details.transfer_characteristics = Some(transfer);
+        }
+        if let Some(matrix) = colour.matrix_coefficients().and_then(matrix_code) {
+            details.matrix_coefficients = Some(matrix);
+        }
+        match colour.range() {
+            Some(Range::Broadcast) => details.full_range = Some(false),
+            Some(Range::Full) => details.full_range = Some(true),
+            _ => {}
+        }
+        if let Some(bits) = colour.bits_per_channel().filter(|bits| *bits > 0) {
+            details.codec.bit_depth.get_or_insert(bits as u8);
+        }
+        details.max_cll = colour.max_cll();
+        details.max_fall = colour.max_fall();
+        details.mastering_display = colour.mastering_metadata().and_then(|metadata| {
+            return Some(MasteringDisplay {
+                red: (
+                    metadata.primary_r_chromaticity_x()?,
+                    metadata.primary_r_chromaticity_y()?,
+                ),
+                green: (
+                    metadata.primary_g_chromaticity_x()?,
+                    metadata.primary_g_chromaticity_y()?,
+                ),
+                blue: (
+                    metadata.primary_b_chromaticity_x()?,
+                    metadata.primary_b_chromaticity_y()?,
+                ),
+                white_point: (
+                    metadata.white_point_chromaticity_x()?,
+                    metadata.white_point_chromaticity_y()?,
+                ),
+                max_luminance: metadata.luminance_max()?,
+                min_luminance: metadata.luminance_min()?,
+            });
+        });
+        return details;
+    }
+
+    /// The HDR format of the base layer, if the transfer function is known
+    pub fn dynamic_range(&self) -> Option<DynamicRange> {
+        return Some(match self.transfer_characteristics? {
+            TRANSFER_PQ if self.mastering_display.is_some() || self.max_cll.is_some() => {
+                DynamicRange::Hdr10
+            }
+            TRANSFER_PQ => DynamicRange::Pq,
+            TRANSFER_HLG => DynamicRange::Hlg,
+            _ => DynamicRange::Sdr,
+        });
+    }
+}
+
+/// Looks for Dolby Vision RPU and enhancement layer NAL units in the first
+/// few frames of an HEVC track. Matroska signals Dolby Vision in a
+/// `BlockAdditionMapping`, which the demuxer doesn't expose, but the
+/// bitstream always carries it.
+pub struct DolbyVisionProbe {
+    nal_length_size: usize,
+    frames: usize,
+    found: bool,
+}
+impl DolbyVisionProbe {
+    /// Only HEVC tracks can be probed
+    pub fn new(track: &TrackEntry) -> Option<Self> {
+        if track.codec_id() != "V_MPEGH/ISO/HEVC" {
+            return None;
+        }
+        return Some(Self {
+            // lengthSizeMinusOne from the HEVCDecoderConfigurationRecord
+            nal_length_size: track
+                .codec_private()
+                .and_then(|private| private.get(21))
+                .map(|byte| (byte & 0x03) as usize + 1)
+                .unwrap_or(4),
+            frames: 0,
+            found: false,
+        });
+    }
+
+    pub fn push_frame(&mut self, data: &[u8]) {
+        if self.found || self.frames >= DOLBY_VISION_PROBE_FRAMES {
+            return;
+        }
+        self.frames += 1;
+        self.found = nal_unit_types(data, self.nal_length_size)
+            .any(|nal_type| matches!(nal_type, NAL_DOLBY_VISION_RPU | NAL_DOLBY_VISION_EL));
+    }
+
+    pub fn found(&self) -> bool {
+        return self.found;
+    }
+}
+
+fn primaries_code(primaries: Primaries) -> Option<u8> {
+    return Some(match primaries {
+        Primaries::Unknown => return None,
+        Primaries::Bt709 => 1,
+        Primaries::Bt470m => 4,
+        Primaries::Bt601 => 5,
+        Primaries::Smpte170 => 6,
+        Primaries::Smpte240 => 7,
+        Primaries::Film => 8,
+        Primaries::Bt2020 => 9,
+        Primaries::SmpteSt428_1 => 10,
+        Primaries::SmpteRp432_2 => 11,
+        Primaries::SmpteEg432_2 => 12,
+        Primaries::JedecP22 => 22,
+    });
+}
+
+fn transfer_code(transfer: TransferCharacteristics) -> Option<u8> {
+    return Some(match transfer {
+        TransferCharacteristics::Unknown => return None,
+        TransferCharacteristics::Bt709 => 1,
+        TransferCharacteristics::Bt407m => 4,
+        TransferCharacteristics::Bt407bg => 5,
+        TransferCharacteristics::Smpte170 => 6,
+        TransferCharacteristics::Smpte240 => 7,
+        TransferCharacteristics::Linear => 8,
+        TransferCharacteristics::Log => 9,
+        TransferCharacteristics::LogSqrt => 10,
+        TransferCharacteristics::Iec61966_2_4 => 11,
+        TransferCharacteristics::Bt1361 => 12,
+        TransferCharacteristics::Iec61966_2_1 => 13,
+        TransferCharacteristics::Bt220_10 => 14,
+        TransferCharacteristics::Bt220_12 => 15,
+        TransferCharacteristics::Bt2100 => TRANSFER_PQ,
+        TransferCharacteristics::SmpteSt428_1 => 17,
+        TransferCharacteristics::Hlg => TRANSFER_HLG,
+    });
+}
+
+fn matrix_code(matrix: MatrixCoefficients) -> Option<u8> {
+    return Some(match matrix {
+        MatrixCoefficients::Unknown => return None,
+        MatrixCoefficients::Identity => 0,
+        MatrixCoefficients::Bt709 => 1,
+        MatrixCoefficients::Fcc73682 => 4,
+        MatrixCoefficients::Bt470bg => 5,
+        MatrixCoefficients::Smpte170 => 6,
+        MatrixCoefficients::Smpte240 => 7,
+        MatrixCoefficients::YCoCg => 8,
+        MatrixCoefficients::Bt2020Ncl => 9,
+        MatrixCoefficients::Bt2020Cl => 10,
+        MatrixCoefficients::SmpteSt2085 => 11,
+        MatrixCoefficients::ChromaDerivedNcl => 12,
+        MatrixCoefficients::ChromaDerivedCl => 13,
+        MatrixCoefficients::Bt2100 => 14,
+    });
+}
@@ -0,0 +1,110 @@
+//! Reads the MPEG-2 sequence header and sequence extension (ISO/IEC 13818-2
+//! 6.2.2), which Matroska stores as the track's `CodecPrivate`.
+
+use super::{
+    CodecDetails,
+    avc::chroma_format_name,
+    bit_reader::{BitReader, find_start_code},
+};
+
+const SEQUENCE_HEADER: [u8; 4] = [0x00, 0x00, 0x01, 0xB3];
+const EXTENSION_START: [u8; 4] = [0x00, 0x00, 0x01, 0xB5];
+const SEQUENCE_EXTENSION_ID: u32 = 1;
+
+pub fn parse_mpeg2_config(data: &[u8]) -> Option<CodecDetails> {
+    let start = find_start_code(data, &SEQUENCE_HEADER)? + SEQUENCE_HEADER.len();
+    let mut reader = BitReader::new(&data[start..]);
+    let width = reader.read_bits(12)?;
+    let height = reader.read_bits(12)?;
+    reader.skip_bits(4)?; // aspect_ratio_information
+    let frame_rate = match reader.read_bits(4)? {
+        1 => Some(24000.0 / 1001.0),
+        2 => Some(24.0),
+        3 => Some(25.0),
+        4 => Some(30000.0 / 1001.0),
+        5 => Some(30.0),
+        6 => Some(50.0),
+        7 => Some(60000.0 / 1001.0),
+        8 => Some(60.0),
+        _ => None,
+    };
+    let mut details = CodecDetails {
+        width: Some(width),
+        height: Some(height),
+        frame_rate,
+        // MPEG-1 streams have no sequence extension, and are always 4:2:0
+        chroma_format: Some("4:2:0"),
+        bit_depth: Some(8),
+        ..Default::default()
+    };
+
+    let mut cursor = start;
+    while let Some(offset) = find_start_code(&data[cursor..], &EXTENSION_START) {
+        cursor += offset + EXTENSION_START.len();
+        let mut reader = BitReader::new(&data[cursor..]);
+        if reader.read_bits(4)? != SEQUENCE_EXTENSION_ID {
+            continue;
+        }
+        let escape = reader.read_bit()?;
+        let profile = reader.read_bits(3)?;
+        let level = reader.read_bits(4)?;
+        reader.skip_bits(1)?; // progressive_sequence
+        details.chroma_format = chroma_format_name(reader.read_bits(2)?);
+        let width_extension = reader.read_bits(2)?;
+        let height_extension = reader.read_bits(2)?;
+        details.width = Some(width | width_extension << 12);
+        details.height = Some(height | height_extension << 12);
+        if escape {
+            // Only the 4:2:2 and multiview profiles use this, at main or high level
+            details.profile = Some(String::from(match profile {
+                0 => "4:2:2",
+                _ => "Multiview",
+            }));
+            details.level = Some(String::from(match level {
+                2 | 0xE => "High",
+                _ => "Main",
+            }));
+        } else {
+            details.profile = Some(String::from(match profile {
+                1 => "High",
+                2 => "Spatially Scalable",
+                3 => "SNR Scalable",
+                4 => "Main",
+                5 => "Simple",
+                _ => "Unknown",
+            }));
+            details.level = Some(String::from(match level {
+                4 => "High",
+                6 => "High 1440",
+                8 => "Main",
+                10 => "Low",
+                _ => "Unknown",
+            }));
+        }
+        break;
+    }
+    return Some(details);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_dvd_sequence_header() {
+        let data = [
+            0x00, 0x00, 0x01, 0xB3, // sequence header
+            0x2D, 0x01, 0xE0, // 720x480
+            0x24, // 4:3, 29.97fps
+            0xFF, 0xFF, 0xE0, 0x18, // bit rate, VBV buffer size
+            0x00, 0x00, 0x01, 0xB5, // sequence extension
+            0x14, 0x8A, 0x00, 0x01, 0x00, 0x00, // main profile, main level, 4:2:0
+        ];
+        let details = parse_mpeg2_config(&data).unwrap();
+        assert_eq!((details.width, details.height), (Some(720), Some(480)));
+        assert_eq!(details.profile.as_deref(), Some("Main"));
+        assert_eq!(details.level.as_deref(), Some("Main"));
+        assert_eq!(details.chroma_format, Some("4:2:0"));
+        assert!((details.frame_rate.unwrap() - 29.97).abs() < 0.01);
+    }
+}